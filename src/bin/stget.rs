@@ -1,26 +1,82 @@
 #[macro_use] extern crate log;
 
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fs::File;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{mpsc, Arc, OnceLock};
+use std::time::{Duration, Instant};
 use byteorder::{ByteOrder, NetworkEndian};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use stget::events::Event;
+use stget::filter::{FileFilter, FileKind};
+use stget::global_discovery::DiscoveryServer;
+use stget::ignore::IgnorePatterns;
+use stget::known_devices::{KnownDevice, KnownDevices};
+use stget::names::CollisionDetector;
+use stget::proxy::Proxy;
+use stget::rate_limit::RateLimiter;
+use stget::scheduler::{BlockScheduler, Priority};
+use stget::session::{DeviceIdMismatch, HandshakeRejected, Session};
 use stget::syncthing_proto as proto;
+use stget::transport::{DeviceAddress, TransportKind};
+
+// How long to wait for a device to announce itself on the local network. Syncthing sends an
+// announcement every 30 seconds.
+const LOCAL_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(35);
+
+// How often to check on downloads when nothing is arriving from the network.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// How long a connection attempt gets before the next address is tried alongside it. RFC 8305
+// recommends 250ms.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// How long to wait before reconnecting to the remote after losing the connection. This doubles
+// with each failed attempt, up to the maximum, and after enough of them we give up.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+// How many files get a progress bar of their own at once. The rest only count towards the total.
+const MAX_FILE_BARS: usize = 5;
+
+// How often to send a progress event with `--progress json`.
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Set once the progress bars are shown.
+static PROGRESS_BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+// With `--quiet`, only errors and warnings are printed.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Everything printed to stderr goes through `print_line` with this, so that it ends up above the
+// progress bars instead of getting mixed up with them.
+macro_rules! report {
+    ($($arg:tt)*) => { print_line(format_args!($($arg)*)) };
+}
+
+// For messages about what's going on, which `--quiet` leaves out.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+            report!($($arg)*)
+        }
+    };
+}
 
 fn main() {
     env_logger::init();
 
-    let args = clap::Command::new(env!("CARGO_PKG_NAME"))
+    let matches = clap::Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about("experimental Syncthing file retrieval program")
-        .arg(clap::Arg::new("address")
-                .help("Address of the remote host. Port 22000 is used if unspecified.")
-                .required(true)
-                .index(1))
-        .arg(clap::Arg::new("device_id")
-                .help("Device ID of the remote host.")
-                .required(true)
-                .index(2))
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .args(remote_args())
         .arg(clap::Arg::new("path")
                 .help("File path to fetch.")
                 .index(3))
@@ -28,32 +84,110 @@ fn main() {
                 .short('l')
                 .long("list")
                 .action(clap::ArgAction::SetTrue)
-                .help("List all files on the remote end."))
-        .arg(clap::Arg::new("destination")
-                .short('d')
-                .long("dest")
-                .help("destination path for downloaded file(s)"))
+                .help("List all files on the remote end. If a path is given, only list files in \
+                       that folder or subdirectory."))
+        .args(list_format_args().into_iter().map(|arg| arg.requires("list")))
+        .group(clap::ArgGroup::new("list_format")
+                .args(["long", "json", "ndjson"]))
+        .args(filter_args())
+        .args(fetch_args())
+        .arg(clap::Arg::new("peer")
+                .long("peer")
+                .value_name("DEVICE_ID[@ADDRESS]")
+                .value_parser(parse_peer)
+                .action(clap::ArgAction::Append)
+                .help("Also fetch blocks from this device, which must share the folder with us. \
+                       Without an address, the addresses the remote lists for it are tried. May \
+                       be given more than once."))
+        .arg(clap::Arg::new("cluster")
+                .long("cluster")
+                .action(clap::ArgAction::SetTrue)
+                .help("Also fetch from the other devices the remote says share the folder, \
+                       including files the remote doesn't have a valid copy of."))
         .group(clap::ArgGroup::new("path_or_list")
                 .args(["path", "list"])
-                .required(true))
+                .multiple(true))
+        .subcommand(clap::Command::new("find")
+                .about("Search the remote index for entries matching all the given criteria.")
+                .args(remote_args())
+                .arg(clap::Arg::new("path")
+                        .help("Folder or subdirectory to search in. Searches all folders if \
+                               unspecified.")
+                        .index(3))
+                .args(list_format_args())
+                .group(clap::ArgGroup::new("list_format")
+                        .args(["long", "json", "ndjson"]))
+                .args(filter_args()))
+        .subcommand(clap::Command::new("devices")
+                .about("Show which devices the remote says each of its folders is shared with.")
+                .args(remote_args())
+                .arg(clap::Arg::new("json")
+                        .long("json")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Print the devices as JSON.")))
+        .subcommand(clap::Command::new("listen")
+                .about("Wait for one of the allowed devices to connect to us, then list or fetch \
+                        files from it the same way as when connecting to it.")
+                .arg(clap::Arg::new("port")
+                        .long("port")
+                        .value_parser(clap::value_parser!(u16))
+                        .default_value("22000")
+                        .help("TCP port to listen on."))
+                .arg(clap::Arg::new("allow")
+                        .long("allow")
+                        .value_name("DEVICE_ID")
+                        .value_parser(parse_device_id)
+                        .action(clap::ArgAction::Append)
+                        .required(true)
+                        .help("Device ID of a device that may connect. May be given more than \
+                               once."))
+                .arg(clap::Arg::new("path")
+                        .help("File path to fetch.")
+                        .index(1))
+                .arg(clap::Arg::new("list")
+                        .short('l')
+                        .long("list")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("List all files on the remote end. If a path is given, only list \
+                               files in that folder or subdirectory."))
+                .args(list_format_args().into_iter().map(|arg| arg.requires("list")))
+                .group(clap::ArgGroup::new("list_format")
+                        .args(["long", "json", "ndjson"]))
+                .args(filter_args())
+                .args(fetch_args()))
         .get_matches();
 
-    let host_and_port = match args.get_one::<String>("address").unwrap() {
-        host if host.contains(':') => host.to_owned(),
-        host => {
-            debug!("no port specified; assuming 22000");
-            format!("{}:22000", host)
-        }
+    let (args, finding, devices, listening) = match matches.subcommand() {
+        Some(("find", sub_args)) => (sub_args, true, false, false),
+        Some(("devices", sub_args)) => (sub_args, false, true, false),
+        Some(("listen", sub_args)) => (sub_args, false, false, true),
+        _ => (&matches, false, false, false),
     };
+    if args.try_get_one::<bool>("quiet").ok().flatten() == Some(&true) {
+        QUIET.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
 
-    let device_id = args.get_one::<String>("device_id").unwrap();
-    if device_id.len() != 63 {
-        eprintln!("Device ID should be 63 characters long, not {}", device_id.len());
+    let (address, device_id, path) = if listening {
+        (None, None, args.get_one::<String>("path").cloned())
+    } else {
+        let (address, device_id, path) = remote_target(args);
+        if let Some(ref device_id) = device_id {
+            if device_id.len() != 63 {
+                report!("Device ID should be 63 characters long, not {}", device_id.len());
+                std::process::exit(1);
+            }
+        }
+        let address = address.map(|address| DeviceAddress::parse(&address).unwrap_or_else(|e| {
+            report!("Invalid address: {:#}", e);
+            std::process::exit(1);
+        }));
+        (address, device_id, path)
+    };
+    if !finding && !devices && path.is_none() && !args.get_flag("list") {
+        report!("Give a path to fetch, or --list to list files.");
         std::process::exit(1);
     }
 
-    let remote_cert_hash = stget::util::hash_from_device_id(device_id);
-
     /*
     // FIXME(wfraser) remove this
     let (host_and_port, device_id) =
@@ -70,377 +204,2641 @@ fn main() {
     let key_path = base_path.join("cert").join("private.pem");
 
     let cert = stget::certificate::read_cert_file_pem(&cert_path).unwrap_or_else(|e| {
-        eprintln!("Unable to load certificate {:?}: {}", cert_path, e);
-        eprintln!("Did you remember to generate a client certificate?");
+        report!("Unable to load certificate {:?}: {}", cert_path, e);
+        report!("Did you remember to generate a client certificate?");
         std::process::exit(1);
     });
     let key = stget::certificate::read_key_file_pem(&key_path).unwrap_or_else(|e| {
-        eprintln!("Unable to load private key {:?}: {}", key_path, e);
-        eprintln!("Did you remember to generate a client certificate?");
+        report!("Unable to load private key {:?}: {}", key_path, e);
+        report!("Did you remember to generate a client certificate?");
         std::process::exit(1);
     });
 
-    let mut session = stget::session::SessionBuilder {
-        remote_host_and_port: host_and_port,
-        remote_device_id: device_id.to_string(),
-        local_device_name: None,
-        client_cert: cert,
-        private_key: key,
-    }.connect().expect("Failed to create TLS session");
+    let local_cert_hash = ring::digest::digest(&ring::digest::SHA256, &cert.0).as_ref().to_vec();
+
+    let known_devices_path = base_path.join("cert").join("known_devices");
+    let known_devices = KnownDevices::load(&known_devices_path).unwrap_or_else(|e| {
+        report!("Unable to load known devices: {:#}", e);
+        std::process::exit(1);
+    });
+    let device_rate_limits = known_devices.rate_limits();
+
+    // Given only an address, the device ID comes from the known devices, or with --tofu, it's
+    // whichever device answers, which then gets added to them.
+    let tofu = !listening && args.get_flag("tofu");
+    let known_device = address.as_ref()
+        .and_then(|address| known_devices.get(&address.to_string()))
+        .cloned();
+    let device_id = match (device_id, &address, &known_device) {
+        (None, Some(_), Some(known)) => Some(known.device_id.clone()),
+        (None, Some(address), None) if !tofu => {
+            report!("{} isn't a known device. Give its device ID, or use --tofu to trust the \
+                       device that answers there and remember it in {:?}.",
+                   address, known_devices_path);
+            std::process::exit(1);
+        }
+        (device_id, _, _) => device_id,
+    };
+    let mut remember_remote = match (&address, &known_device) {
+        (Some(address), None) if tofu => Some((known_devices, KnownDevice {
+            address: address.to_string(),
+            device_id: String::new(),
+            name: String::new(),
+            limit_rate: None,
+        })),
+        _ => None,
+    };
+
+    // Discovery and proxies are for connecting out, so they don't apply when listening.
+    let (servers, proxy) = if listening {
+        (vec![], None)
+    } else {
+        (discovery_servers(args), proxy(args))
+    };
+
+    let (mut session, device_id, remote_addresses) = if listening {
+        let (session, device_id) = accept_remote(args, &cert, &key);
+        (session, device_id, vec![])
+    } else {
+        let addresses = remote_addresses(
+            args, address, device_id.as_deref(), &servers, &cert, &key);
+        let result = connect_device(
+            device_id.as_deref(), &addresses, &cert, &key, proxy.as_ref());
+        let session = result.unwrap_or_else(|e| {
+            match (e.downcast_ref::<DeviceIdMismatch>(), &known_device) {
+                (Some(mismatch), Some(known)) if mismatch.expected == known.device_id => {
+                    warn_device_changed(known, mismatch, &known_devices_path);
+                }
+                _ => {
+                    report!("Failed to connect: {:#}", e);
+                    if e.is::<HandshakeRejected>() {
+                        explain_tls_rejection(&local_cert_hash);
+                    }
+                }
+            }
+            std::process::exit(1);
+        });
+        let device_id = device_id.unwrap_or_else(|| {
+            session.remote_device_id().expect("no server certificate after the handshake")
+        });
+        if let Some((_, ref mut known)) = remember_remote {
+            known.device_id = device_id.clone();
+        }
+        (session, device_id, addresses)
+    };
+    let device_id = &device_id;
+    let remote_cert_hash = stget::util::hash_from_device_id(device_id);
 
-    session.write_hello().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    session.spawn_reader((0, 0), sender.clone())
+        .expect("Failed to start reading from the connection");
+    let mut connections = vec![Connection::new(session, device_id)];
 
+    let dry_run = args.try_get_one::<bool>("dry_run").ok().flatten() == Some(&true);
+    let json_progress = args.try_get_one::<String>("progress").ok().flatten()
+        .map(|s| s.as_str()) == Some("json");
     let mut program_state = ProgramState {
         remote_cert_hash,
+        local_cert_hash,
         folders_by_id: HashMap::new(),
-        mode: if args.get_flag("list") {
-            Mode::List
+        mode: if devices {
+            Mode::Devices { json: args.get_flag("json") }
+        } else if finding || args.get_flag("list") {
+            let format = if args.get_flag("long") {
+                ListFormat::Long
+            } else if args.get_flag("json") {
+                ListFormat::Json
+            } else if args.get_flag("ndjson") {
+                ListFormat::Ndjson
+            } else {
+                ListFormat::Plain
+            };
+            Mode::List(ListOptions {
+                format,
+                path: path.clone(),
+                include_directories: finding || format != ListFormat::Plain,
+                num_listed: 0,
+            })
         } else {
-            let path = args.get_one::<String>("path").unwrap().to_owned();
+            let path = path.clone().unwrap();
             if !path.contains('/') {
                 panic!("To fetch an entire folder, append a '/' to the path.");
             }
             Mode::Fetch(path)
         },
-        destination: args.get_one::<String>("destination").map(|s| s.as_str()).unwrap_or(".").to_owned(),
-        protocol_state: Some(State::ExpectHello),
+        filter: file_filter(args),
+        ignores: ignore_patterns(args),
+        preserve_permissions: !args.try_get_one::<bool>("no_perms").ok().flatten()
+            .copied().unwrap_or(false),
+        num_matched: 0,
+        pending_dirs: vec![],
+        symlink_policy: if args.try_get_one::<bool>("follow").ok().flatten() == Some(&true) {
+            SymlinkPolicy::Follow
+        } else if args.try_get_one::<bool>("skip_symlinks").ok().flatten() == Some(&true) {
+            SymlinkPolicy::Skip
+        } else {
+            SymlinkPolicy::Create
+        },
+        pending_follows: vec![],
+        deferred: vec![],
+        aborted: false,
+        known_files: HashMap::new(),
+        normalize_nfc: args.try_get_one::<bool>("nfc").ok().flatten() == Some(&true),
+        on_conflict: args.try_get_one::<ConflictPolicy>("on_conflict").ok().flatten()
+            .copied().unwrap_or(ConflictPolicy::Overwrite),
+        dry_run,
+        collisions: CollisionDetector::new(),
+        num_errors: 0,
+        failure: None,
+        remote_closed: false,
+        // Only worth it when there's something to download, and we know where the remote is.
+        can_reconnect: !listening && !devices && !finding && !args.get_flag("list") && !dry_run,
+        reconnecting: false,
+        handled_versions: HashMap::new(),
+        progress: Progress::new(json_progress),
+        destination: args.try_get_one::<String>("destination").ok().flatten()
+            .map(|s| s.as_str()).unwrap_or(".").to_owned(),
+        fetches: HashMap::new(),
+        next_fetch_id: 0,
+        fetch_ids: HashMap::new(),
+        scheduler: BlockScheduler::new(),
+        peer_files: HashMap::new(),
+        peer_index_pending: HashMap::new(),
+        peers_connecting: 0,
+        use_cluster: args.try_get_one::<bool>("cluster").ok().flatten() == Some(&true)
+            && !dry_run,
+        missing_files: HashMap::new(),
+        discovery_servers: servers,
+        proxy,
+        remember_remote,
+        cluster_config: None,
+        device_addresses: HashMap::new(),
+        device_rate_limits,
+        file_events: vec![],
+    };
+    program_state.scheduler.add_peer(0);
+    program_state.limit_device(0, device_id);
+    if let Ok(Some(&limit)) = args.try_get_one::<u64>("limit_rate") {
+        program_state.scheduler.set_rate_limit(RateLimiter::new(limit, Instant::now()));
+    }
+    if args.try_get_one::<bool>("small_files_first").ok().flatten() == Some(&true) {
+        program_state.scheduler.set_priority(Priority::SmallestFirst);
+    }
+
+    let peers: Vec<PeerSpec> = match args.try_get_many::<PeerSpec>("peer") {
+        Ok(Some(peers)) if !dry_run => peers.cloned().collect(),
+        _ => vec![],
     };
+    let (peer_sender, peer_receiver) = mpsc::channel();
+    let mut peers_started = false;
+    let (reconnect_sender, reconnect_receiver) = mpsc::channel();
+    let mut reconnect_pending = false;
+    let mut reconnect_attempts = 0;
 
-    let mut data = vec![];
-    while session.complete_io().is_ok() {
-        match session.read_to_end(&mut data) {
-            Ok(n) => {
-                debug!("read {}", n);
-
-                if n > 0 {
-                    let new_state = process_network_data(
-                        &mut program_state,
-                        &mut session,
-                        &mut data);
-                    if let State::Done = new_state {
-                        break;
-                    } else {
-                        program_state.protocol_state = Some(new_state);
-                    }
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok((tag, result)) => program_state.handle_received(&mut connections, tag, result),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => unreachable!("we hold a sender"),
+        }
+
+        if !peers_started && program_state.cluster_config.is_some() {
+            program_state.connect_peers(&peers, &cert, &key, &peer_sender);
+            peers_started = true;
+        }
+        while let Ok((peer_id, result)) = peer_receiver.try_recv() {
+            program_state.peers_connecting -= 1;
+            match result.and_then(|mut session: Session| {
+                session.spawn_reader((connections.len(), 0), sender.clone())?;
+                Ok(session)
+            }) {
+                Ok(session) => {
+                    status!("connected to device {}", &peer_id[..7]);
+                    program_state.limit_device(connections.len(), &peer_id);
+                    connections.push(Connection::new(session, &peer_id));
                 }
+                Err(e) => report!("Unable to connect to device {}: {:#}", &peer_id[..7], e),
             }
-            Err(e) => {
-                if let Some(e) = e.downcast_ref::<std::io::Error>() {
-                    if e.kind() == std::io::ErrorKind::ConnectionAborted {
-                        eprintln!("Connection closed.");
-                    } else {
-                        eprintln!("Read error: {}", e);
-                    }
-                } else {
-                    eprintln!("Unknown error: {}", e);
+        }
+
+        if program_state.reconnecting && !reconnect_pending {
+            if reconnect_attempts == MAX_RECONNECT_ATTEMPTS {
+                report!("Giving up on reconnecting to the remote.");
+                program_state.reconnecting = false;
+            } else {
+                let delay = (RECONNECT_DELAY * 2u32.pow(reconnect_attempts))
+                    .min(MAX_RECONNECT_DELAY);
+                reconnect_attempts += 1;
+                reconnect_pending = true;
+                status!("Reconnecting in {} seconds...", delay.as_secs());
+                let device_id = device_id.clone();
+                let addresses = remote_addresses.clone();
+                let (cert, key) = (cert.clone(), key.clone());
+                let proxy = program_state.proxy.clone();
+                let reconnect_sender = reconnect_sender.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(delay);
+                    let result = connect_device(
+                        Some(&device_id), &addresses, &cert, &key, proxy.as_ref());
+                    let _ = reconnect_sender.send(result);
+                });
+            }
+        }
+        if let Ok(result) = reconnect_receiver.try_recv() {
+            reconnect_pending = false;
+            let generation = connections[0].generation + 1;
+            match result.and_then(|mut session: Session| {
+                session.spawn_reader((0, generation), sender.clone())?;
+                Ok(session)
+            }) {
+                Ok(session) => {
+                    status!("Reconnected to the remote.");
+                    connections[0] = Connection { generation, ..Connection::new(session, device_id) };
                 }
-                break;
+                Err(e) => report!("Unable to reconnect: {:#}", e),
             }
         }
-    }
+        // Once the remote has sent its cluster config again, the connection counts as working.
+        if reconnect_attempts > 0 && !program_state.reconnecting && connections[0].connected {
+            reconnect_attempts = 0;
+        }
 
-    match program_state.protocol_state {
-        Some(State::ExpectHello) | Some(State::ExpectClusterConfig) => {
-            eprintln!("Remote declined to talk with us.");
-            let (_len, remote_hello): (usize, proto::Hello) =
-                stget::session::Session::read_hello(&data).unwrap_or_else(|e| {
-                    panic!("error reading remote hello: {}", e);
-                });
-            eprintln!("Remote is \"{}\", running {} {}",
-                      remote_hello.device_name,
-                      remote_hello.client_name,
-                      remote_hello.client_version);
+        program_state.fail_stalled_files();
+        program_state.send_requests(&mut connections);
+        for idx in 0 .. connections.len() {
+            if connections[idx].connected {
+                if let Err(e) = connections[idx].session.flush() {
+                    program_state.disconnect(&mut connections, idx, &e);
+                }
+            }
+        }
+
+        program_state.send_file_events(&connections);
+        program_state.progress.tick(Instant::now());
+        if program_state.is_finished(&connections) {
+            break;
         }
-        _ => ()
     }
-}
+    program_state.progress.finish(Instant::now());
 
-fn process_network_data(
-    program: &mut ProgramState,
-    session: &mut stget::session::Session,
-    data: &mut Vec<u8>,
-) -> State {
-    match program.protocol_state.take().unwrap() {
-        State::ExpectHello => {
-            debug!("got hello");
-            program.handle_hello(data)
-        },
-        State::ExpectClusterConfig => {
-            debug!("got remote cluster config");
-            program.handle_cluster_config(data, session)
-        },
-        State::IndexOrBlocks(index_recv_state, fetch_state) => {
-            program.handle_index_or_response(
-                data,
-                session,
-                index_recv_state,
-                fetch_state)
+    if let Mode::List(ref mut opts) = program_state.mode {
+        opts.finish();
+    }
+    program_state.finish_directories();
+
+    match connections[0].state {
+        // These only mean something about the first connection; if a reconnection didn't get
+        // this far, the connection was lost like any other time.
+        Some(State::ExpectHello) if program_state.cluster_config.is_none() => {
+            // With TLS 1.3, the remote checks our certificate after we've finished our side of
+            // the handshake, so it refusing it only shows up now.
+            explain_tls_rejection(&program_state.local_cert_hash);
+            std::process::exit(1);
+        }
+        Some(State::ExpectClusterConfig) if program_state.cluster_config.is_none() => {
+            report!("The remote said hello, but then closed the connection without sending its \
+                       cluster config, which is what Syncthing does with devices it hasn't \
+                       accepted. Its web interface should be showing a notification about our \
+                       device; add it from there, or with \"Add Remote Device\", checking that the \
+                       device ID matches ours.");
+            print_our_device_id(&program_state.local_cert_hash);
+            std::process::exit(1);
+        }
+        _ if program_state.remote_closed && !program_state.index_complete() => {
+            report!("Error: the remote closed the connection before sending its whole index.");
+            program_state.note_failure(Failure::RemoteClosed);
+        }
+        _ => {
+            if let (&Mode::Fetch(_), 0) = (&program_state.mode, program_state.num_matched) {
+                if program_state.index_complete() {
+                    report!("No matching file was found in the directory index.");
+                }
+            }
         }
-        State::Done => panic!("bad state"),
     }
-}
 
-#[derive(Debug)]
-struct ProgramState {
-    remote_cert_hash: Vec<u8>,
-    folders_by_id: HashMap<String, FolderInfo>,
-    mode: Mode,
-    destination: String,
-    protocol_state: Option<State>,
-}
+    for fetch_state in program_state.fetches.values() {
+        report!("Error: {:?}: download incomplete", fetch_state.path);
+        program_state.num_errors += 1;
+        program_state.file_events.push(Event::FileFailed {
+            folder: fetch_state.folder_id.clone(),
+            name: fetch_state.path.clone(),
+            reason: "download incomplete".to_owned(),
+        });
+    }
+    program_state.send_file_events(&connections);
+    if !program_state.fetches.is_empty() && program_state.remote_closed {
+        program_state.note_failure(Failure::RemoteClosed);
+    }
+    for (_, display_path) in program_state.missing_files.values() {
+        report!("Error: not fetching {:?}: no device has a valid copy of it", display_path);
+        program_state.num_errors += 1;
+    }
 
-#[derive(Debug)]
-enum Mode {
-    List,
-    Fetch(String),
+    if program_state.num_errors > 0 {
+        report!("{} file(s) could not be fetched.", program_state.num_errors);
+        program_state.note_failure(Failure::Other);
+    }
+    if let Some(failure) = program_state.failure {
+        std::process::exit(failure.exit_code());
+    }
 }
 
-#[derive(Debug)]
-enum State {
-    Done,
-    ExpectHello,
-    ExpectClusterConfig,
-    IndexOrBlocks(IndexRecvState, Option<BlockFetchState>),
-}
+/// The addresses of the device given on the command line: the one given, or if there isn't one,
+/// the ones found with discovery (exiting if it can't be found).
+fn remote_addresses(
+    args: &clap::ArgMatches,
+    address: Option<DeviceAddress>,
+    device_id: Option<&str>,
+    servers: &[DiscoveryServer],
+    cert: &stget::Certificate,
+    key: &stget::PrivateKey,
+) -> Vec<DeviceAddress> {
+    let announce_addresses = args.get_many::<String>("announce_address")
+        .map(|addresses| addresses.cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let announce = args.get_flag("announce");
+    if announce && !announce_addresses.is_empty() {
+        announce_globally(servers, &announce_addresses, cert, key);
+    }
 
-#[derive(Debug)]
-struct FolderInfo {
-    label: String,
-    max_remote_seq: i64,
+    match address {
+        Some(address) => vec![address],
+        None => {
+            let device_id = device_id.expect("no address or device ID to connect to");
+            let our_announcement = announce.then(|| {
+                let mut instance_id = [0u8; 8];
+                ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut instance_id)
+                    .expect("failed to generate an instance ID");
+                stget::discovery::Announcement {
+                    device_hash: ring::digest::digest(&ring::digest::SHA256, &cert.0)
+                        .as_ref().to_vec(),
+                    addresses: announce_addresses.clone(),
+                    instance_id: i64::from_be_bytes(instance_id),
+                }
+            });
+            let remote_cert_hash = stget::util::hash_from_device_id(device_id);
+            discover_device(device_id, &remote_cert_hash, our_announcement, servers)
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct IndexRecvState {
-    index_n: usize,
+/// Explain that the remote refused our connection during the TLS handshake.
+fn explain_tls_rejection(local_cert_hash: &[u8]) {
+    report!("The remote refused our connection during the TLS handshake, so it doesn't accept \
+               our certificate. It may not know our device, or may be set to ignore it. Add our \
+               device in its web interface with \"Add Remote Device\" (or remove it from the \
+               ignored devices).");
+    print_our_device_id(local_cert_hash);
 }
 
-#[derive(Debug)]
-struct BlockFetchState {
-    request_map: HashMap<i32, FileFetchState>,
+/// Print our device ID, for pasting into the remote's web interface.
+fn print_our_device_id(local_cert_hash: &[u8]) {
+    report!("Our device ID is:");
+    report!("    {}", stget::util::device_id_from_hash(local_cert_hash));
 }
 
-#[derive(Debug)]
-struct FileFetchState {
-    file: File,
-    size: u64,
-    read_bytes: u64,
-    all_blocks: Vec<proto::BlockInfo>,
-    current_outstanding_idx: usize,
-    folder_id: String,
-    path: String,
+/// Complain loudly that a known device presented a different certificate than the one it had when
+/// we first connected to it.
+fn warn_device_changed(known: &KnownDevice, mismatch: &DeviceIdMismatch, path: &Path) {
+    report!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+    report!("@      WARNING: THE REMOTE DEVICE'S IDENTITY HAS CHANGED!      @");
+    report!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+    report!("The device at {} (\"{}\") is known as", known.address, known.name);
+    report!("    {}", mismatch.expected);
+    report!("but it presented a certificate for");
+    report!("    {}", mismatch.presented);
+    report!("Someone could be pretending to be it, or it could have been set up again with a new");
+    report!("certificate. If you're sure it's the latter, remove its line from {:?}", path);
+    report!("and connect again with --tofu.");
 }
 
-impl ProgramState {
-    pub fn handle_hello(&self, data: &mut Vec<u8>) -> State {
-        let (len, remote_hello): (usize, proto::Hello) =
-            stget::session::Session::read_hello(data).unwrap_or_else(|e| {
-                panic!("error reading remote hello: {}", e);
-            });
-        eprintln!("Remote is \"{}\", running {} {}",
-                remote_hello.device_name,
-                remote_hello.client_name,
-                remote_hello.client_version);
-        data.drain(0..len);
-
-        // Wait to send cluster config until we read the remote one.
-        State::ExpectClusterConfig
-    }
+/// Listen for one of the devices given with `--allow` to connect to us, and return the session and
+/// its device ID. Connections from other devices, or that fail partway through, are reported and
+/// then we keep waiting.
+fn accept_remote(
+    args: &clap::ArgMatches,
+    cert: &stget::Certificate,
+    key: &stget::PrivateKey,
+) -> (Session, String) {
+    let port = *args.get_one::<u16>("port").unwrap();
+    let acceptor = stget::session::SessionAcceptor {
+        allowed_device_ids: args.get_many::<String>("allow").unwrap().cloned().collect(),
+        local_device_name: None,
+        server_cert: cert.clone(),
+        private_key: key.clone(),
+    };
 
-    pub fn handle_cluster_config(
-        &mut self, data: &mut Vec<u8>,
-        session: &mut stget::session::Session,
-    ) -> State {
-        let (len, msgtype, message) = stget::session::Session::read_message(data)
-            .unwrap_or_else(|e| {
-                panic!("Error reading remote cluster config: {}", e);
-            });
-        data.drain(0..len);
+    // Listening on IPv6 also takes IPv4 connections on most systems.
+    let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, port))
+        .or_else(|_| TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)))
+        .unwrap_or_else(|e| {
+            report!("Failed to listen on port {}: {}", port, e);
+            std::process::exit(1);
+        });
+    status!("Waiting for a device to connect on port {}...", port);
 
-        let remote_cluster_config: &proto::ClusterConfig = match msgtype {
-            proto::MessageType::CLUSTER_CONFIG => message.as_any().downcast_ref().unwrap(),
-            other => {
-                eprintln!("unexpected message type {:?}; wanted CLUSTER_CONFIG", other);
-                return State::Done;
+    loop {
+        let (stream, from) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                report!("Failed to accept a connection: {}", e);
+                continue;
             }
         };
+        let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+        debug!("connection from {}", from);
+        let result = acceptor.accept(stream).and_then(|mut session| {
+            session.handshake()?;
+            session.write_hello()?;
+            session.flush()?;
+            Ok(session)
+        });
+        match result {
+            Ok(session) => {
+                let device_id = session.remote_device_id()
+                    .expect("no client certificate after the handshake");
+                status!("Device {} connected from {}", &device_id[..7], from);
+                return (session, device_id);
+            }
+            Err(e) => report!("Rejected connection from {}: {:#}", from, e),
+        }
+    }
+}
 
-        debug!("remote cluster config: {:#?}", remote_cluster_config);
+fn parse_device_id(s: &str) -> anyhow::Result<String> {
+    if !stget::util::is_device_id(s) {
+        anyhow::bail!("not a valid device ID");
+    }
+    Ok(s.to_owned())
+}
 
-        let mut cluster_config = proto::ClusterConfig::new();
+/// A device given with `--peer`.
+#[derive(Debug, Clone)]
+struct PeerSpec {
+    device_id: String,
+    address: Option<DeviceAddress>,
+}
 
-        for folder in &remote_cluster_config.folders {
-            for device in &folder.devices {
-                let device_cert_hash: &[u8] = &device.id;
-                if device_cert_hash == self.remote_cert_hash.as_slice() {
-                    self.folders_by_id.insert(
-                        folder.id.clone(),
-                        FolderInfo {
-                            label: folder.label.clone(),
-                            max_remote_seq: device.max_sequence,
-                        });
-                }
-            }
+fn parse_peer(s: &str) -> anyhow::Result<PeerSpec> {
+    let (device_id, address) = match s.split_once('@') {
+        Some((device_id, address)) => (device_id, Some(DeviceAddress::parse(address)?)),
+        None => (s, None),
+    };
+    if device_id.len() != 63 {
+        anyhow::bail!("Device ID should be 63 characters long, not {}", device_id.len());
+    }
+    Ok(PeerSpec { device_id: device_id.to_owned(), address })
+}
+
+/// Parse an address from a cluster config or discovery (e.g. `tcp://192.0.2.1:22000`), or return
+/// None if it's `dynamic` or uses a transport we don't support.
+fn address_from_url(url: &str) -> Option<DeviceAddress> {
+    if url == "dynamic" {
+        return None;
+    }
+    match DeviceAddress::parse(url) {
+        Ok(address) if address.transport == TransportKind::Quic && !cfg!(feature = "quic") => None,
+        Ok(address) => Some(address),
+        Err(e) => {
+            debug!("ignoring address {:?}: {:#}", url, e);
+            None
         }
+    }
+}
 
-        match self.mode {
-            Mode::List => {
-                // make a cluster config with all the folders in the remote
-                for remote_folder in &remote_cluster_config.folders {
-                    let mut folder = proto::Folder::new();
-                    folder.id = remote_folder.id.clone();
-                    folder.label = remote_folder.label.clone();
-                    folder.read_only = true;
-                    folder.ignore_permissions = true;
-                    folder.ignore_delete = true;
-                    folder.disable_temp_indexes = true;
-                    cluster_config.folders.push(folder);
-                }
-            },
-            Mode::Fetch(ref path) => {
-                let folder_name = path.split('/').next().unwrap();
-
-                let mut folder_id = None;
-                for folder in &remote_cluster_config.folders {
-                    if folder.label == folder_name {
-                        folder_id = Some(folder.id.clone());
-                        break;
-                    }
-                }
-                if folder_id.is_none() {
-                    eprintln!("The remote computer is not offering a folder with the specified name (\"{}\").", folder_name);
-                    eprintln!("it offered:");
-                    for folder in &remote_cluster_config.folders {
-                        eprintln!("    {} ({})", folder.label, folder.id);
+/// Connect to a device at any of the given addresses, and exchange hellos. Host names are looked
+/// up and each of their IP addresses is tried, in the style of happy eyeballs (RFC 8305): attempts
+/// are started one after another, each one `CONNECTION_ATTEMPT_DELAY` after the last or as soon as
+/// the last fails, and whichever connects first wins. If none do, the error lists each attempt.
+/// Through a proxy, host names are left for the proxy to look up. Without a device ID, any device
+/// is accepted. If the device at an address isn't the one expected, that's the error, without
+/// waiting for the other attempts, and if one refused the TLS handshake, that is.
+fn connect_device(
+    device_id: Option<&str>,
+    addresses: &[DeviceAddress],
+    cert: &stget::Certificate,
+    key: &stget::PrivateKey,
+    proxy: Option<&Proxy>,
+) -> anyhow::Result<Session> {
+    let mut errors = vec![];
+    let mut rejection = None;
+    let mut candidates = vec![];
+    for address in addresses {
+        let resolved = match proxy {
+            Some(_) => Ok(vec![address.clone()]),
+            None => address.resolve(),
+        };
+        match resolved {
+            Ok(resolved) => {
+                for candidate in resolved {
+                    if !candidates.contains(&candidate) {
+                        candidates.push(candidate);
                     }
-                    return State::Done;
                 }
-
-                let mut folder = proto::Folder::new();
-                folder.id = folder_id.unwrap();
-                folder.label = folder_name.to_owned();
-                folder.read_only = true;
-                folder.ignore_permissions = true;
-                folder.ignore_delete = true;
-                folder.disable_temp_indexes = true;
-                cluster_config.folders.push(folder);
             }
+            Err(e) => errors.push(format!("{}: {:#}", address, e)),
         }
+    }
+    // Prefer TCP, then QUIC, which is less likely to get through firewalls, and then relays, which
+    // are slower.
+    candidates.sort_by_key(|address| match address.transport {
+        TransportKind::Tcp => 0,
+        TransportKind::Quic => 1,
+        TransportKind::Relay => 2,
+    });
 
-        debug!("sending cluster config");
-        session.write_message(
-                &cluster_config,
-                proto::MessageType::CLUSTER_CONFIG)
-            .unwrap_or_else(|e| {
-                panic!("error sending our cluster config: {}", e);
+    let (sender, receiver) = mpsc::channel();
+    let mut candidates = candidates.into_iter().peekable();
+    let mut attempts = 0;
+    loop {
+        if let Some(address) = candidates.next() {
+            debug!("connecting to {} at {}", device_id.unwrap_or("any device"), address);
+            let builder = stget::session::SessionBuilder {
+                remote_host_and_port: address.host_and_port.clone(),
+                transport: address.transport,
+                proxy: proxy.cloned(),
+                remote_device_id: device_id.map(str::to_owned),
+                local_device_name: None,
+                client_cert: cert.clone(),
+                private_key: key.clone(),
+            };
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                let result = builder.connect().and_then(|mut session| {
+                    session.write_hello()?;
+                    session.handshake()?;
+                    session.flush()?;
+                    Ok(session)
+                });
+                // If another attempt won, the receiver is gone and this session gets dropped.
+                let _ = sender.send((address, result));
             });
+            attempts += 1;
+        }
+        if attempts == 0 {
+            break;
+        }
 
-        eprintln!("receiving folder index");
-        let index_recv_state = IndexRecvState {
-            index_n: 0,
+        let (address, result) = if candidates.peek().is_some() {
+            match receiver.recv_timeout(CONNECTION_ATTEMPT_DELAY) {
+                Ok(received) => received,
+                Err(_) => continue,
+            }
+        } else {
+            receiver.recv().expect("we hold a sender")
         };
-        State::IndexOrBlocks(index_recv_state, None)
+        attempts -= 1;
+        match result {
+            Ok(session) => {
+                debug!("connected to {} at {}", device_id.unwrap_or("a device"), address);
+                return Ok(session);
+            }
+            Err(e) if e.is::<DeviceIdMismatch>() => {
+                return Err(e.context(address.to_string()));
+            }
+            Err(e) => {
+                debug!("failed to connect to {}: {:#}", address, e);
+                errors.push(format!("{}: {:#}", address, e));
+                if rejection.is_none() && e.is::<HandshakeRejected>() {
+                    rejection = Some(e.context(address.to_string()));
+                }
+            }
+        }
     }
 
-    pub fn handle_index_or_response(
-        &mut self,
-        data: &mut Vec<u8>,
-        session: &mut stget::session::Session,
-        mut index_recv_state: IndexRecvState,
-        mut fetch_state: Option<BlockFetchState>,
-    ) -> State {
-        let header_len = NetworkEndian::read_u16(&data[0..2]) as usize;
-        let body_len = NetworkEndian::read_u32(&data[
-            2 + header_len
-                .. 2 + header_len + 4]) as usize;
-        let data_len = 2 + header_len + 4 + body_len;
-        if data.len() < data_len {
-            debug!("not enough data; reading more (need {}, have {})",
-                    data_len, data.len());
-            return State::IndexOrBlocks(index_recv_state, fetch_state);
-        }
-
-        let (input_pos, msgtype, mut message) = stget::session::Session::read_message(data)
-            .unwrap_or_else(|e| {
-                panic!("Error reading remote message: {}", e);
-            });
-        debug!("{} bytes read", input_pos);
-        assert_eq!(data_len, input_pos);
+    // If the device was reached but turned us away, that says more than the other errors.
+    if let Some(e) = rejection {
+        return Err(e);
+    }
 
-        match msgtype {
-            proto::MessageType::INDEX => {
-                let index: &proto::Index = message.as_any().downcast_ref().unwrap();
-                self.handle_index(index, session, &mut index_recv_state, &mut fetch_state);
-            },
-            proto::MessageType::INDEX_UPDATE => {
-                // Horrible hack relying on the fact that Index and IndexUpdate are the same
-                let index: &proto::Index = unsafe {
-                    &*(message.as_any().downcast_ref::<proto::IndexUpdate>().unwrap()
-                        as *const proto::IndexUpdate
-                        as *const proto::Index)
-                };
-                self.handle_index(index, session, &mut index_recv_state, &mut fetch_state);
-            },
-            proto::MessageType::PING => {
-                debug!("got a ping message");
-                return State::IndexOrBlocks(index_recv_state, fetch_state);
-            },
-            proto::MessageType::CLOSE => {
-                let close: &proto::Close = message.as_any().downcast_ref().unwrap();
-                debug!("got a close message: {:?}", close);
-                return State::Done;
-            },
-            proto::MessageType::RESPONSE if fetch_state.is_some() => {
-                debug!("got a RESPONSE message");
-                let msg: &mut proto::Response = message.as_any_mut().downcast_mut().unwrap();
+    match errors.len() {
+        0 => anyhow::bail!("no addresses to connect to"),
+        1 => anyhow::bail!("failed to connect to {}", errors[0]),
+        _ => anyhow::bail!("couldn't connect at any of {} addresses:\n  {}",
+                           errors.len(), errors.join("\n  ")),
+    }
+}
 
-                let fetch_state: &mut BlockFetchState = fetch_state.as_mut().unwrap();
+fn remote_args() -> [clap::Arg; 8] {
+    [
+        clap::Arg::new("address")
+            .help("Address of the remote host: a host name or IP address, with port 22000 if \
+                   unspecified, or a URL like tcp://[2001:db8::1]:22000, quic://<host> or \
+                   relay://<host>:<port>/?id=<relay ID>. Leave it out to look for the device \
+                   on the local network.")
+            .required(true)
+            .index(1),
+        clap::Arg::new("device_id")
+            .help("Device ID of the remote host. Can be left out for devices in \
+                   cert/known_devices.")
+            .index(2),
+        clap::Arg::new("tofu")
+            .long("tofu")
+            .action(clap::ArgAction::SetTrue)
+            .help("If the address isn't in cert/known_devices, trust whichever device answers \
+                   there (or the one with the given device ID), and add it, so it can be \
+                   connected to by address from then on."),
+        clap::Arg::new("announce")
+            .long("announce")
+            .action(clap::ArgAction::SetTrue)
+            .help("Announce ourselves on the local network while looking for the device there, \
+                   so it answers right away and offers to add us as a device. With \
+                   --announce-address, also announce to the global discovery servers."),
+        clap::Arg::new("announce_address")
+            .long("announce-address")
+            .value_name("URL")
+            .action(clap::ArgAction::Append)
+            .help("An address to announce we can be reached at, like tcp://192.0.2.1:22000. May \
+                   be given more than once."),
+        clap::Arg::new("discovery_server")
+            .long("discovery-server")
+            .value_name("URL")
+            .value_parser(stget::global_discovery::DiscoveryServer::parse)
+            .action(clap::ArgAction::Append)
+            .help("Global discovery server to use instead of Syncthing's, like \
+                   https://host/v2/?id=<server device ID>. May be given more than once."),
+        clap::Arg::new("no_global_discovery")
+            .long("no-global-discovery")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("discovery_server")
+            .help("Only look for devices on the local network."),
+        clap::Arg::new("proxy")
+            .long("proxy")
+            .value_name("URL")
+            .value_parser(Proxy::parse)
+            .help("Connect to devices through a proxy: socks5://, socks5h:// (the proxy looks up \
+                   host names) or http:// (using CONNECT), optionally with user:password@ \
+                   before the host. Defaults to $all_proxy. Hosts in $no_proxy are connected to \
+                   directly."),
+    ]
+}
 
-                let file_state = fetch_state.request_map
-                    .remove(&msg.id).expect("unexpected message ID");
+/// The proxy to connect through, from the command line or the environment.
+fn proxy(args: &clap::ArgMatches) -> Option<Proxy> {
+    match args.get_one::<Proxy>("proxy") {
+        Some(proxy) => Some(Proxy { no_proxy: Proxy::no_proxy_from_env(), ..proxy.clone() }),
+        None => Proxy::from_env().unwrap_or_else(|e| {
+            report!("{:#}", e);
+            std::process::exit(1);
+        }),
+    }
+}
 
-                match self.handle_response(msg, session, file_state) {
-                    Some((req_id, new_file_state)) => {
-                        fetch_state.request_map.insert(req_id, new_file_state);
-                    }
-                    None => {
-                        if fetch_state.request_map.is_empty() {
-                            return State::Done;
-                        }
-                    }
-                }
-            },
-            other => {
-                eprintln!("got an unexpected message type: {:?}", other);
-                return State::Done;
-            }
-        };
+/// The global discovery servers to use, from the command line or Syncthing's defaults.
+fn discovery_servers(args: &clap::ArgMatches) -> Vec<DiscoveryServer> {
+    if args.get_flag("no_global_discovery") {
+        return vec![];
+    }
+    match args.get_many::<DiscoveryServer>("discovery_server") {
+        Some(servers) => servers.cloned().collect(),
+        None => stget::global_discovery::DEFAULT_SERVERS.iter()
+            .map(|url| DiscoveryServer::parse(url).unwrap())
+            .collect(),
+    }
+}
 
-        data.drain(0..data_len);
+/// Announce our addresses to the global discovery servers that take announcements.
+fn announce_globally(
+    servers: &[DiscoveryServer],
+    addresses: &[String],
+    cert: &stget::Certificate,
+    key: &stget::PrivateKey,
+) {
+    for server in servers.iter().filter(|s| s.announce) {
+        match server.announce(cert, key, addresses) {
+            Ok(_) => debug!("announced ourselves to {}", server.url()),
+            Err(e) => report!("Failed to announce ourselves to {}: {:#}", server.url(), e),
+        }
+    }
+}
 
-        if let (&Mode::Fetch(_), true) = (
-                &self.mode,
-                index_recv_state.index_n != 0 && fetch_state.is_none()
-        ) {
-            eprintln!("No matching file was found in the directory index.");
-            State::Done
-        } else if index_recv_state.index_n == self.folders_by_id.len() {
-            // all done :)
-            State::Done
-        } else {
-            State::IndexOrBlocks(index_recv_state, fetch_state)
+/// Work out the address, device ID, and path from the positional arguments. The address can be
+/// left out, in which case the device ID comes first, or the device ID can be, in which case the
+/// path comes second.
+fn remote_target(args: &clap::ArgMatches) -> (Option<String>, Option<String>, Option<String>) {
+    let first = args.get_one::<String>("address").unwrap().to_owned();
+    let second = args.get_one::<String>("device_id").cloned();
+    let third = args.try_get_one::<String>("path").ok().flatten().cloned();
+    if stget::util::is_device_id(&first) {
+        if let Some(extra) = third {
+            report!("Unexpected argument {:?}", extra);
+            std::process::exit(1);
         }
+        (None, Some(first), second)
+    } else if second.as_deref().is_some_and(stget::util::is_device_id) {
+        (Some(first), second, third)
+    } else {
+        if let Some(extra) = third {
+            report!("Unexpected argument {:?}", extra);
+            std::process::exit(1);
+        }
+        (Some(first), None, second)
     }
+}
 
-    // Return the destination path for the given file if it matches the check pattern, or None if it
-    // doesn't.
-    fn dest_path(&self, file_path: &str, check_path: &str, folder: &str) -> Option<PathBuf> {
+/// Ask the global discovery servers for a device's addresses, trying each in turn.
+fn lookup_globally(device_id: &str, servers: &[DiscoveryServer])
+    -> anyhow::Result<Vec<DeviceAddress>>
+{
+    let mut last_error = None;
+    for server in servers {
+        let result = server.lookup(device_id).and_then(|urls| {
+            let addresses = urls.iter()
+                .filter_map(|url| address_from_url(url))
+                .collect::<Vec<_>>();
+            if addresses.is_empty() {
+                anyhow::bail!("no addresses we can connect to: {}", urls.join(", "));
+            }
+            Ok(addresses)
+        });
+        match result {
+            Ok(addresses) => return Ok(addresses),
+            Err(e) => last_error = Some(e.context(format!("lookup via {} failed", server.url()))),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no discovery servers to ask")))
+}
 
-        let file_part = if check_path.is_empty() {
-            // Degenerate case (whole folder): folder name plus full file path.
-            return Some(Path::new(&self.destination).join(folder).join(file_path));
-        } else if check_path.ends_with('/')
-                && file_path.starts_with(check_path) {
-            // Folder match; start from the last component of the check path.
+/// Find the addresses of a device, by listening for it on the local network and asking the global
+/// discovery servers at the same time, or exit if it can't be found.
+fn discover_device(
+    device_id: &str,
+    remote_cert_hash: &[u8],
+    our_announcement: Option<stget::discovery::Announcement>,
+    servers: &[DiscoveryServer],
+) -> Vec<DeviceAddress> {
+    report!("Looking for device {}...", &device_id[..7]);
+    let (sender, receiver) = mpsc::channel();
+    {
+        let (remote_cert_hash, sender) = (remote_cert_hash.to_vec(), sender.clone());
+        std::thread::spawn(move || {
+            let result = stget::discovery::lookup_local(
+                &remote_cert_hash, our_announcement.as_ref(), LOCAL_DISCOVERY_TIMEOUT);
+            let _ = sender.send(("the local network".to_owned(), result));
+        });
+    }
+    for server in servers.iter().filter(|s| s.lookup) {
+        let (server, device_id, sender) = (server.clone(), device_id.to_owned(), sender.clone());
+        std::thread::spawn(move || {
+            let result = server.lookup(&device_id);
+            let _ = sender.send((server.url().to_owned(), result));
+        });
+    }
+    drop(sender);
+
+    // Go with whichever answers first with an address we can use.
+    let mut errors = vec![];
+    for (source, result) in receiver {
+        match result {
+            Ok(urls) => {
+                let addresses = urls.iter()
+                    .filter_map(|url| address_from_url(url))
+                    .collect::<Vec<_>>();
+                if addresses.is_empty() {
+                    errors.push(format!("{}: no addresses we can connect to: {}",
+                                        source, urls.join(", ")));
+                } else {
+                    debug!("found device at {:?} via {}", addresses, source);
+                    return addresses;
+                }
+            }
+            Err(e) => {
+                debug!("lookup via {} failed: {:#}", source, e);
+                errors.push(format!("{}: {:#}", source, e));
+            }
+        }
+    }
+    report!("Unable to find device {}:", &device_id[..7]);
+    for error in errors {
+        report!("    {}", error);
+    }
+    std::process::exit(1);
+}
+
+fn fetch_args() -> [clap::Arg; 14] {
+    [
+        clap::Arg::new("exclude")
+            .long("exclude")
+            .value_name("PATTERN")
+            .action(clap::ArgAction::Append)
+            .help("When fetching, skip paths matching this .stignore-style pattern. May be \
+                   given more than once; the first matching --exclude/--include wins."),
+        clap::Arg::new("include")
+            .long("include")
+            .value_name("PATTERN")
+            .action(clap::ArgAction::Append)
+            .help("When fetching, keep paths matching this .stignore-style pattern even if a \
+                   later pattern would exclude them."),
+        clap::Arg::new("ignore_file")
+            .long("ignore-file")
+            .value_name("PATH")
+            .action(clap::ArgAction::Append)
+            .help("When fetching, skip paths matched by the patterns in this .stignore file. \
+                   These are checked after any --exclude/--include patterns."),
+        clap::Arg::new("no_perms")
+            .long("no-perms")
+            .action(clap::ArgAction::SetTrue)
+            .help("Don't apply the remote's permission bits to fetched files and \
+                   directories."),
+        clap::Arg::new("follow")
+            .long("follow")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("skip_symlinks")
+            .help("Fetch the file a symlink points to in place of the link, instead of \
+                   recreating the link."),
+        clap::Arg::new("skip_symlinks")
+            .long("skip-symlinks")
+            .action(clap::ArgAction::SetTrue)
+            .help("Don't fetch symlinks."),
+        clap::Arg::new("nfc")
+            .long("nfc")
+            .action(clap::ArgAction::SetTrue)
+            .help("Normalize the names of fetched files to Unicode NFC."),
+        clap::Arg::new("on_conflict")
+            .long("on-conflict")
+            .value_name("POLICY")
+            .value_parser(|s: &str| s.parse::<ConflictPolicy>())
+            .default_value("overwrite")
+            .help("What to do when a destination file already exists: skip, overwrite, \
+                   rename (move the existing file aside), newer (only replace it if the \
+                   remote copy is newer), or fail."),
+        clap::Arg::new("dry_run")
+            .short('n')
+            .long("dry-run")
+            .action(clap::ArgAction::SetTrue)
+            .help("Print what would be done for each file without fetching anything."),
+        clap::Arg::new("destination")
+            .short('d')
+            .long("dest")
+            .help("destination path for downloaded file(s)"),
+        clap::Arg::new("limit_rate")
+            .long("limit-rate")
+            .value_name("SIZE")
+            .value_parser(stget::util::parse_rate)
+            .help("Download at most this many bytes per second (e.g. 5M), from all devices \
+                   together."),
+        clap::Arg::new("small_files_first")
+            .long("small-files-first")
+            .action(clap::ArgAction::SetTrue)
+            .help("When fetching several files, finish the smallest ones first instead of \
+                   sharing the bandwidth between them."),
+        clap::Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .action(clap::ArgAction::SetTrue)
+            .help("Only print errors and warnings, without progress bars."),
+        clap::Arg::new("progress")
+            .long("progress")
+            .value_name("FORMAT")
+            .value_parser(["bar", "json"])
+            .default_value("bar")
+            .help("How to show progress: bars on the terminal, or json for a JSON event per \
+                   line on standard output (file_started, progress, file_finished, \
+                   file_failed and done)."),
+    ]
+}
+
+fn list_format_args() -> [clap::Arg; 3] {
+    [
+        clap::Arg::new("long")
+            .long("long")
+            .action(clap::ArgAction::SetTrue)
+            .help("List in long format: type, permissions, size, modification time, block \
+                   count, last modifying device, and version vector."),
+        clap::Arg::new("json")
+            .long("json")
+            .action(clap::ArgAction::SetTrue)
+            .help("List as a JSON array of objects."),
+        clap::Arg::new("ndjson")
+            .long("ndjson")
+            .action(clap::ArgAction::SetTrue)
+            .help("List as newline-delimited JSON objects, one per file."),
+    ]
+}
+
+fn filter_args() -> [clap::Arg; 7] {
+    [
+        clap::Arg::new("name")
+            .long("name")
+            .action(clap::ArgAction::Append)
+            .help("Only select entries whose file name matches this glob pattern (e.g. '*.mkv'). \
+                   May be given more than once."),
+        clap::Arg::new("regex")
+            .long("regex")
+            .value_parser(|s: &str| regex::Regex::new(s))
+            .help("Only select entries whose path within the folder matches this regular \
+                   expression."),
+        clap::Arg::new("larger_than")
+            .long("larger-than")
+            .value_name("SIZE")
+            .value_parser(stget::util::parse_size)
+            .help("Only select entries larger than this size (e.g. 500K, 1G)."),
+        clap::Arg::new("smaller_than")
+            .long("smaller-than")
+            .value_name("SIZE")
+            .value_parser(stget::util::parse_size)
+            .help("Only select entries smaller than this size (e.g. 500K, 1G)."),
+        clap::Arg::new("newer_than")
+            .long("newer-than")
+            .value_name("DATE")
+            .value_parser(stget::util::parse_timestamp)
+            .help("Only select entries modified after this UTC date (YYYY-MM-DD[ HH:MM[:SS]])."),
+        clap::Arg::new("older_than")
+            .long("older-than")
+            .value_name("DATE")
+            .value_parser(stget::util::parse_timestamp)
+            .help("Only select entries modified before this UTC date (YYYY-MM-DD[ HH:MM[:SS]])."),
+        clap::Arg::new("type")
+            .long("type")
+            .value_parser(|s: &str| s.parse::<FileKind>())
+            .help("Only select entries of this type: file, dir, or symlink."),
+    ]
+}
+
+fn file_filter(args: &clap::ArgMatches) -> FileFilter {
+    if args.try_contains_id("name").is_err() {
+        // This subcommand doesn't take filters.
+        return FileFilter::default();
+    }
+    FileFilter {
+        names: args.get_many::<String>("name").unwrap_or_default().cloned().collect(),
+        regex: args.get_one::<regex::Regex>("regex").cloned(),
+        larger_than: args.get_one::<u64>("larger_than").copied(),
+        smaller_than: args.get_one::<u64>("smaller_than").copied(),
+        newer_than: args.get_one::<i64>("newer_than").copied(),
+        older_than: args.get_one::<i64>("older_than").copied(),
+        kind: args.get_one::<FileKind>("type").copied(),
+    }
+}
+
+fn ignore_patterns(args: &clap::ArgMatches) -> IgnorePatterns {
+    let mut ignores = IgnorePatterns::new();
+    if args.try_contains_id("exclude").is_err() {
+        // This subcommand doesn't take ignore patterns.
+        return ignores;
+    }
+
+    // Command-line patterns apply in the order they were given, regardless of which option.
+    let mut cmdline = vec![];
+    for (id, include) in [("exclude", false), ("include", true)] {
+        if let (Some(indices), Some(values)) = (args.indices_of(id), args.get_many::<String>(id)) {
+            cmdline.extend(indices.zip(values).map(|(i, v)| (i, include, v)));
+        }
+    }
+    cmdline.sort_by_key(|(i, ..)| *i);
+    for (_, include, pattern) in cmdline {
+        if include {
+            ignores.add_include(pattern);
+        } else {
+            ignores.add_exclude(pattern);
+        }
+    }
+
+    for path in args.get_many::<String>("ignore_file").unwrap_or_default() {
+        if let Err(e) = ignores.load_file(Path::new(path)) {
+            report!("Unable to load ignore file: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+
+    ignores
+}
+
+fn process_network_data(
+    program: &mut ProgramState,
+    connections: &mut [Connection],
+    idx: usize,
+    data: &[u8],
+) -> State {
+    let result = match connections[idx].state.take().unwrap() {
+        State::ExpectHello => {
+            debug!("got hello");
+            if idx == 0 {
+                program.handle_hello(data, &connections[0].session, "Remote")
+            } else {
+                let who = format!("Device {}", connections[idx].name);
+                program.handle_hello(data, &connections[idx].session, &who)
+            }
+        },
+        State::ExpectClusterConfig if idx == 0 => {
+            debug!("got remote cluster config");
+            program.handle_cluster_config(data, &mut connections[0].session)
+        },
+        State::ExpectClusterConfig => {
+            debug!("got cluster config from {}", connections[idx].name);
+            program.handle_peer_cluster_config(data, &mut connections[idx], idx)
+        },
+        State::IndexOrBlocks => {
+            return program.handle_index_or_response(data, connections, idx);
+        }
+        State::Done => panic!("bad state"),
+    };
+    // A device that sends something we can't make sense of is dropped like a lost connection,
+    // so that, for the remote, it's reconnected to, and one bad device doesn't end the run.
+    result.unwrap_or_else(|e| {
+        program.disconnect(connections, idx, &e);
+        State::Done
+    })
+}
+
+/// Read the cluster config that should be the first message after the hello.
+fn read_cluster_config(session: &Session, data: &[u8]) -> anyhow::Result<proto::ClusterConfig> {
+    let (_len, msgtype, message) = session.read_message(data)
+        .map_err(|e| e.context("Bad cluster config"))?;
+    match msgtype {
+        proto::MessageType::CLUSTER_CONFIG => {
+            Ok(message.as_any().downcast_ref::<proto::ClusterConfig>().unwrap().clone())
+        }
+        other => anyhow::bail!("Unexpected message type {:?}; wanted CLUSTER_CONFIG", other),
+    }
+}
+
+// The length of the first message in the buffer, if all of it has arrived.
+fn complete_message_len(data: &[u8], hello: bool) -> Option<usize> {
+    let len = if hello {
+        if data.len() < 6 {
+            return None;
+        }
+        6 + NetworkEndian::read_u16(&data[4..6]) as usize
+    } else {
+        if data.len() < 2 {
+            return None;
+        }
+        let header_len = NetworkEndian::read_u16(&data[0..2]) as usize;
+        if data.len() < 2 + header_len + 4 {
+            return None;
+        }
+        2 + header_len + 4
+            + NetworkEndian::read_u32(&data[2 + header_len .. 2 + header_len + 4]) as usize
+    };
+    if data.len() >= len {
+        Some(len)
+    } else {
+        debug!("not enough data; reading more (need {}, have {})", len, data.len());
+        None
+    }
+}
+
+/// A connection to a device. The first one is to the device given on the command line, whose
+/// index decides what gets fetched; any others are only asked for blocks.
+#[derive(Debug)]
+struct Connection {
+    session: Session,
+    device_id: String,
+    /// The short form of the device ID, for messages.
+    name: String,
+    data: Vec<u8>,
+    state: Option<State>,
+    /// Block requests that haven't been answered yet: the fetch and block number by request ID.
+    requests: HashMap<i32, (usize, usize)>,
+    connected: bool,
+    /// How many times the connection has been replaced by a new one after being lost, to tell
+    /// what arrives from each apart.
+    generation: u32,
+}
+
+impl Connection {
+    fn new(mut session: Session, device_id: &str) -> Connection {
+        session.set_observer(Arc::new(log_event));
+        Connection {
+            session,
+            device_id: device_id.to_owned(),
+            name: device_id[..7].to_owned(),
+            data: vec![],
+            state: Some(State::ExpectHello),
+            requests: HashMap::new(),
+            connected: true,
+            generation: 0,
+        }
+    }
+}
+
+/// What happens on the sessions, and to the files being fetched (which `send_file_events` passes
+/// on to the remote's session), goes to the debug log.
+fn log_event(event: &Event) {
+    debug!("event: {:?}", event);
+}
+
+#[derive(Debug)]
+struct ProgramState {
+    remote_cert_hash: Vec<u8>,
+    local_cert_hash: Vec<u8>,
+    folders_by_id: HashMap<String, FolderInfo>,
+    mode: Mode,
+    filter: FileFilter,
+    ignores: IgnorePatterns,
+    preserve_permissions: bool,
+    num_matched: usize,
+    pending_dirs: Vec<(PathBuf, FileMetadata)>,
+    symlink_policy: SymlinkPolicy,
+    pending_follows: Vec<PendingFollow>,
+    /// With `--on-conflict fail`, the entries to write, held back until the whole index has
+    /// arrived so that they can all be checked for conflicts before anything is written.
+    deferred: Vec<DeferredEntry>,
+    /// Whether the run was stopped by a conflict with `--on-conflict fail`.
+    aborted: bool,
+    known_files: HashMap<(String, String), proto::FileInfo>,
+    normalize_nfc: bool,
+    on_conflict: ConflictPolicy,
+    dry_run: bool,
+    collisions: CollisionDetector,
+    num_errors: usize,
+    /// The worst kind of failure so far, if it's not just that some files couldn't be fetched.
+    failure: Option<Failure>,
+    /// Whether the remote closed the connection (or it was lost) while we were still using it.
+    remote_closed: bool,
+    /// Whether to reconnect to the remote if the connection to it is lost.
+    can_reconnect: bool,
+    /// Whether the connection to the remote was lost and we're trying to get it back.
+    reconnecting: bool,
+    /// The version of each file in the remote's index that's been dealt with, by folder ID and
+    /// name, so that it isn't dealt with again if the index is sent again after reconnecting.
+    handled_versions: HashMap<(String, String), protobuf::MessageField<proto::Vector>>,
+    destination: String,
+    /// Files being downloaded, by an ID used with the scheduler.
+    fetches: HashMap<usize, FileFetchState>,
+    next_fetch_id: usize,
+    /// The IDs of files being downloaded, by folder ID and name.
+    fetch_ids: HashMap<(String, String), usize>,
+    scheduler: BlockScheduler,
+    /// The files other devices have, by connection number.
+    peer_files: HashMap<usize, HashMap<(String, String), proto::FileInfo>>,
+    /// For other devices whose index we're still receiving, the last sequence number to expect
+    /// for each folder, by connection number.
+    peer_index_pending: HashMap<usize, HashMap<String, i64>>,
+    /// How many connections to other devices are being set up.
+    peers_connecting: usize,
+    /// Whether to fetch from all the devices the remote lists, with `--cluster`.
+    use_cluster: bool,
+    /// For looking up other devices the remote doesn't list an address for.
+    discovery_servers: Vec<DiscoveryServer>,
+    /// What to connect to other devices through, with `--proxy` or `$all_proxy`.
+    proxy: Option<Proxy>,
+    /// With `--tofu`, where to record the remote once its hello says what it's called.
+    remember_remote: Option<(KnownDevices, KnownDevice)>,
+    /// Files the remote doesn't have a valid copy of, to be fetched from another device when its
+    /// index arrives: the destination and display paths by folder ID and name.
+    missing_files: HashMap<(String, String), (PathBuf, String)>,
+    /// The cluster config we sent, once we have.
+    cluster_config: Option<proto::ClusterConfig>,
+    progress: Progress,
+    /// Addresses of the devices sharing the folders we want, by certificate hash.
+    device_addresses: HashMap<Vec<u8>, Vec<String>>,
+    /// Rate limits for particular devices from cert/known_devices, by device ID.
+    device_rate_limits: HashMap<String, u64>,
+    /// Files finished or given up on, to be reported to the remote's session.
+    file_events: Vec<Event>,
+}
+
+#[derive(Debug)]
+enum Mode {
+    List(ListOptions),
+    Fetch(String),
+    Devices { json: bool },
+}
+
+#[derive(Debug)]
+struct ListOptions {
+    format: ListFormat,
+    path: Option<String>,
+    include_directories: bool,
+    num_listed: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListFormat {
+    Plain,
+    Long,
+    Json,
+    Ndjson,
+}
+
+impl ListOptions {
+    /// The folder label and the path within it that the listing is limited to, if any.
+    fn folder_and_subpath(&self) -> Option<(&str, &str)> {
+        self.path.as_ref().map(|path| {
+            let mut parts = path.splitn(2, '/');
+            let folder = parts.next().unwrap();
+            let subpath = parts.next().unwrap_or("").trim_end_matches('/');
+            (folder, subpath)
+        })
+    }
+
+    fn matches(&self, file: &proto::FileInfo) -> bool {
+        if file.deleted {
+            return false;
+        }
+        if !self.include_directories && FileKind::of(file) == FileKind::Directory {
+            return false;
+        }
+        match self.folder_and_subpath() {
+            None | Some((_, "")) => true,
+            Some((_, subpath)) => {
+                file.name == subpath
+                    || (file.name.starts_with(subpath)
+                        && file.name[subpath.len() ..].starts_with('/'))
+            }
+        }
+    }
+
+    fn print_entry(&mut self, folder_label: &str, folder_id: &str, file: &proto::FileInfo) {
+        let mut display_path = format!("{}/{}", folder_label, file.name);
+        if FileKind::of(file) == FileKind::Symlink {
+            display_path = format!("{} -> {}", display_path, file.symlink_target);
+        }
+        match self.format {
+            ListFormat::Plain => println!("{}", display_path),
+            ListFormat::Long => println!("{}", long_entry(file, &display_path)),
+            ListFormat::Json => {
+                let prefix = if self.num_listed == 0 { "[\n" } else { ",\n" };
+                print!("{}{}", prefix, file_json(folder_label, folder_id, file));
+            }
+            ListFormat::Ndjson => {
+                println!("{}", file_json(folder_label, folder_id, file));
+            }
+        }
+        self.num_listed += 1;
+    }
+
+    fn finish(&mut self) {
+        if self.format == ListFormat::Json {
+            if self.num_listed == 0 {
+                println!("[]");
+            } else {
+                println!("\n]");
+            }
+        }
+    }
+}
+
+/// A line of `--format long` output: type and permissions, size, modification time, number of
+/// blocks, who modified it last, version, and path.
+fn long_entry(file: &proto::FileInfo, display_path: &str) -> String {
+    format!("{}{} {:>14} {} {:>6} {:7} {:<24} {}",
+            file_type_char(file),
+            permissions_string(file),
+            file.size,
+            stget::util::format_timestamp(file.modified_s),
+            file.blocks.len(),
+            stget::util::short_device_id(file.modified_by),
+            version_string(&file.version),
+            display_path)
+}
+
+fn file_type_char(file: &proto::FileInfo) -> char {
+    match FileKind::of(file) {
+        FileKind::File => '-',
+        FileKind::Directory => 'd',
+        FileKind::Symlink => 'l',
+    }
+}
+
+fn file_type_name(file: &proto::FileInfo) -> &'static str {
+    match FileKind::of(file) {
+        FileKind::File => "file",
+        FileKind::Directory => "directory",
+        FileKind::Symlink => "symlink",
+    }
+}
+
+fn permissions_string(file: &proto::FileInfo) -> String {
+    if file.no_permissions {
+        return "?????????".to_owned();
+    }
+    let mut s = String::with_capacity(9);
+    for shift in [6, 3, 0] {
+        let bits = (file.permissions >> shift) & 0o7;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    s
+}
+
+fn version_string(version: &protobuf::MessageField<proto::Vector>) -> String {
+    let counters = match version.as_ref() {
+        Some(v) => &v.counters,
+        None => return "{}".to_owned(),
+    };
+    let parts = counters.iter()
+        .map(|c| format!("{}:{}", stget::util::short_device_id(c.id), c.value))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", parts.join(","))
+}
+
+fn file_json(folder_label: &str, folder_id: &str, file: &proto::FileInfo) -> serde_json::Value {
+    let version = file.version.as_ref()
+        .map(|v| v.counters.iter()
+            .map(|c| serde_json::json!({
+                "id": stget::util::short_device_id(c.id),
+                "value": c.value,
+            }))
+            .collect::<Vec<_>>())
+        .unwrap_or_default();
+    serde_json::json!({
+        "folder": folder_label,
+        "folder_id": folder_id,
+        "name": file.name,
+        "type": file_type_name(file),
+        "size": file.size,
+        "permissions": if file.no_permissions {
+            serde_json::Value::Null
+        } else {
+            format!("{:04o}", file.permissions).into()
+        },
+        "modified": stget::util::format_timestamp(file.modified_s),
+        "modified_s": file.modified_s,
+        "modified_ns": file.modified_ns,
+        "modified_by": stget::util::short_device_id(file.modified_by),
+        "blocks": file.blocks.len(),
+        "sequence": file.sequence,
+        "version": version,
+        "symlink_target": if FileKind::of(file) == FileKind::Symlink {
+            file.symlink_target.as_str().into()
+        } else {
+            serde_json::Value::Null
+        },
+    })
+}
+
+/// Print the devices each folder in a cluster config is shared with, as `stget devices` does.
+fn print_devices(
+    cluster_config: &proto::ClusterConfig,
+    remote_cert_hash: &[u8],
+    local_cert_hash: &[u8],
+    json: bool,
+) {
+    let role = |device: &proto::Device| {
+        let mut tags = vec![];
+        if device.id == remote_cert_hash {
+            tags.push("remote");
+        }
+        if device.id == local_cert_hash {
+            tags.push("this device");
+        }
+        if device.introducer {
+            tags.push("introducer");
+        }
+        tags
+    };
+
+    if json {
+        let folders = cluster_config.folders.iter()
+            .map(|folder| serde_json::json!({
+                "folder": folder.label,
+                "folder_id": folder.id,
+                "devices": folder.devices.iter()
+                    .map(|device| serde_json::json!({
+                        "device_id": stget::util::device_id_from_hash(&device.id),
+                        "name": device.name,
+                        "remote": device.id == remote_cert_hash,
+                        "this_device": device.id == local_cert_hash,
+                        "introducer": device.introducer,
+                        "max_sequence": device.max_sequence,
+                        "addresses": device.addresses,
+                    }))
+                    .collect::<Vec<_>>(),
+            }))
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&folders).unwrap());
+        return;
+    }
+
+    for folder in &cluster_config.folders {
+        println!("{} ({})", folder.label, folder.id);
+        for device in &folder.devices {
+            let tags = role(device);
+            println!("    {} {:?}{}",
+                     stget::util::device_id_from_hash(&device.id),
+                     device.name,
+                     if tags.is_empty() { String::new() } else { format!(" [{}]", tags.join(", ")) });
+            println!("        max sequence: {}", device.max_sequence);
+            if !device.addresses.is_empty() {
+                println!("        addresses: {}", device.addresses.join(", "));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SymlinkPolicy {
+    /// Recreate symlinks as symlinks.
+    Create,
+    /// Don't fetch symlinks at all.
+    Skip,
+    /// Fetch the contents of the file a symlink points to, in place of the link.
+    Follow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConflictPolicy {
+    Skip,
+    Overwrite,
+    /// Move the existing file aside to a Syncthing-style conflict name.
+    Rename,
+    /// Replace the existing file only if the remote copy is newer, or differs in size.
+    Newer,
+    Fail,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "skip" => ConflictPolicy::Skip,
+            "overwrite" => ConflictPolicy::Overwrite,
+            "rename" => ConflictPolicy::Rename,
+            "newer" => ConflictPolicy::Newer,
+            "fail" => ConflictPolicy::Fail,
+            other => return Err(format!(
+                "unknown conflict policy {:?}; expected skip, overwrite, rename, newer, or fail",
+                other)),
+        })
+    }
+}
+
+/// What will be done with an entry that's going to be written.
+#[derive(Debug, PartialEq, Eq)]
+enum Plan {
+    /// Nothing is in the way.
+    Create,
+    /// Replace whatever is at the destination.
+    Overwrite,
+    /// Move whatever is at the destination to the given path first.
+    RenameExisting(PathBuf),
+}
+
+/// Why an entry won't be written.
+#[derive(Debug, PartialEq, Eq)]
+enum Refusal {
+    /// Leaving what's there is what was asked for.
+    Skip(&'static str),
+    /// It's an error for this entry.
+    Error(&'static str),
+    /// A conflict with `--on-conflict fail`, which stops the whole run.
+    Conflict,
+}
+
+/// Whether something at the destination is in the way of an entry.
+fn is_conflict(file: &proto::FileInfo, existing: &std::fs::Metadata) -> bool {
+    // Fetching a directory into an existing one just fills it in.
+    !(FileKind::of(file) == FileKind::Directory && existing.is_dir())
+}
+
+/// The name Syncthing would give to a conflicting copy of a file, set aside at the given time.
+fn conflict_path(path: &Path, now: std::time::SystemTime) -> PathBuf {
+    let now = now
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let timestamp = stget::util::format_timestamp(now)
+        .replace(['-', ':'], "")
+        .replace(' ', "-");
+    // Like Syncthing, everything from the last dot on counts as the extension, even in a name
+    // like ".bashrc".
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let (stem, ext) = name.split_at(name.rfind('.').unwrap_or(name.len()));
+    path.with_file_name(format!("{}.sync-conflict-{}{}", stem, timestamp, ext))
+}
+
+/// A symlink whose target will be fetched once the whole index is known.
+#[derive(Debug)]
+struct PendingFollow {
+    folder_id: String,
+    target_name: String,
+    dest_path: PathBuf,
+    display_path: String,
+}
+
+/// An entry from the index held back until it can be checked for conflicts.
+#[derive(Debug)]
+struct DeferredEntry {
+    folder_id: String,
+    file: proto::FileInfo,
+    dest_path: PathBuf,
+    display_path: String,
+}
+
+#[derive(Debug)]
+enum State {
+    Done,
+    ExpectHello,
+    ExpectClusterConfig,
+    IndexOrBlocks,
+}
+
+/// What went wrong, for the exit code, so that scripts can tell some kinds of failure apart. When
+/// there are several, the later ones here win.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Failure {
+    Other,
+    /// The remote closed the connection before we were done.
+    RemoteClosed,
+    /// A file was deleted or replaced on the remote while we were downloading it.
+    FileVanished,
+    /// The data we got doesn't match the hashes or size in the index.
+    Integrity,
+}
+
+impl Failure {
+    fn exit_code(self) -> i32 {
+        // 2 is what clap exits with for usage errors.
+        match self {
+            Failure::Other => 1,
+            Failure::RemoteClosed => 3,
+            Failure::FileVanished => 4,
+            Failure::Integrity => 5,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FolderInfo {
+    label: String,
+    max_remote_seq: i64,
+    index_complete: bool,
+    /// The remote's ID for its index of the folder, which changes if it starts it over.
+    index_id: u64,
+    /// The sequence number of the last index entry received.
+    last_sequence: i64,
+}
+
+#[derive(Debug)]
+struct FileFetchState {
+    /// The temporary file blocks are written into until they've all been received.
+    file: File,
+    temp_path: PathBuf,
+    metadata: FileMetadata,
+    dest_path: PathBuf,
+    /// Where to move an existing file at `dest_path` before putting this one in its place.
+    rename_existing_to: Option<PathBuf>,
+    size: u64,
+    read_bytes: u64,
+    all_blocks: Vec<proto::BlockInfo>,
+    have_blocks: Vec<bool>,
+    folder_id: String,
+    path: String,
+    version: protobuf::MessageField<proto::Vector>,
+    /// Whether the version being downloaded is the one in the remote's index, rather than one
+    /// only another device has.
+    from_remote: bool,
+    /// Why a block request last failed.
+    problem: Option<Failure>,
+}
+
+impl FileFetchState {
+    fn request_block(&self, session: &mut Session, idx: usize) -> std::io::Result<i32> {
+        session.write_block_request(
+            self.folder_id.clone(),
+            self.path.clone(),
+            self.all_blocks[idx].offset,
+            self.all_blocks[idx].size,
+            self.all_blocks[idx].hash.clone()
+        ).map_err(|e| match e.downcast::<std::io::Error>() {
+            Ok(e) => e,
+            Err(e) => std::io::Error::other(format!("{:#}", e)),
+        })
+    }
+
+    fn write_block(&mut self, idx: usize, data: &[u8]) -> std::io::Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+        self.file.seek(SeekFrom::Start(self.all_blocks[idx].offset as u64))?;
+        self.file.write_all(data)?;
+        self.have_blocks[idx] = true;
+        self.read_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    /// Flush the temporary file to disk and move it into place.
+    fn finish(self) -> std::io::Result<()> {
+        self.file.set_len(self.size)?;
+        self.metadata.apply(&self.file, &self.dest_path);
+        self.file.sync_all()?;
+        drop(self.file);
+        if let Some(ref conflict_path) = self.rename_existing_to {
+            std::fs::rename(&self.dest_path, conflict_path)?;
+        }
+        std::fs::rename(&self.temp_path, &self.dest_path)
+    }
+}
+
+/// Keeps track of how the downloads are going, and shows it: with progress bars on a terminal, or
+/// with `--progress json`, as events on standard output, one JSON object per line.
+#[derive(Debug)]
+struct Progress {
+    json: bool,
+    bars: Option<MultiProgress>,
+    /// The bar for all the files together, once there are any.
+    total_bar: Option<ProgressBar>,
+    /// The files being downloaded, by fetch ID.
+    files: HashMap<usize, FileProgress>,
+    /// The size of all the files started, and how much of that we have.
+    total_size: u64,
+    total_done: u64,
+    /// How much has been downloaded this time, and since when.
+    received: u64,
+    started: Option<Instant>,
+    last_event: Option<Instant>,
+    num_finished: usize,
+    num_failed: usize,
+    /// The events printed, for tests to check.
+    #[cfg(test)]
+    printed: Vec<String>,
+}
+
+#[derive(Debug)]
+struct FileProgress {
+    path: String,
+    size: u64,
+    done: u64,
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    fn new(json: bool) -> Progress {
+        let bars = if json || QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+            None
+        } else {
+            // indicatif doesn't draw anything if stderr isn't a terminal.
+            Some(PROGRESS_BARS.get_or_init(MultiProgress::new).clone())
+        };
+        Progress {
+            json,
+            bars,
+            total_bar: None,
+            files: HashMap::new(),
+            total_size: 0,
+            total_done: 0,
+            received: 0,
+            started: None,
+            last_event: None,
+            num_finished: 0,
+            num_failed: 0,
+            #[cfg(test)]
+            printed: vec![],
+        }
+    }
+
+    fn start_file(&mut self, fetch_id: usize, path: &str, size: u64, have: u64) {
+        self.total_size += size;
+        self.total_done += have;
+        if let (Some(bars), None) = (&self.bars, &self.total_bar) {
+            let bar = bars.add(ProgressBar::new(0));
+            bar.set_style(progress_style(
+                "{wide_msg} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec}, {eta} left"));
+            self.total_bar = Some(bar);
+        }
+        self.files.insert(fetch_id, FileProgress {
+            path: path.to_owned(),
+            size,
+            done: have,
+            bar: None,
+        });
+        if let (Some(bar), true) = (&self.total_bar, have > 0) {
+            // Part of it was already downloaded last time.
+            bar.set_position(self.total_done);
+        }
+        self.update_total_bar();
+        self.event(serde_json::json!({
+            "event": "file_started",
+            "path": path,
+            "size": size,
+            "bytes": have,
+        }));
+    }
+
+    fn received(&mut self, fetch_id: usize, bytes: u64, now: Instant) {
+        self.started.get_or_insert(now);
+        self.received += bytes;
+        self.total_done += bytes;
+        if let Some(ref bar) = self.total_bar {
+            bar.inc(bytes);
+        }
+        let num_file_bars = self.files.values().filter(|file| file.bar.is_some()).count();
+        let file = match self.files.get_mut(&fetch_id) {
+            Some(file) => file,
+            None => return,
+        };
+        file.done += bytes;
+        if let (None, Some(bars), Some(total_bar)) = (&file.bar, &self.bars, &self.total_bar) {
+            if num_file_bars < MAX_FILE_BARS {
+                let bar = bars.insert_before(total_bar, ProgressBar::new(file.size));
+                bar.set_style(progress_style("{wide_msg} [{bar:30}] {bytes}/{total_bytes}"));
+                bar.set_message(file.path.clone());
+                bar.set_position(file.done - bytes);
+                file.bar = Some(bar);
+            }
+        }
+        if let Some(ref bar) = file.bar {
+            bar.inc(bytes);
+        }
+    }
+
+    /// A file is done with, one way or the other.
+    fn finished(&mut self, fetch_id: usize, ok: bool) {
+        let file = match self.files.remove(&fetch_id) {
+            Some(file) => file,
+            None => return,
+        };
+        if let Some(ref bar) = file.bar {
+            bar.finish_and_clear();
+        }
+        if ok {
+            self.num_finished += 1;
+        } else {
+            // What was downloaded still counts, but not what's missing.
+            self.num_failed += 1;
+            self.total_size -= file.size - file.done;
+        }
+        self.update_total_bar();
+        self.event(serde_json::json!({
+            "event": if ok { "file_finished" } else { "file_failed" },
+            "path": file.path,
+            "size": file.size,
+        }));
+    }
+
+    /// Stop showing a file, e.g. because it's being started over. What's left of it no longer
+    /// counts towards the total.
+    fn remove(&mut self, fetch_id: usize) -> Option<FileProgress> {
+        let file = self.files.remove(&fetch_id)?;
+        self.total_size -= file.size;
+        self.total_done -= file.done;
+        if let Some(ref bar) = file.bar {
+            bar.finish_and_clear();
+        }
+        if let Some(ref bar) = self.total_bar {
+            bar.set_position(self.total_done);
+        }
+        self.update_total_bar();
+        Some(file)
+    }
+
+    fn update_total_bar(&self) {
+        if let Some(ref bar) = self.total_bar {
+            bar.set_length(self.total_size);
+            bar.set_message(format!("{} file(s) done, {} to go", self.num_finished,
+                                    self.files.len()));
+        }
+    }
+
+    /// The average download rate so far, in bytes per second, and how long until everything
+    /// started so far is done at that rate.
+    fn rate_and_eta(&self, now: Instant) -> Option<(f64, Duration)> {
+        let elapsed = now.duration_since(self.started?).as_secs_f64();
+        if elapsed <= 0. || self.received == 0 {
+            return None;
+        }
+        let rate = self.received as f64 / elapsed;
+        let remaining = self.total_size.saturating_sub(self.total_done) as f64;
+        Some((rate, Duration::from_secs_f64(remaining / rate)))
+    }
+
+    /// Send a progress event every so often.
+    fn tick(&mut self, now: Instant) {
+        if !self.json || self.files.is_empty() {
+            return;
+        }
+        if self.last_event.is_some_and(|last| now.duration_since(last) < PROGRESS_EVENT_INTERVAL) {
+            return;
+        }
+        self.last_event = Some(now);
+        let (rate, eta) = match self.rate_and_eta(now) {
+            Some((rate, eta)) => (Some(rate.round() as u64), Some(eta.as_secs_f64().round())),
+            None => (None, None),
+        };
+        let mut files = self.files.values()
+            .map(|file| serde_json::json!({
+                "path": file.path,
+                "size": file.size,
+                "bytes": file.done,
+            }))
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+        self.event(serde_json::json!({
+            "event": "progress",
+            "size": self.total_size,
+            "bytes": self.total_done,
+            "bytes_per_sec": rate,
+            "eta_secs": eta,
+            "files": files,
+        }));
+    }
+
+    /// Clear the progress bars, and say how it all went.
+    fn finish(&mut self, now: Instant) {
+        for file in self.files.values() {
+            if let Some(ref bar) = file.bar {
+                bar.finish_and_clear();
+            }
+        }
+        if let Some(bar) = self.total_bar.take() {
+            bar.finish_and_clear();
+        }
+        let elapsed = self.started.map(|started| now.duration_since(started).as_secs_f64());
+        self.event(serde_json::json!({
+            "event": "done",
+            "files_finished": self.num_finished,
+            "files_failed": self.num_failed + self.files.len(),
+            "bytes_received": self.received,
+            "secs": (elapsed.unwrap_or(0.) * 10.).round() / 10.,
+        }));
+    }
+
+    fn event(&mut self, event: serde_json::Value) {
+        if self.json {
+            let line = event.to_string();
+            println!("{}", line);
+            #[cfg(test)]
+            self.printed.push(line);
+        }
+    }
+}
+
+fn progress_style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("bad progress bar template")
+        .progress_chars("=> ")
+}
+
+fn print_line(args: std::fmt::Arguments) {
+    match PROGRESS_BARS.get() {
+        Some(bars) => bars.suspend(|| eprintln!("{}", args)),
+        None => eprintln!("{}", args),
+    }
+}
+
+/// Open the temporary file for a download, keeping anything a previous run left in it. Returns the
+/// file and which of the blocks it already has the right contents for.
+fn open_temp_file(path: &Path, blocks: &[proto::BlockInfo]) -> std::io::Result<(File, Vec<bool>)> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    let mut have_blocks = vec![false; blocks.len()];
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut buf = vec![];
+        for (idx, block) in blocks.iter().enumerate() {
+            if block.offset as u64 + block.size as u64 > len {
+                continue;
+            }
+            buf.resize(block.size as usize, 0);
+            file.seek(SeekFrom::Start(block.offset as u64))?;
+            file.read_exact(&mut buf)?;
+            have_blocks[idx] = block_hash_matches(block, &buf);
+        }
+    }
+    Ok((file, have_blocks))
+}
+
+fn block_hash_matches(block: &proto::BlockInfo, data: &[u8]) -> bool {
+    data.len() == block.size as usize
+        && ring::digest::digest(&ring::digest::SHA256, data).as_ref() == block.hash.as_slice()
+}
+
+/// The parts of a `FileInfo` that get applied to the local copy once it's written.
+#[derive(Debug)]
+struct FileMetadata {
+    modified_s: i64,
+    modified_ns: i32,
+    permissions: Option<u32>,
+}
+
+impl FileMetadata {
+    fn new(file: &proto::FileInfo, preserve_permissions: bool) -> FileMetadata {
+        FileMetadata {
+            modified_s: file.modified_s,
+            modified_ns: file.modified_ns,
+            permissions: if preserve_permissions && !file.no_permissions {
+                Some(file.permissions)
+            } else {
+                None
+            },
+        }
+    }
+
+    fn apply(&self, file: &File, path: &Path) {
+        if let Some(mode) = self.permissions {
+            if let Err(e) = stget::util::set_file_mode(file, mode) {
+                warn!("failed to set permissions of {:?} to {:o}: {}", path, mode, e);
+            }
+        }
+        if let Err(e) = stget::util::set_file_mtime(file, self.modified_s, self.modified_ns) {
+            warn!("failed to set modification time of {:?}: {}", path, e);
+        }
+    }
+
+    /// Like `apply`, for things that can't be opened as a file, like directories on Windows.
+    fn apply_to_path(&self, path: &Path) {
+        if let Some(mode) = self.permissions {
+            if let Err(e) = stget::util::set_path_mode(path, mode) {
+                warn!("failed to set permissions of {:?} to {:o}: {}", path, mode, e);
+            }
+        }
+        if let Err(e) = stget::util::set_path_mtime(path, self.modified_s, self.modified_ns) {
+            warn!("failed to set modification time of {:?}: {}", path, e);
+        }
+    }
+}
+
+impl ProgramState {
+    /// Decide what to do about an entry given what's already at its destination.
+    fn decide(&self, file: &proto::FileInfo, dest_path: &Path) -> Result<Plan, Refusal> {
+        let kind = FileKind::of(file);
+        match std::fs::symlink_metadata(dest_path) {
+            Err(_) => Ok(Plan::Create),
+            Ok(existing) if !is_conflict(file, &existing) => Ok(Plan::Create),
+            Ok(_) if self.on_conflict == ConflictPolicy::Fail => Err(Refusal::Conflict),
+            Ok(existing) if existing.is_dir() && self.on_conflict != ConflictPolicy::Rename
+                && self.on_conflict != ConflictPolicy::Skip =>
+            {
+                // Replacing it would mean deleting everything in it.
+                Err(Refusal::Error("a directory is in the way"))
+            }
+            Ok(existing) => match self.on_conflict {
+                ConflictPolicy::Overwrite => Ok(Plan::Overwrite),
+                ConflictPolicy::Rename => {
+                    Ok(Plan::RenameExisting(conflict_path(dest_path, std::time::SystemTime::now())))
+                }
+                ConflictPolicy::Skip => Err(Refusal::Skip("it already exists")),
+                ConflictPolicy::Fail => unreachable!(),
+                ConflictPolicy::Newer => {
+                    let local_mtime = existing.modified().ok()
+                        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs() as i64);
+                    match local_mtime {
+                        Some(t) if t > file.modified_s => {
+                            Err(Refusal::Skip("the local copy is newer"))
+                        }
+                        Some(t) if t == file.modified_s && existing.len() == file.size as u64
+                            && kind == FileKind::File =>
+                        {
+                            Err(Refusal::Skip("the local copy is up to date"))
+                        }
+                        _ => Ok(Plan::Overwrite),
+                    }
+                }
+            },
+        }
+    }
+
+    /// Work out what to do about an entry given what's already at its destination. Skips and
+    /// failures are reported here, as is everything in a dry run; None means to do nothing more.
+    fn plan(
+        &mut self,
+        file: &proto::FileInfo,
+        dest_path: &Path,
+        display_path: &str,
+    ) -> Option<Plan> {
+        let kind = FileKind::of(file);
+        let result = self.decide(file, dest_path);
+
+        if self.dry_run {
+            let what = file_type_name(file);
+            let size = match kind {
+                FileKind::File => format!(" ({} bytes)", file.size),
+                _ => String::new(),
+            };
+            match result {
+                Ok(Plan::Create) => println!("create {} {:?} -> {:?}{}",
+                                             what, display_path, dest_path, size),
+                Ok(Plan::Overwrite) => println!("overwrite {} {:?} -> {:?}{}",
+                                                what, display_path, dest_path, size),
+                Ok(Plan::RenameExisting(ref aside)) => {
+                    println!("replace {} {:?} -> {:?}{}, moving the existing one to {:?}",
+                             what, display_path, dest_path, size, aside);
+                }
+                Err(Refusal::Skip(reason)) => {
+                    println!("skip {} {:?}: {}", what, display_path, reason);
+                }
+                Err(Refusal::Error(reason)) => {
+                    println!("fail {} {:?}: {}", what, display_path, reason);
+                }
+                Err(Refusal::Conflict) => println!("fail {} {:?}: {:?} already exists",
+                                                   what, display_path, dest_path),
+            }
+            return None;
+        }
+
+        match result {
+            Ok(plan) => Some(plan),
+            Err(Refusal::Skip(reason)) => {
+                status!("skipping {:?}: {}", display_path, reason);
+                None
+            }
+            Err(Refusal::Error(reason)) => {
+                report!("Error: not fetching {:?}: {}", display_path, reason);
+                self.num_errors += 1;
+                None
+            }
+            Err(Refusal::Conflict) => {
+                self.abort_for_conflict(display_path, dest_path, 1);
+                None
+            }
+        }
+    }
+
+    /// Stop fetching anything more after a conflict with `--on-conflict fail`. `not_started` is
+    /// how many entries won't be written because of it, besides any downloads in progress.
+    fn abort_for_conflict(&mut self, display_path: &str, dest_path: &Path, not_started: usize) {
+        report!("Error: not fetching {:?}: {:?} already exists; stopping",
+               display_path, dest_path);
+        self.aborted = true;
+        self.num_errors += not_started;
+        for fetch_id in self.fetches.keys().copied().collect::<Vec<_>>() {
+            self.abandon_fetch(fetch_id, "stopped because of a conflict".to_owned());
+            self.num_errors += 1;
+        }
+        self.num_errors +=
+            self.deferred.len() + self.pending_follows.len() + self.missing_files.len();
+        self.deferred.clear();
+        self.pending_follows.clear();
+        self.missing_files.clear();
+    }
+
+    /// With `--on-conflict fail`, check everything that's going to be written for conflicts, now
+    /// that the whole index is here, and only go ahead if there are none.
+    fn start_deferred(&mut self) {
+        let deferred = std::mem::take(&mut self.deferred);
+        let conflict = deferred.iter().find(|entry| {
+            std::fs::symlink_metadata(&entry.dest_path)
+                .map(|existing| is_conflict(&entry.file, &existing))
+                .unwrap_or(false)
+        });
+        if let Some(entry) = conflict {
+            self.abort_for_conflict(&entry.display_path, &entry.dest_path, deferred.len());
+            return;
+        }
+        for entry in deferred {
+            self.handle_entry(&entry.folder_id, &entry.file, entry.dest_path, entry.display_path);
+        }
+    }
+
+    /// Apply metadata to the directories that were fetched. This is done after all the files
+    /// have been written, so that creating files doesn't change the directories' modification
+    /// times, and so that read-only directories can still be filled in.
+    fn finish_directories(&mut self) {
+        // Deepest first, so that a read-only parent doesn't get in the way.
+        self.pending_dirs.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        for (path, metadata) in self.pending_dirs.drain(..) {
+            metadata.apply_to_path(&path);
+        }
+    }
+
+    pub fn handle_hello(
+        &mut self,
+        data: &[u8],
+        session: &Session,
+        who: &str,
+    ) -> anyhow::Result<State> {
+        let (_len, remote_hello): (usize, proto::Hello) =
+            session.read_hello(data).map_err(|e| e.context("Bad hello"))?;
+        status!("{} is \"{}\", running {} {}",
+                who,
+                remote_hello.device_name,
+                remote_hello.client_name,
+                remote_hello.client_version);
+
+        // This is only set for the remote, and other devices aren't connected to until after its
+        // hello and cluster config have arrived.
+        if let Some((mut known_devices, mut device)) = self.remember_remote.take() {
+            device.name = remote_hello.device_name.clone();
+            match known_devices.add(device.clone()) {
+                Ok(()) => status!("Added {} ({}) to {:?}",
+                                  device.address, device.device_id, known_devices.path()),
+                Err(e) => report!("Unable to remember the remote: {:#}", e),
+            }
+        }
+
+        // Wait to send cluster config until we read the remote one.
+        Ok(State::ExpectClusterConfig)
+    }
+
+    pub fn handle_cluster_config(
+        &mut self, data: &[u8],
+        session: &mut Session,
+    ) -> anyhow::Result<State> {
+        let remote_cluster_config = &read_cluster_config(session, data)?;
+        let reconnected = std::mem::take(&mut self.reconnecting);
+
+        debug!("remote cluster config: {:#?}", remote_cluster_config);
+
+        if let Mode::Devices { json } = self.mode {
+            print_devices(remote_cluster_config, &self.remote_cert_hash, &self.local_cert_hash,
+                          json);
+            return Ok(State::Done);
+        }
+
+        // Syncthing lists the devices each folder is shared with, which includes us if it's shared
+        // with us at all.
+        let shared_with_us = |folder: &proto::Folder| {
+            folder.devices.iter().any(|device| device.id == self.local_cert_hash)
+        };
+        if !remote_cluster_config.folders.iter().any(shared_with_us) {
+            report!("The remote accepted our device, but doesn't share any folders with it. In \
+                       its web interface, edit the folder you want and tick our device on its \
+                       \"Sharing\" tab.");
+            print_our_device_id(&self.local_cert_hash);
+            self.note_failure(Failure::Other);
+            return Ok(State::Done);
+        }
+
+        let mut cluster_config = proto::ClusterConfig::new();
+
+        let folder_name = match self.mode {
+            Mode::List(ref opts) => opts.folder_and_subpath().map(|(folder, _)| folder),
+            Mode::Fetch(ref path) => path.split('/').next(),
+            Mode::Devices { .. } => unreachable!(),
+        };
+
+        let wanted_folders: Vec<&proto::Folder> = match folder_name {
+            None => {
+                // make a cluster config with all the folders the remote shares with us
+                remote_cluster_config.folders.iter().filter(|f| shared_with_us(f)).collect()
+            }
+            Some(folder_name) => {
+                match remote_cluster_config.folders.iter().find(|f| f.label == folder_name) {
+                    Some(folder) if shared_with_us(folder) => vec![folder],
+                    Some(folder) => {
+                        report!("The remote has a folder \"{}\", but doesn't share it with us. In \
+                                   its web interface, edit the folder and tick our device on its \
+                                   \"Sharing\" tab.", folder.label);
+                        print_our_device_id(&self.local_cert_hash);
+                        self.note_failure(Failure::Other);
+                        return Ok(State::Done);
+                    }
+                    None => {
+                        report!("The remote computer is not offering a folder with the specified name (\"{}\").", folder_name);
+                        report!("it offered:");
+                        for folder in &remote_cluster_config.folders {
+                            report!("    {} ({})", folder.label, folder.id);
+                        }
+                        self.note_failure(Failure::Other);
+                        return Ok(State::Done);
+                    }
+                }
+            }
+        };
+
+        for remote_folder in wanted_folders {
+            for device in &remote_folder.devices {
+                let device_cert_hash: &[u8] = &device.id;
+                if device_cert_hash == self.remote_cert_hash.as_slice() {
+                    // After reconnecting, what we already have of the same index doesn't need to
+                    // be sent again.
+                    let last_sequence = match self.folders_by_id.get(&remote_folder.id) {
+                        Some(info) if info.index_id == device.index_id => info.last_sequence,
+                        _ => 0,
+                    };
+                    self.folders_by_id.insert(
+                        remote_folder.id.clone(),
+                        FolderInfo {
+                            label: remote_folder.label.clone(),
+                            max_remote_seq: device.max_sequence,
+                            index_complete: last_sequence > 0
+                                && last_sequence >= device.max_sequence,
+                            index_id: device.index_id,
+                            last_sequence,
+                        });
+                } else {
+                    self.device_addresses.entry(device.id.clone())
+                        .or_insert_with(|| device.addresses.clone());
+                }
+            }
+
+            let mut folder = proto::Folder::new();
+            folder.id = remote_folder.id.clone();
+            folder.label = remote_folder.label.clone();
+            folder.read_only = true;
+            folder.ignore_permissions = true;
+            folder.ignore_delete = true;
+            folder.disable_temp_indexes = true;
+            if let Some(info) = self.folders_by_id.get(&remote_folder.id) {
+                if info.last_sequence > 0 {
+                    // Tell the remote how far we got, so that it only sends what's newer.
+                    let mut device = proto::Device::new();
+                    device.id = self.remote_cert_hash.clone();
+                    device.index_id = info.index_id;
+                    device.max_sequence = info.last_sequence;
+                    folder.devices.push(device);
+                }
+            }
+            cluster_config.folders.push(folder);
+        }
+
+        debug!("sending cluster config");
+        session.write_message(&cluster_config, proto::MessageType::CLUSTER_CONFIG)
+            .map_err(|e| e.context("Failed to send our cluster config"))?;
+
+        self.cluster_config = Some(cluster_config);
+
+        if reconnected {
+            // Carry on with the downloads that were under way.
+            self.remote_closed = false;
+            self.scheduler.add_peer(0);
+            for (&fetch_id, fetch_state) in &self.fetches {
+                if fetch_state.from_remote {
+                    self.scheduler.add_file_peer(fetch_id, 0);
+                }
+            }
+            if self.index_complete() {
+                return Ok(State::IndexOrBlocks);
+            }
+        }
+
+        status!("receiving folder index");
+        Ok(State::IndexOrBlocks)
+    }
+
+    fn handle_peer_cluster_config(
+        &mut self,
+        data: &[u8],
+        connection: &mut Connection,
+        idx: usize,
+    ) -> anyhow::Result<State> {
+        let peer_cluster_config = &read_cluster_config(&connection.session, data)?;
+
+        for (id, folder) in &self.folders_by_id {
+            if !peer_cluster_config.folders.iter().any(|f| &f.id == id) {
+                report!("Device {} doesn't share folder {:?} with us",
+                       connection.name, folder.label);
+            }
+        }
+
+        debug!("sending cluster config to {}", connection.name);
+        connection.session.write_message(
+            self.cluster_config.as_ref().unwrap(),
+            proto::MessageType::CLUSTER_CONFIG,
+        ).map_err(|e| e.context("Failed to send our cluster config"))?;
+
+        let peer_cert_hash = stget::util::hash_from_device_id(&connection.device_id);
+        let pending: HashMap<String, i64> = peer_cluster_config.folders.iter()
+            .filter(|f| self.folders_by_id.contains_key(&f.id))
+            .filter_map(|f| {
+                f.devices.iter()
+                    .find(|d| d.id == peer_cert_hash && d.max_sequence > 0)
+                    .map(|d| (f.id.clone(), d.max_sequence))
+            })
+            .collect();
+        if !pending.is_empty() {
+            self.peer_index_pending.insert(idx, pending);
+        }
+
+        self.scheduler.add_peer(idx);
+        self.peer_files.insert(idx, HashMap::new());
+        Ok(State::IndexOrBlocks)
+    }
+
+    pub fn handle_index_or_response(
+        &mut self,
+        data: &[u8],
+        connections: &mut [Connection],
+        idx: usize,
+    ) -> State {
+        let (input_pos, msgtype, message) = match connections[idx].session.read_message(data) {
+            Ok(read) => read,
+            Err(e) => {
+                self.disconnect(connections, idx, &e.context("Bad message"));
+                return State::Done;
+            }
+        };
+        debug!("{} bytes read", input_pos);
+        if input_pos != data.len() {
+            let error = anyhow::anyhow!(
+                "Bad message: {} bytes left over", data.len() - input_pos);
+            self.disconnect(connections, idx, &error);
+            return State::Done;
+        }
+
+        match msgtype {
+            proto::MessageType::INDEX | proto::MessageType::INDEX_UPDATE => {
+                let index: &proto::Index = if msgtype == proto::MessageType::INDEX {
+                    message.as_any().downcast_ref().unwrap()
+                } else {
+                    // Horrible hack relying on the fact that Index and IndexUpdate are the same
+                    unsafe {
+                        &*(message.as_any().downcast_ref::<proto::IndexUpdate>().unwrap()
+                            as *const proto::IndexUpdate
+                            as *const proto::Index)
+                    }
+                };
+                // We can't hold the index back, but it uses up bandwidth as much as blocks do.
+                self.scheduler.count_unrequested(idx, data.len() as u64, Instant::now());
+                if idx == 0 {
+                    self.handle_index(index);
+                } else {
+                    let peer_name = connections[idx].name.clone();
+                    self.handle_peer_index(idx, &peer_name, index);
+                }
+            },
+            proto::MessageType::PING => {
+                debug!("got a ping message");
+            },
+            proto::MessageType::CLOSE => {
+                let close: &proto::Close = message.as_any().downcast_ref().unwrap();
+                debug!("got a close message: {:?}", close);
+                if idx == 0 {
+                    report!("Remote closed the connection: {}", close.reason);
+                    self.remote_closed = true;
+                } else {
+                    report!("Device {} closed the connection: {}",
+                           connections[idx].name, close.reason);
+                }
+                return State::Done;
+            },
+            proto::MessageType::RESPONSE => {
+                debug!("got a RESPONSE message");
+                let msg: &proto::Response = message.as_any().downcast_ref().unwrap();
+                // A response to a request from before a reconnect, or one that was never made.
+                let (fetch_id, block) = match connections[idx].requests.remove(&msg.id) {
+                    Some(request) => request,
+                    None => {
+                        warn!("ignoring response with unknown ID {} from {}",
+                              msg.id, connections[idx].name);
+                        return State::IndexOrBlocks;
+                    }
+                };
+                self.handle_response(msg, idx, &connections[idx].name, fetch_id, block);
+            },
+            other => {
+                report!("got an unexpected message type: {:?}", other);
+                return State::Done;
+            }
+        };
+
+        State::IndexOrBlocks
+    }
+
+    /// Handle what the reader thread for a connection sent, tagged with the connection's index and
+    /// generation.
+    fn handle_received(
+        &mut self,
+        connections: &mut [Connection],
+        (idx, generation): (usize, u32),
+        result: std::io::Result<Vec<u8>>,
+    ) {
+        // Anything still arriving from a connection to the remote that has since been replaced is
+        // of no use.
+        if connections[idx].generation == generation {
+            self.handle_network_data(connections, idx, result);
+        }
+    }
+
+    fn handle_network_data(
+        &mut self,
+        connections: &mut [Connection],
+        idx: usize,
+        result: std::io::Result<Vec<u8>>,
+    ) {
+        let connection = &mut connections[idx];
+        if !connection.connected {
+            return;
+        }
+        let read = result.map_err(anyhow::Error::from)
+            .and_then(|tls_data| connection.session.receive(&tls_data, &mut connection.data));
+        match read {
+            Ok(n) => debug!("read {} from {}", n, connection.name),
+            Err(e) => {
+                self.disconnect(connections, idx, &e);
+                return;
+            }
+        }
+
+        // Handle every complete message that has arrived.
+        loop {
+            let connection = &mut connections[idx];
+            let len = match connection.state {
+                Some(State::Done) => break,
+                Some(ref state) => match complete_message_len(
+                    &connection.data, matches!(state, State::ExpectHello))
+                {
+                    Some(len) => len,
+                    None => break,
+                },
+                None => unreachable!(),
+            };
+            let message = connection.data.drain(.. len).collect::<Vec<u8>>();
+            let new_state = process_network_data(self, connections, idx, &message);
+            connections[idx].state = Some(new_state);
+        }
+
+        if matches!(connections[idx].state, Some(State::Done)) {
+            connections[idx].connected = false;
+            connections[idx].requests.clear();
+            self.forget_peer(idx);
+        }
+    }
+
+    /// Report the files that have been finished or given up on to the remote's session, whose
+    /// observer gets them along with everything that happens on the session itself.
+    fn send_file_events(&mut self, connections: &[Connection]) {
+        for event in self.file_events.drain(..) {
+            connections[0].session.notify(event);
+        }
+    }
+
+    /// Give a new connection the rate limit set for its device, if there is one.
+    fn limit_device(&mut self, idx: usize, device_id: &str) {
+        if let Some(&limit) = self.device_rate_limits.get(device_id) {
+            self.scheduler.set_peer_rate_limit(idx, RateLimiter::new(limit, Instant::now()));
+        }
+    }
+
+    fn forget_peer(&mut self, idx: usize) {
+        self.scheduler.remove_peer(idx);
+        self.peer_files.remove(&idx);
+        self.peer_index_pending.remove(&idx);
+    }
+
+    /// Stop using a connection after an error.
+    fn disconnect(&mut self, connections: &mut [Connection], idx: usize, error: &anyhow::Error) {
+        let connection = &mut connections[idx];
+        if !connection.connected {
+            return;
+        }
+        connection.connected = false;
+        connection.requests.clear();
+        self.forget_peer(idx);
+
+        let prefix = if idx == 0 {
+            self.remote_closed = true;
+            // Only once there's something to pick up where we left off from.
+            self.reconnecting = self.can_reconnect && self.cluster_config.is_some();
+            String::new()
+        } else {
+            format!("Device {}: ", connection.name)
+        };
+        if let Some(e) = error.downcast_ref::<std::io::Error>() {
+            if e.kind() == std::io::ErrorKind::ConnectionAborted {
+                report!("{}Connection closed.", prefix);
+            } else {
+                report!("{}Read error: {}", prefix, e);
+            }
+        } else {
+            report!("{}Error: {:#}", prefix, error);
+        }
+    }
+
+    /// Start connecting to the devices given with `--peer` (and with `--cluster`, the ones the
+    /// remote lists) in the background. Each connection (or the error from trying) is sent over
+    /// the channel along with the device ID.
+    fn connect_peers(
+        &mut self,
+        peers: &[PeerSpec],
+        cert: &stget::Certificate,
+        key: &stget::PrivateKey,
+        sender: &mpsc::Sender<(String, anyhow::Result<Session>)>,
+    ) {
+        if !matches!(self.mode, Mode::Fetch(_)) {
+            return;
+        }
+
+        let mut peers = peers.to_vec();
+        if self.use_cluster {
+            let mut listed = self.device_addresses.keys()
+                .filter(|hash| **hash != self.local_cert_hash)
+                .map(|hash| stget::util::device_id_from_hash(hash))
+                .filter(|id| !peers.iter().any(|p| &p.device_id == id))
+                .collect::<Vec<_>>();
+            listed.sort();
+            peers.extend(listed.into_iter().map(|device_id| PeerSpec { device_id, address: None }));
+        }
+
+        for peer in &peers {
+            let addresses = match peer.address {
+                Some(ref address) => vec![address.clone()],
+                None => {
+                    let hash = stget::util::hash_from_device_id(&peer.device_id);
+                    self.device_addresses.get(&hash).into_iter().flatten()
+                        .filter_map(|url| address_from_url(url))
+                        .collect()
+                }
+            };
+            let servers = self.discovery_servers.iter()
+                .filter(|s| s.lookup)
+                .cloned()
+                .collect::<Vec<_>>();
+            if addresses.is_empty() && servers.is_empty() {
+                report!("No address is known for device {}; give one with --peer {}@<address>",
+                       &peer.device_id[..7], peer.device_id);
+                continue;
+            }
+
+            self.peers_connecting += 1;
+            let (device_id, cert, key, proxy, sender) = (peer.device_id.clone(), cert.clone(),
+                key.clone(), self.proxy.clone(), sender.clone());
+            std::thread::spawn(move || {
+                let result = if addresses.is_empty() {
+                    lookup_globally(&device_id, &servers).and_then(|addresses| {
+                        connect_device(Some(&device_id), &addresses, &cert, &key, proxy.as_ref())
+                    })
+                } else {
+                    connect_device(Some(&device_id), &addresses, &cert, &key, proxy.as_ref())
+                };
+                let _ = sender.send((device_id, result));
+            });
+        }
+    }
+
+    /// Send the block requests the scheduler decides on.
+    fn send_requests(&mut self, connections: &mut [Connection]) {
+        for assignment in self.scheduler.assign(Instant::now()) {
+            let fetch_state = &self.fetches[&assignment.file];
+            let connection = &mut connections[assignment.peer];
+            if !connection.connected {
+                // Lost while sending an earlier request; the scheduler has taken its blocks back.
+                continue;
+            }
+            debug!("requesting block {} of {:?} from {}",
+                   assignment.block, fetch_state.path, connection.name);
+            match fetch_state.request_block(&mut connection.session, assignment.block) {
+                Ok(req_id) => {
+                    connection.requests.insert(req_id, (assignment.file, assignment.block));
+                }
+                // The same as if the reader had found the connection gone, so the remote is
+                // reconnected to.
+                Err(e) => self.disconnect(connections, assignment.peer, &e.into()),
+            }
+        }
+    }
+
+    /// Give up on files that need a block that no device can provide.
+    fn fail_stalled_files(&mut self) {
+        if self.reconnecting {
+            // The remote might be able to provide the blocks once it's back.
+            return;
+        }
+        for (fetch_id, block) in self.scheduler.stalled() {
+            let fetch_state = &self.fetches[&fetch_id];
+            let failure = match fetch_state.problem {
+                Some(problem) => problem,
+                // The remote is the only other device a block could have come from.
+                None if self.remote_closed => Failure::RemoteClosed,
+                None => Failure::Other,
+            };
+            report!("Error: {:?}: no device could provide block {}", fetch_state.path, block);
+            self.num_errors += 1;
+            self.note_failure(failure);
+            self.abandon_fetch(fetch_id, format!("no device could provide block {}", block));
+        }
+    }
+
+    fn note_failure(&mut self, failure: Failure) {
+        self.failure = self.failure.max(Some(failure));
+    }
+
+    fn abandon_fetch(&mut self, fetch_id: usize, reason: String) {
+        self.progress.finished(fetch_id, false);
+        self.scheduler.abandon_file(fetch_id);
+        if let Some(fetch_state) = self.fetches.remove(&fetch_id) {
+            self.file_events.push(Event::FileFailed {
+                folder: fetch_state.folder_id.clone(),
+                name: fetch_state.path.clone(),
+                reason,
+            });
+            self.fetch_ids.remove(&(fetch_state.folder_id, fetch_state.path));
+        }
+    }
+
+    fn index_complete(&self) -> bool {
+        self.cluster_config.is_some() && self.folders_by_id.values().all(|f| f.index_complete)
+    }
+
+    fn is_finished(&self, connections: &[Connection]) -> bool {
+        // Files the remote doesn't have might still turn up in another device's index.
+        let waiting_for_peers = !self.missing_files.is_empty()
+            && (self.peers_connecting > 0 || !self.peer_index_pending.is_empty());
+        if self.index_complete() && self.fetches.is_empty() && !waiting_for_peers {
+            // all done :)
+            return true;
+        }
+        if self.reconnecting {
+            return false;
+        }
+        let remote_usable = connections[0].connected
+            && !matches!(connections[0].state, Some(State::Done));
+        // Without the remote, we can only carry on if the whole index was received. Downloads
+        // that no other device can help with will stall and be given up on.
+        !remote_usable && !self.index_complete()
+    }
+
+    // Return the destination path for the given file if it matches the check pattern, or None if it
+    // doesn't.
+    fn dest_path(&self, file_path: &str, check_path: &str, folder: &str) -> Option<PathBuf> {
+
+        let file_part = if check_path.is_empty() {
+            // Degenerate case (whole folder): folder name plus full file path.
+            return Some(Path::new(&self.destination).join(folder).join(file_path));
+        } else if check_path.ends_with('/')
+                && file_path.starts_with(check_path) {
+            // Folder match; start from the last component of the check path.
             let prefix_len = match check_path.rsplitn(3, '/').nth(2) {
                 Some(prefix) => prefix.len() + 1,
                 None => 0,
@@ -453,190 +2851,1349 @@ impl ProgramState {
             return None;
         };
 
-        Some(Path::new(&self.destination)
-            .join(file_part))
+        Some(Path::new(&self.destination)
+            .join(file_part))
+    }
+
+    fn handle_index(&mut self, index: &proto::Index) {
+        debug!("remote index: {:#?}", index);
+
+        let folder_label = self.folders_by_id[&index.folder].label.clone();
+
+        for file in &index.files {
+            if let Mode::List(ref mut opts) = self.mode {
+                if opts.matches(file) && self.filter.matches(file) {
+                    opts.print_entry(&folder_label, &index.folder, file);
+                }
+                continue;
+            }
+
+            let key = (index.folder.clone(), file.name.clone());
+            if let Some(&fetch_id) = self.fetch_ids.get(&key) {
+                // An index update for a file we're downloading. Anything but an older version
+                // (including one that conflicts with it) replaces what we're downloading, unless
+                // we're getting it from another device because the remote's copy isn't valid.
+                let fetch_state = &self.fetches[&fetch_id];
+                let ordering = stget::version::compare(
+                    file.version.get_or_default(), fetch_state.version.get_or_default());
+                match ordering {
+                    Some(Ordering::Equal) => (),
+                    Some(Ordering::Less) => {
+                        debug!("ignoring an older version of {:?} than the one being fetched",
+                               file.name);
+                    }
+                    _ if !fetch_state.from_remote && file.invalid => (),
+                    _ => {
+                        let display_path = format!("{}/{}", folder_label, file.name);
+                        self.restart_fetch(fetch_id, file, &display_path, true);
+                        if self.can_reconnect {
+                            self.handled_versions.insert(key, file.version.clone());
+                        }
+                    }
+                }
+                continue;
+            }
+
+            if self.can_reconnect {
+                if self.handled_versions.get(&key) == Some(&file.version) {
+                    // Seen before the connection was lost.
+                    continue;
+                }
+                self.handled_versions.insert(key.clone(), file.version.clone());
+            }
+
+            if file.deleted {
+                continue;
+            }
+            if self.symlink_policy == SymlinkPolicy::Follow {
+                self.known_files.insert((index.folder.clone(), file.name.clone()), file.clone());
+            }
+            let kind = FileKind::of(file);
+            if kind == FileKind::Directory {
+                if let Mode::Fetch(ref check_path) = self.mode {
+                    if !check_path.ends_with('/')
+                         && check_path[folder_label.len() + 1 ..] == file.name
+                    {
+                        panic!("Cannot fetch a directory entry. To recursively fetch a whole \
+                                directory, append a '/' to the path.");
+                    }
+                }
+            }
+
+            let display_path = format!("{}/{}", folder_label, file.name);
+            match self.mode {
+                Mode::List(_) | Mode::Devices { .. } => unreachable!(),
+                Mode::Fetch(_) if self.aborted => (),
+                Mode::Fetch(ref check_path) => {
+                    let dest_path = match self.dest_path(
+                            &file.name,
+                            &check_path[folder_label.len() + 1 ..],
+                            &folder_label) {
+                        Some(p) => p,
+                        None => continue
+                    };
+                    if !self.filter.matches(file) {
+                        debug!("skipping {:?}: excluded by filter", display_path);
+                        continue;
+                    }
+                    if let Some(pattern) = self.ignores.ignored_by(&file.name) {
+                        debug!("skipping {:?}: ignored by pattern {:?}", display_path, pattern);
+                        continue;
+                    }
+                    if let Err(e) = stget::names::validate_name(&file.name) {
+                        report!("Error: not fetching {:?}: {}", display_path, e);
+                        self.num_errors += 1;
+                        continue;
+                    }
+
+                    let dest_path = if self.normalize_nfc {
+                        let relative = dest_path.strip_prefix(&self.destination).unwrap();
+                        Path::new(&self.destination)
+                            .join(stget::names::nfc(&relative.to_string_lossy()))
+                    } else {
+                        dest_path
+                    };
+                    let relative = dest_path.strip_prefix(&self.destination).unwrap()
+                        .to_string_lossy().into_owned();
+                    if let Some(other) = self.collisions.check(&relative) {
+                        report!("Warning: {:?} and {:?} differ only by case or Unicode \
+                                   normalization and will collide on case-insensitive \
+                                   filesystems", other, relative);
+                    }
+                    if let Err(e) = self.check_parents(&dest_path) {
+                        report!("Error: not fetching {:?}: {:#}", display_path, e);
+                        self.num_errors += 1;
+                        continue;
+                    }
+
+                    self.num_matched += 1;
+
+                    if self.on_conflict == ConflictPolicy::Fail && !self.dry_run
+                        && !self.index_complete()
+                    {
+                        self.deferred.push(DeferredEntry {
+                            folder_id: index.folder.clone(),
+                            file: file.clone(),
+                            dest_path,
+                            display_path,
+                        });
+                        continue;
+                    }
+                    self.handle_entry(&index.folder, file, dest_path, display_path);
+                }
+            }
+        }
+
+        let last_sequence = match index.files.last() {
+            Some(file) => file.sequence,
+            None => 0,
+        };
+        let folder = self.folders_by_id.get_mut(&index.folder).unwrap();
+        folder.last_sequence = folder.last_sequence.max(last_sequence);
+        if folder.index_complete {
+            // An update for something that changed since.
+            return;
+        }
+        status!("index entries: {} / {}", last_sequence, folder.max_remote_seq);
+
+        if last_sequence >= folder.max_remote_seq {
+            // Note that this assumes nothing changed in between when we got the
+            // cluster config and now.
+            // It also assumes that the files in each message are sorted by
+            // sequence number.
+            debug!("got last index update for this folder");
+            folder.index_complete = true;
+
+            if self.index_complete() {
+                self.start_deferred();
+            }
+            self.fetch_followed_links();
+        }
+    }
+
+    // Note which files another device has, so that it can be asked for their blocks.
+    fn handle_peer_index(&mut self, peer: usize, peer_name: &str, index: &proto::Index) {
+        for file in &index.files {
+            let key = (index.folder.clone(), file.name.clone());
+            let files = self.peer_files.entry(peer).or_default();
+            if file.deleted || file.invalid {
+                files.remove(&key);
+                if let Some(&fetch_id) = self.fetch_ids.get(&key) {
+                    self.scheduler.remove_file_peer(fetch_id, peer);
+                }
+                continue;
+            }
+            files.insert(key.clone(), file.clone());
+
+            if let Some(&fetch_id) = self.fetch_ids.get(&key) {
+                let fetch_state = &self.fetches[&fetch_id];
+                let ordering = stget::version::compare(
+                    file.version.get_or_default(), fetch_state.version.get_or_default());
+                match ordering {
+                    Some(Ordering::Equal) => {
+                        debug!("peer {} also has {:?}", peer, file.name);
+                        self.scheduler.add_file_peer(fetch_id, peer);
+                    }
+                    // The version being fetched only came from other devices, so a newer one
+                    // replaces it.
+                    Some(Ordering::Greater) if !fetch_state.from_remote => {
+                        let display_path = match self.folders_by_id.get(&index.folder) {
+                            Some(folder) => format!("{}/{}", folder.label, file.name),
+                            None => file.name.clone(),
+                        };
+                        self.restart_fetch(fetch_id, file, &display_path, false);
+                    }
+                    _ => {
+                        // Any blocks it sends now might not belong to the version being fetched.
+                        debug!("peer {} has a different version of {:?}", peer, file.name);
+                        self.scheduler.remove_file_peer(fetch_id, peer);
+                    }
+                }
+            } else if let Some((dest_path, display_path)) = self.missing_files.remove(&key) {
+                if FileKind::of(file) == FileKind::File {
+                    status!("fetching {:?} from device {}, since the remote doesn't have a \
+                             valid copy of it", display_path, peer_name);
+                    self.start_fetch(&index.folder, file, dest_path, &display_path, false);
+                } else {
+                    self.missing_files.insert(key, (dest_path, display_path));
+                }
+            }
+        }
+
+        let last_sequence = index.files.last().map(|f| f.sequence).unwrap_or(0);
+        if let Some(pending) = self.peer_index_pending.get_mut(&peer) {
+            if pending.get(&index.folder).map(|max| last_sequence >= *max).unwrap_or(false) {
+                debug!("got the whole index of folder {} from peer {}", index.folder, peer);
+                pending.remove(&index.folder);
+            }
+            if pending.is_empty() {
+                self.peer_index_pending.remove(&peer);
+            }
+        }
+    }
+
+    // A valid copy of a file that another device has.
+    fn peer_file(&self, key: &(String, String)) -> Option<&proto::FileInfo> {
+        let mut peers = self.peer_files.keys().collect::<Vec<_>>();
+        peers.sort();
+        peers.into_iter().find_map(|peer| self.peer_files[peer].get(key))
+    }
+
+    /// Check that none of the directories leading to a path in the destination is a symlink. The
+    /// name has been checked already, but a link created earlier in the run could still lead
+    /// whatever is written there out of the destination.
+    fn check_parents(&self, path: &Path) -> anyhow::Result<()> {
+        match stget::util::symlink_in_parents(Path::new(&self.destination), path)? {
+            Some(link) => anyhow::bail!("{:?} is a symlink", link),
+            None => Ok(()),
+        }
+    }
+
+    /// Create the directories leading to a path in the destination, after checking them with
+    /// `check_parents`. Everything is created through here.
+    fn create_parent_dirs(&self, path: &Path) -> anyhow::Result<()> {
+        self.check_parents(path)?;
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    /// Write an entry from the index that's been checked and will be fetched.
+    fn handle_entry(
+        &mut self,
+        folder_id: &str,
+        file: &proto::FileInfo,
+        dest_path: PathBuf,
+        display_path: String,
+    ) {
+        let kind = FileKind::of(file);
+        if file.invalid && kind == FileKind::File {
+            let key = (folder_id.to_owned(), file.name.clone());
+            if !self.use_cluster {
+                report!("Error: not fetching {:?}: the remote doesn't have a valid copy of it",
+                       display_path);
+                self.num_errors += 1;
+            } else if let Some(peer_file) = self.peer_file(&key).cloned() {
+                self.start_fetch(folder_id, &peer_file, dest_path, &display_path, false);
+            } else {
+                debug!("waiting for another device to provide {:?}", display_path);
+                self.missing_files.insert(key, (dest_path, display_path));
+            }
+            return;
+        }
+
+        match kind {
+            FileKind::Directory => self.create_directory(file, dest_path, &display_path),
+            FileKind::Symlink => self.handle_symlink(folder_id, file, dest_path, &display_path),
+            FileKind::File => self.start_fetch(folder_id, file, dest_path, &display_path, true),
+        }
+    }
+
+    fn create_directory(&mut self, file: &proto::FileInfo, dest_path: PathBuf, display_path: &str) {
+        let result = match self.plan(file, &dest_path, display_path) {
+            None => return,
+            Some(Plan::Create) => Ok(()),
+            Some(Plan::Overwrite) => std::fs::remove_file(&dest_path),
+            Some(Plan::RenameExisting(aside)) => std::fs::rename(&dest_path, aside),
+        };
+        debug!("creating directory {:?} for {:?}", dest_path, display_path);
+        let result = result.map_err(anyhow::Error::from)
+            .and_then(|()| self.create_parent_dirs(&dest_path))
+            .and_then(|()| Ok(std::fs::create_dir_all(&dest_path)?));
+        if let Err(e) = result {
+            report!("Error: failed to create directory {:?}: {:#}", dest_path, e);
+            self.num_errors += 1;
+            return;
+        }
+        let metadata = FileMetadata::new(file, self.preserve_permissions);
+        self.pending_dirs.push((dest_path, metadata));
+    }
+
+    // Create the destination file and hand its blocks to the scheduler.
+    fn start_fetch(
+        &mut self,
+        folder_id: &str,
+        file: &proto::FileInfo,
+        dest_path: PathBuf,
+        display_path: &str,
+        from_remote: bool,
+    ) {
+        debug!("found matching file: {:?}", display_path);
+        let rename_existing_to = match self.plan(file, &dest_path, display_path) {
+            None => return,
+            Some(Plan::RenameExisting(aside)) => Some(aside),
+            Some(Plan::Create) | Some(Plan::Overwrite) => None,
+        };
+        self.fetch_file(folder_id, file, dest_path, rename_existing_to, display_path, from_remote);
+    }
+
+    // A file being downloaded has changed. Start again with the new version, keeping the blocks
+    // that are the same, or give up on it if it's gone.
+    fn restart_fetch(
+        &mut self,
+        fetch_id: usize,
+        file: &proto::FileInfo,
+        display_path: &str,
+        from_remote: bool,
+    ) {
+        let old = self.fetches.remove(&fetch_id).unwrap();
+        self.scheduler.abandon_file(fetch_id);
+        self.fetch_ids.remove(&(old.folder_id.clone(), old.path.clone()));
+
+        if file.deleted || file.invalid || FileKind::of(file) != FileKind::File {
+            self.progress.finished(fetch_id, false);
+            report!("Error: {:?} was removed or replaced on the remote while it was being \
+                       downloaded", display_path);
+            self.num_errors += 1;
+            self.note_failure(Failure::FileVanished);
+            return;
+        }
+        self.progress.remove(fetch_id);
+        status!("{:?} changed while it was being downloaded; downloading the new version",
+                display_path);
+        let FileFetchState { folder_id, dest_path, rename_existing_to, file: fs_file, .. } = old;
+        // Close the temporary file before it's opened again.
+        drop(fs_file);
+        self.fetch_file(
+            &folder_id, file, dest_path, rename_existing_to, display_path, from_remote);
     }
 
-    fn handle_index(
-        &self,
-        index: &proto::Index,
-        session: &mut stget::session::Session,
-        index_recv_state: &mut IndexRecvState,
-        fetch_state: &mut Option<BlockFetchState>,
+    fn fetch_file(
+        &mut self,
+        folder_id: &str,
+        file: &proto::FileInfo,
+        dest_path: PathBuf,
+        rename_existing_to: Option<PathBuf>,
+        display_path: &str,
+        from_remote: bool,
     ) {
-        debug!("remote index: {:#?}", index);
+        status!("requesting file: {:?}", display_path);
+        debug!("destination path: {:?}", dest_path);
 
-        let folder_info = &self.folders_by_id[&index.folder];
+        let metadata = FileMetadata::new(file, self.preserve_permissions);
 
-        for file in &index.files {
-            if file.deleted {
-                continue;
+        if let Err(e) = self.create_parent_dirs(&dest_path) {
+            report!("Error: not fetching {:?}: {:#}", display_path, e);
+            self.num_errors += 1;
+            return;
+        }
+        let temp_path = dest_path.with_file_name(stget::names::temp_name(
+            &dest_path.file_name().unwrap().to_string_lossy()));
+        let (fs_file, have_blocks) = match open_temp_file(&temp_path, &file.blocks) {
+            Ok(result) => result,
+            Err(e) => {
+                report!("Error: unable to open temporary file {:?}: {}", temp_path, e);
+                self.num_errors += 1;
+                return;
             }
-            if file.type_.unwrap() == proto::FileInfoType::DIRECTORY {
-                if let Mode::Fetch(ref check_path) = self.mode {
-                    if !check_path.ends_with('/')
-                         && check_path[folder_info.label.len() + 1 ..] == file.name
-                    {
-                        panic!("Cannot fetch a directory entry. To recursively fetch a whole \
-                                directory, append a '/' to the path.");
-                    }
-                }
-                continue;
+        };
+
+        let have_count = have_blocks.iter().filter(|have| **have).count();
+        if have_count > 0 {
+            status!("resuming {:?}: {} of {} blocks already downloaded",
+                    display_path, have_count, file.blocks.len());
+        }
+
+        let block_state = FileFetchState {
+            file: fs_file,
+            temp_path,
+            metadata,
+            dest_path,
+            rename_existing_to,
+            size: file.size as u64,
+            read_bytes: file.blocks.iter().zip(&have_blocks)
+                .filter(|(_, have)| **have)
+                .map(|(block, _)| block.size as u64)
+                .sum(),
+            all_blocks: file.blocks.clone(),
+            have_blocks,
+            folder_id: folder_id.to_owned(),
+            path: file.name.clone(),
+            version: file.version.clone(),
+            from_remote,
+            problem: None,
+        };
+
+        let fetch_id = self.next_fetch_id;
+        self.next_fetch_id += 1;
+        self.progress.start_file(
+            fetch_id, display_path, block_state.size, block_state.read_bytes);
+
+        if block_state.have_blocks.iter().all(|have| *have) {
+            // Nothing to request.
+            self.finish_file(fetch_id, block_state);
+            return;
+        }
+
+        let key = (folder_id.to_owned(), file.name.clone());
+        let mut peers = vec![];
+        if from_remote && self.scheduler.has_peer(0) {
+            peers.push(0);
+        }
+        for (&peer, files) in &self.peer_files {
+            if files.get(&key).map(|f| &f.version) == Some(&file.version) {
+                peers.push(peer);
             }
+        }
 
-            let display_path = format!("{}/{}", folder_info.label, file.name);
-            match self.mode {
-                Mode::List => {
-                    println!("{}", display_path);
+        let sizes = file.blocks.iter().map(|block| block.size as u64).collect::<Vec<_>>();
+        self.scheduler.add_file(fetch_id, &block_state.have_blocks, &sizes, peers);
+        self.fetches.insert(fetch_id, block_state);
+        self.fetch_ids.insert(key, fetch_id);
+    }
+
+    fn handle_symlink(
+        &mut self,
+        folder_id: &str,
+        file: &proto::FileInfo,
+        dest_path: PathBuf,
+        display_path: &str,
+    ) {
+        let target = &file.symlink_target;
+        match self.symlink_policy {
+            SymlinkPolicy::Skip => {
+                status!("skipping symlink {:?} -> {:?}", display_path, target);
+            }
+            SymlinkPolicy::Create => {
+                if let Err(e) = self.check_parents(&dest_path) {
+                    report!("Error: not creating symlink {:?}: {}", display_path, e);
+                    self.num_errors += 1;
+                    return;
+                }
+                let destination = Path::new(&self.destination);
+                // Links that were already created count too, so this has to look at the
+                // filesystem rather than just the target's text.
+                match stget::util::symlink_stays_within(destination, &dest_path, target) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        report!("refusing to create symlink {:?} -> {:?}: the target is outside \
+                                   the destination", display_path, target);
+                        return;
+                    }
+                    Err(e) => {
+                        report!("Error: not creating symlink {:?}: unable to check where \
+                                   {:?} leads: {}", display_path, target, e);
+                        self.num_errors += 1;
+                        return;
+                    }
                 }
-                Mode::Fetch(ref check_path) => {
-                    let dest_path = match self.dest_path(
-                            &file.name,
-                            &check_path[folder_info.label.len() + 1 ..],
-                            &folder_info.label) {
-                        Some(p) => p,
-                        None => continue
-                    };
 
-                    debug!("found matching file: {:?}", display_path);
-                    eprintln!("requesting file: {:?}", display_path);
-                    debug!("destination path: {:?}", dest_path);
-
-                    std::fs::create_dir_all(dest_path.parent().unwrap()).unwrap();
-                    let fs_file = File::create(dest_path).unwrap();
-
-                    let req_id = session.write_block_request(
-                        index.folder.clone(),
-                        file.name.to_owned(),
-                        file.blocks[0].offset,
-                        file.blocks[0].size,
-                        file.blocks[0].hash.clone()
-                    ).unwrap_or_else(|e| {
-                        panic!("Error sending block request: {}", e);
-                    });
-
-                    let block_state = FileFetchState {
-                        file: fs_file,
-                        size: file.size as u64,
-                        read_bytes: 0,
-                        all_blocks: file.blocks.clone(),
-                        current_outstanding_idx: 0,
-                        folder_id: index.folder.clone(),
-                        path: file.name.clone(),
-                    };
+                let result = match self.plan(file, &dest_path, display_path) {
+                    None => return,
+                    Some(Plan::Create) => Ok(()),
+                    Some(Plan::Overwrite) => std::fs::remove_file(&dest_path),
+                    Some(Plan::RenameExisting(aside)) => std::fs::rename(&dest_path, aside),
+                };
 
-                    if let Some(state) = fetch_state {
-                        state.request_map.insert(req_id, block_state);
-                    } else {
-                        let mut m = HashMap::new();
-                        m.insert(req_id, block_state);
-                        *fetch_state = Some(BlockFetchState { request_map: m });
+                #[allow(deprecated)]
+                let is_dir = file.type_.enum_value_or_default()
+                    == proto::FileInfoType::SYMLINK_DIRECTORY;
+                let result = result.map_err(anyhow::Error::from)
+                    .and_then(|()| self.create_parent_dirs(&dest_path))
+                    .and_then(|()| Ok(stget::util::create_symlink(target, &dest_path, is_dir)?));
+                match result {
+                    Ok(()) => status!("created symlink {:?} -> {:?}", display_path, target),
+                    Err(e) => {
+                        report!("Error: failed to create symlink {:?}: {:#}", dest_path, e);
+                        self.num_errors += 1;
+                    }
+                }
+            }
+            SymlinkPolicy::Follow => {
+                let link_dir = Path::new(&file.name).parent().unwrap_or_else(|| Path::new(""));
+                match stget::util::resolve_relative_path(link_dir, target) {
+                    Some(target_path) => {
+                        self.pending_follows.push(PendingFollow {
+                            folder_id: folder_id.to_owned(),
+                            target_name: target_path.to_string_lossy().replace('\\', "/"),
+                            dest_path,
+                            display_path: display_path.to_owned(),
+                        });
+                    }
+                    None => {
+                        report!("not following symlink {:?} -> {:?}: the target is outside \
+                                   the folder", display_path, target);
                     }
                 }
             }
         }
+    }
 
+    // With the whole index received, fetch the contents of the targets of any symlinks we're
+    // following.
+    fn fetch_followed_links(&mut self) {
+        const MAX_LINK_DEPTH: usize = 8;
 
-        eprintln!("index entries: {} / {}",
-                index.files.last().unwrap().sequence,
-                folder_info.max_remote_seq);
-
-        if index.files[index.files.len() - 1].sequence >= folder_info.max_remote_seq {
-            // Note that this assumes nothing changed in between when we got the
-            // cluster config and now.
-            // It also assumes that the files in each message are sorted by
-            // sequence number.
-            debug!("got last index update for this folder");
-            debug!("index_n = {}; folders_by_id = {}",
-                   index_recv_state.index_n,
-                   self.folders_by_id.len());
+        for follow in std::mem::take(&mut self.pending_follows) {
+            let mut name = follow.target_name.clone();
+            let mut depth = 0;
+            let target = loop {
+                let key = (follow.folder_id.clone(), name.clone());
+                match self.known_files.get(&key) {
+                    Some(file) if FileKind::of(file) == FileKind::Symlink
+                        && depth < MAX_LINK_DEPTH =>
+                    {
+                        let link_dir = Path::new(&file.name).parent()
+                            .unwrap_or_else(|| Path::new(""));
+                        match stget::util::resolve_relative_path(link_dir, &file.symlink_target) {
+                            Some(next) => name = next.to_string_lossy().replace('\\', "/"),
+                            None => break None,
+                        }
+                        depth += 1;
+                    }
+                    other => break other.cloned(),
+                }
+            };
 
-            index_recv_state.index_n += 1;
+            match target {
+                Some(file) if FileKind::of(&file) == FileKind::File => {
+                    self.start_fetch(
+                        &follow.folder_id, &file, follow.dest_path, &follow.display_path, true);
+                }
+                Some(file) if FileKind::of(&file) == FileKind::Directory => {
+                    report!("not following symlink {:?}: following links to directories is \
+                               not supported", follow.display_path);
+                }
+                _ => {
+                    report!("not following symlink {:?}: its target {:?} was not found in the \
+                               index", follow.display_path, follow.target_name);
+                }
+            }
         }
+        self.known_files.clear();
     }
 
     fn handle_response(
-        &self,
-        response: &mut proto::Response,
-        session: &mut stget::session::Session,
-        mut fetch_state: FileFetchState,
-    ) -> Option<(i32, FileFetchState)> {
-
-        fetch_state.read_bytes += response.data.len() as u64;
-        eprintln!("{:?}: received block {} / {} -- {} / {} bytes",
-                  fetch_state.path,
-                  response.id + 1,
-                  fetch_state.all_blocks.len(),
-                  fetch_state.read_bytes,
-                  fetch_state.size);
-
-        match response.code.unwrap() {
-            proto::ErrorCode::NO_ERROR => (),
+        &mut self,
+        response: &proto::Response,
+        peer: usize,
+        peer_name: &str,
+        fetch_id: usize,
+        idx: usize,
+    ) {
+        if !self.scheduler.response(peer, fetch_id, idx, Instant::now()) {
+            debug!("block {} of fetch {} from {} is no longer needed", idx, fetch_id, peer_name);
+            return;
+        }
+        let fetch_state = self.fetches.get_mut(&fetch_id).unwrap();
+
+        // Any of these can happen because the file is changing on the device, so the request is
+        // tried again after a while, by when an index update with the new version may have
+        // arrived.
+        let problem = match response.code.enum_value_or_default() {
+            proto::ErrorCode::NO_ERROR => {
+                if block_hash_matches(&fetch_state.all_blocks[idx], &response.data) {
+                    None
+                } else {
+                    Some((Failure::Integrity, "sent data that does not match the block's hash; \
+                                               the file may have changed on the remote"))
+                }
+            }
             proto::ErrorCode::GENERIC => {
-                eprintln!("Error: remote host says there is some unspecified error");
-                return None;
+                Some((Failure::Other, "says there is some unspecified error"))
             }
             proto::ErrorCode::NO_SUCH_FILE => {
-                eprintln!("Error: remote host says there is no such file");
-                return None;
+                Some((Failure::FileVanished, "says there is no such file"))
             }
-            proto::ErrorCode::INVALID_FILE => {
-                eprintln!("Error: remote host says invalid file");
-                return None;
+            proto::ErrorCode::INVALID_FILE => Some((Failure::FileVanished, "says invalid file")),
+        };
+        if let Some((failure, problem)) = problem {
+            let repeated = fetch_state.problem == Some(failure);
+            fetch_state.problem = Some(failure);
+            if self.scheduler.retry_later(peer, fetch_id, idx, Instant::now()) {
+                // Once per file is enough, since it's probably the same for every block.
+                if !repeated {
+                    report!("{:?}: block {}: device {} {}; will try again",
+                           fetch_state.path, idx, peer_name, problem);
+                }
+            } else {
+                report!("{:?}: block {}: device {} {}",
+                       fetch_state.path, idx, peer_name, problem);
+                self.scheduler.failed(peer, fetch_id, idx);
             }
+            return;
+        }
+
+        if let Err(e) = fetch_state.write_block(idx, &response.data) {
+            let reason = format!("failed to write to {:?}: {}", fetch_state.temp_path, e);
+            report!("Error: {}", reason);
+            self.num_errors += 1;
+            self.abandon_fetch(fetch_id, reason);
+            return;
         }
 
-        std::io::Write::write_all(&mut fetch_state.file, &response.data)
-            .expect("file write error");
+        self.progress.received(fetch_id, response.data.len() as u64, Instant::now());
 
-        if fetch_state.current_outstanding_idx == fetch_state.all_blocks.len() - 1 {
-            assert_eq!(fetch_state.size, fetch_state.read_bytes);
-            eprintln!("fetched {} bytes", fetch_state.size);
-            return None;
+        if self.scheduler.completed(fetch_id, idx) {
+            let fetch_state = self.fetches.remove(&fetch_id).unwrap();
+            self.fetch_ids.remove(&(fetch_state.folder_id.clone(), fetch_state.path.clone()));
+            self.finish_file(fetch_id, fetch_state);
         }
+    }
 
-        let idx = fetch_state.current_outstanding_idx + 1;
-        fetch_state.current_outstanding_idx += 1;
-        let req_id = session.write_block_request(
-            fetch_state.folder_id.clone(),
-            fetch_state.path.clone(),
-            fetch_state.all_blocks[idx].offset,
-            fetch_state.all_blocks[idx].size,
-            fetch_state.all_blocks[idx].hash.clone()
-        ).unwrap_or_else(|e| {
-            panic!("Error sending block request: {}", e);
-        });
+    fn finish_file(&mut self, fetch_id: usize, fetch_state: FileFetchState) {
+        let folder = fetch_state.folder_id.clone();
+        let name = fetch_state.path.clone();
+        if fetch_state.read_bytes != fetch_state.size {
+            let reason = format!("got {} bytes, but expected {}",
+                                 fetch_state.read_bytes, fetch_state.size);
+            report!("Error: {:?}: {}", fetch_state.path, reason);
+            self.num_errors += 1;
+            self.note_failure(Failure::Integrity);
+            self.progress.finished(fetch_id, false);
+            self.file_events.push(Event::FileFailed { folder, name, reason });
+            return;
+        }
+        let size = fetch_state.size;
+        let dest_path = fetch_state.dest_path.clone();
+        // The directories were checked when the download started, but a link could have been
+        // created in their place since.
+        match self.check_parents(&dest_path).and_then(|()| Ok(fetch_state.finish()?)) {
+            Ok(()) => {
+                self.progress.finished(fetch_id, true);
+                status!("fetched {:?}: {} bytes", dest_path, size);
+                self.file_events.push(Event::FileFinished { folder, name, size });
+            }
+            Err(e) => {
+                self.progress.finished(fetch_id, false);
+                report!("Error: failed to move the download into place at {:?}: {:#}",
+                       dest_path, e);
+                self.num_errors += 1;
+                let reason = format!("{:#}", e);
+                self.file_events.push(Event::FileFailed { folder, name, reason });
+            }
+        }
+    }
+}
+
+/// A `ProgramState` for a test, as if from the command line with no options, fetching into a
+/// new temporary directory (which is returned too) from the remote, as device 0.
+#[cfg(test)]
+fn test_state(name: &str) -> (ProgramState, PathBuf) {
+    let destination = std::env::temp_dir()
+        .join(format!("stget-{}-test-{}", name, std::process::id()));
+    // Anything left behind by an earlier run that failed would get in the way.
+    let _ = std::fs::remove_dir_all(&destination);
+    let mut state = ProgramState {
+        remote_cert_hash: vec![],
+        local_cert_hash: vec![],
+        folders_by_id: HashMap::new(),
+        mode: Mode::Fetch("F/".to_owned()),
+        filter: FileFilter::default(),
+        ignores: IgnorePatterns::new(),
+        preserve_permissions: false,
+        num_matched: 0,
+        pending_dirs: vec![],
+        symlink_policy: SymlinkPolicy::Create,
+        pending_follows: vec![],
+        deferred: vec![],
+        aborted: false,
+        known_files: HashMap::new(),
+        normalize_nfc: false,
+        on_conflict: ConflictPolicy::Overwrite,
+        dry_run: false,
+        collisions: CollisionDetector::new(),
+        num_errors: 0,
+        failure: None,
+        remote_closed: false,
+        can_reconnect: false,
+        reconnecting: false,
+        handled_versions: HashMap::new(),
+        destination: destination.to_string_lossy().into_owned(),
+        fetches: HashMap::new(),
+        next_fetch_id: 0,
+        fetch_ids: HashMap::new(),
+        scheduler: BlockScheduler::new(),
+        peer_files: HashMap::new(),
+        peer_index_pending: HashMap::new(),
+        peers_connecting: 0,
+        use_cluster: false,
+        discovery_servers: vec![],
+        proxy: None,
+        remember_remote: None,
+        missing_files: HashMap::new(),
+        cluster_config: None,
+        progress: Progress::new(false),
+        device_addresses: HashMap::new(),
+        device_rate_limits: HashMap::new(),
+        file_events: vec![],
+    };
+    state.scheduler.add_peer(0);
+    (state, destination)
+}
 
-        Some((req_id, fetch_state))
+/// An index entry for a file made of the given blocks.
+#[cfg(test)]
+fn test_file(name: &str, blocks: &[&[u8]]) -> proto::FileInfo {
+    let mut file = proto::FileInfo::new();
+    file.name = name.to_owned();
+    let mut offset = 0;
+    for data in blocks {
+        let mut block = proto::BlockInfo::new();
+        block.offset = offset;
+        block.size = data.len() as i32;
+        block.hash = ring::digest::digest(&ring::digest::SHA256, data).as_ref().to_vec();
+        file.blocks.push(block);
+        offset += data.len() as i64;
     }
+    file.size = offset;
+    file
 }
 
-#[allow(dead_code)]
-fn hexdump(data: &[u8]) {
-    for i in 0 ..= (data.len() / 16) {
-        eprint!("{:04x}  ", i * 16);
-        for h in 0 .. 16 {
-            if i * 16 + h < data.len() {
-                eprint!("{:02x} ", data[i * 16 + h]);
-            } else {
-                eprint!("   ");
+/// Start fetching a file made of the given blocks, which the remote (0) and device 1 both have.
+/// Returns the fetch ID and the file's contents.
+#[cfg(test)]
+fn start_test_fetch(state: &mut ProgramState, blocks: &[&[u8]]) -> (usize, Vec<u8>) {
+    let file = test_file("file", blocks);
+
+    state.scheduler.add_peer(1);
+    let key = ("folder".to_owned(), file.name.clone());
+    state.peer_files.entry(1).or_default().insert(key.clone(), file.clone());
+    let dest_path = Path::new(&state.destination).join("file");
+    state.fetch_file("folder", &file, dest_path, None, "F/file", true);
+    (state.fetch_ids[&key], blocks.concat())
+}
+
+#[cfg(test)]
+fn test_response(data: &[u8], code: proto::ErrorCode) -> proto::Response {
+    let mut response = proto::Response::new();
+    response.data = data.to_vec();
+    response.code = code.into();
+    response
+}
+
+/// Passes what's received straight through, and keeps what's sent, so that tests can feed a
+/// connection messages.
+#[cfg(test)]
+struct TestTransport {
+    sent: Arc<std::sync::Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl stget::transport::Transport for TestTransport {
+    fn handshake(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn writer(&mut self) -> Box<dyn std::io::Write + '_> {
+        struct SharedWriter<'a>(&'a std::sync::Mutex<Vec<u8>>);
+        impl std::io::Write for SharedWriter<'_> {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
             }
-            if h == 8 {
-                eprint!(" ");
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
             }
         }
-        eprint!("  |");
-        for h in 0 .. 16 {
-            if i * 16 + h < data.len() {
-                let c = match data[i * 16 + h] {
-                    0..=0x19 | 0x7f..=0xff => '.',
-                    other => other as char,
-                };
-                eprint!("{}", c);
-            } else {
-                eprint!(" ");
+        Box::new(SharedWriter(&self.sent))
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn raw_reader(&mut self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
+        anyhow::bail!("not supported");
+    }
+
+    fn receive(&mut self, raw: &[u8], data: &mut Vec<u8>) -> anyhow::Result<usize> {
+        data.extend_from_slice(raw);
+        Ok(raw.len())
+    }
+
+    fn peer_certificate(&self) -> Option<rustls::Certificate> {
+        None
+    }
+}
+
+#[cfg(test)]
+fn test_session() -> Session {
+    let sent = Arc::new(std::sync::Mutex::new(vec![]));
+    Session::new(Box::new(TestTransport { sent }), "test".to_owned())
+}
+
+/// What a device sends: its hello if `message` is None, or else the message.
+#[cfg(test)]
+fn test_data<M: protobuf::Message>(message: Option<(&M, proto::MessageType)>) -> Vec<u8> {
+    let sent = Arc::new(std::sync::Mutex::new(vec![]));
+    let mut session = Session::new(
+        Box::new(TestTransport { sent: sent.clone() }), "device".to_owned());
+    match message {
+        Some((message, msgtype)) => session.write_message(message, msgtype).unwrap(),
+        None => session.write_hello().unwrap(),
+    }
+    let data = sent.lock().unwrap().clone();
+    data
+}
+
+#[test]
+fn test_bad_peer_is_disconnected() {
+    let (mut state, _) = test_state("bad-peer");
+    let mut connections = ["REMOTE0", "DEVICE1", "DEVICE2"].iter()
+        .map(|id| Connection::new(test_session(), id))
+        .collect::<Vec<_>>();
+    connections[0].state = Some(State::IndexOrBlocks);
+
+    // Not a hello: the magic number is wrong.
+    state.handle_network_data(&mut connections, 1, Ok(vec![0; 6]));
+    assert!(!connections[1].connected);
+
+    // A hello, but then something other than a cluster config.
+    state.handle_network_data(&mut connections, 2, Ok(test_data::<proto::Ping>(None)));
+    assert!(connections[2].connected);
+    let ping = test_data(Some((&proto::Ping::new(), proto::MessageType::PING)));
+    state.handle_network_data(&mut connections, 2, Ok(ping));
+    assert!(!connections[2].connected);
+
+    // The remote carries on.
+    assert!(connections[0].connected);
+    assert!(state.scheduler.has_peer(0));
+    assert!(!state.remote_closed);
+}
+
+#[test]
+fn test_reconnect() {
+    let (mut state, dir) = test_state("reconnect");
+    state.can_reconnect = true;
+    state.local_cert_hash = vec![1; 32];
+    state.remote_cert_hash = vec![2; 32];
+
+    let mut config = proto::ClusterConfig::new();
+    let mut folder = proto::Folder::new();
+    folder.id = "folder".to_owned();
+    folder.label = "F".to_owned();
+    for (id, max_sequence) in [(vec![1; 32], 0), (vec![2; 32], 2)] {
+        let mut device = proto::Device::new();
+        device.id = id;
+        device.index_id = 5;
+        device.max_sequence = max_sequence;
+        folder.devices.push(device);
+    }
+    config.folders.push(folder);
+    let config = test_data(Some((&config, proto::MessageType::CLUSTER_CONFIG)));
+
+    let small: [&[u8]; 1] = [b"small"];
+    let big: [&[u8]; 2] = [b"first block", b"second block"];
+    let mut index = proto::Index::new();
+    index.folder = "folder".to_owned();
+    for (sequence, file) in [(1, test_file("small", &small)), (2, test_file("big", &big))] {
+        let mut file = file;
+        file.sequence = sequence;
+        file.version.mut_or_insert_default().counters.push(proto::Counter {
+            id: 1,
+            value: 1,
+            ..Default::default()
+        });
+        index.files.push(file);
+    }
+    let index = test_data(Some((&index, proto::MessageType::INDEX)));
+
+    let respond = |state: &mut ProgramState, connections: &mut [Connection], generation| {
+        let requests = connections[0].requests.clone();
+        for (id, (fetch_id, block)) in requests {
+            let name = &state.fetches[&fetch_id].path;
+            let data = if name == "small" { small[block] } else { big[block] };
+            // The second block of the big file never arrives on the first connection.
+            if generation == 0 && name == "big" && block == 1 {
+                continue;
+            }
+            let mut response = test_response(data, proto::ErrorCode::NO_ERROR);
+            response.id = id;
+            let response = test_data(Some((&response, proto::MessageType::RESPONSE)));
+            state.handle_received(connections, (0, generation), Ok(response));
+        }
+    };
+
+    let mut connections = vec![Connection::new(test_session(), "REMOTE0")];
+    for data in [test_data::<proto::Ping>(None), config.clone(), index.clone()] {
+        state.handle_received(&mut connections, (0, 0), Ok(data));
+    }
+    assert_eq!(2, state.fetches.len());
+    state.send_requests(&mut connections);
+    assert_eq!(3, connections[0].requests.len());
+    respond(&mut state, &mut connections, 0);
+    assert_eq!(1, state.fetches.len());
+
+    // The connection is lost, and replaced by a new one.
+    let lost = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+    state.handle_received(&mut connections, (0, 0), Err(lost));
+    assert!(state.reconnecting);
+    assert!(!state.scheduler.has_peer(0));
+    connections[0] = Connection { generation: 1, ..Connection::new(test_session(), "REMOTE0") };
+
+    // Anything left over from the old connection is ignored.
+    state.handle_received(&mut connections, (0, 0), Ok(vec![0; 6]));
+    assert!(connections[0].connected);
+
+    // A reconnection that goes wrong is just another lost connection.
+    let ping = test_data(Some((&proto::Ping::new(), proto::MessageType::PING)));
+    for data in [test_data::<proto::Ping>(None), ping] {
+        state.handle_received(&mut connections, (0, 1), Ok(data));
+    }
+    assert!(!connections[0].connected);
+    assert!(state.reconnecting);
+    connections[0] = Connection { generation: 2, ..Connection::new(test_session(), "REMOTE0") };
+
+    // The remote sends its whole index again, but only the block that was never received is
+    // asked for again.
+    for data in [test_data::<proto::Ping>(None), config, index] {
+        state.handle_received(&mut connections, (0, 2), Ok(data));
+    }
+    assert!(!state.reconnecting);
+    assert_eq!(1, state.fetches.len());
+    state.send_requests(&mut connections);
+    assert_eq!(vec![1], connections[0].requests.values().map(|r| r.1).collect::<Vec<_>>());
+    respond(&mut state, &mut connections, 2);
+
+    assert!(state.fetches.is_empty());
+    assert_eq!(0, state.num_errors);
+    assert_eq!(big.concat(), std::fs::read(dir.join("F/big")).unwrap());
+    assert_eq!(small.concat(), std::fs::read(dir.join("F/small")).unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_file_events() {
+    let (mut state, dir) = test_state("events");
+    let mut connections = vec![Connection::new(test_session(), "REMOTE0")];
+    let events = Arc::new(std::sync::Mutex::new(vec![]));
+    let seen = events.clone();
+    connections[0].session.set_observer(Arc::new(move |event: &Event| {
+        if matches!(event, Event::FileFinished { .. } | Event::FileFailed { .. }) {
+            seen.lock().unwrap().push(event.clone());
+        }
+    }));
+
+    // One file is fetched, and another can't be, as the remote has run out of tries for it.
+    let blocks: [&[u8]; 1] = [b"contents"];
+    for name in ["file", "gone"] {
+        state.fetch_file("folder", &test_file(name, &blocks), dir.join(name), None, name, true);
+    }
+    for assignment in state.scheduler.assign(Instant::now()) {
+        if state.fetches[&assignment.file].path == "file" {
+            let response = test_response(blocks[0], proto::ErrorCode::NO_ERROR);
+            state.handle_response(&response, 0, "remote", assignment.file, assignment.block);
+        } else {
+            state.scheduler.response(0, assignment.file, assignment.block, Instant::now());
+            state.scheduler.failed(0, assignment.file, assignment.block);
+        }
+    }
+    state.fail_stalled_files();
+    state.send_file_events(&connections);
+
+    assert_eq!(
+        vec![
+            Event::FileFinished {
+                folder: "folder".to_owned(),
+                name: "file".to_owned(),
+                size: blocks[0].len() as u64,
+            },
+            Event::FileFailed {
+                folder: "folder".to_owned(),
+                name: "gone".to_owned(),
+                reason: "no device could provide block 0".to_owned(),
+            },
+        ],
+        *events.lock().unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_resume_from_temp_file() {
+    let (mut state, dir) = test_state("resume");
+    let blocks: [&[u8]; 2] = [b"first block", b"second block"];
+
+    // A previous run got the first block, but not the second, and left some junk at the end.
+    std::fs::create_dir_all(&dir).unwrap();
+    let temp_path = dir.join(stget::names::temp_name("file"));
+    std::fs::write(&temp_path, b"first blockSECOND BLOCKjunk").unwrap();
+
+    let (fetch_id, contents) = start_test_fetch(&mut state, &blocks);
+    let assignments = state.scheduler.assign(Instant::now());
+    assert_eq!(vec![1], assignments.iter().map(|a| a.block).collect::<Vec<_>>());
+    let response = test_response(blocks[1], proto::ErrorCode::NO_ERROR);
+    state.handle_response(&response, assignments[0].peer, "device", fetch_id, 1);
+
+    // The finished file is moved into place, without the junk.
+    assert!(state.fetches.is_empty());
+    assert_eq!(0, state.num_errors);
+    assert_eq!(contents, std::fs::read(dir.join("file")).unwrap());
+    assert!(!temp_path.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_restart_fetch() {
+    let (mut state, dir) = test_state("restart");
+    let blocks: [&[u8]; 2] = [b"first block", b"second block"];
+    let (fetch_id, _) = start_test_fetch(&mut state, &blocks);
+
+    // Only the first block arrives before the file changes.
+    let assignments = state.scheduler.assign(Instant::now());
+    let first = assignments.iter().find(|a| a.block == 0).unwrap();
+    let response = test_response(blocks[0], proto::ErrorCode::NO_ERROR);
+    state.handle_response(&response, first.peer, "device", fetch_id, 0);
+
+    // The new version keeps the first block, but not the second.
+    let changed: [&[u8]; 2] = [b"first block", b"changed second block"];
+    state.restart_fetch(fetch_id, &test_file("file", &changed), "F/file", true);
+    let fetch_id = state.fetch_ids[&("folder".to_owned(), "file".to_owned())];
+    let assignments = state.scheduler.assign(Instant::now());
+    assert_eq!(vec![1], assignments.iter().map(|a| a.block).collect::<Vec<_>>());
+    let response = test_response(changed[1], proto::ErrorCode::NO_ERROR);
+    state.handle_response(&response, assignments[0].peer, "device", fetch_id, 1);
+
+    assert!(state.fetches.is_empty());
+    assert_eq!(0, state.num_errors);
+    assert_eq!(changed.concat(), std::fs::read(dir.join("file")).unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_restart_fetch_of_deleted_file() {
+    let (mut state, dir) = test_state("restart-deleted");
+    let (fetch_id, _) = start_test_fetch(&mut state, &[b"first block", b"second block"]);
+    let mut deleted = test_file("file", &[]);
+    deleted.deleted = true;
+    state.restart_fetch(fetch_id, &deleted, "F/file", true);
+    assert!(state.fetches.is_empty());
+    assert!(state.scheduler.assign(Instant::now()).is_empty());
+    assert_eq!(1, state.num_errors);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_block_retried_on_other_peer() {
+    let (mut state, dir) = test_state("retry");
+    let blocks: [&[u8]; 2] = [b"first block", b"second block"];
+    let (fetch_id, contents) = start_test_fetch(&mut state, &blocks);
+
+    let assignments = state.scheduler.assign(Instant::now());
+    assert_eq!(2, assignments.len());
+    for assignment in &assignments {
+        let response = if assignment.block == 0 {
+            test_response(&[], proto::ErrorCode::NO_SUCH_FILE)
+        } else {
+            test_response(blocks[1], proto::ErrorCode::NO_ERROR)
+        };
+        state.handle_response(&response, assignment.peer, "device", fetch_id, assignment.block);
+    }
+
+    // The device that couldn't provide the block has to wait, but the other one gets asked now.
+    let failed_peer = assignments.iter().find(|a| a.block == 0).unwrap().peer;
+    let retries = state.scheduler.assign(Instant::now());
+    assert_eq!(vec![(1 - failed_peer, 0)],
+               retries.iter().map(|a| (a.peer, a.block)).collect::<Vec<_>>());
+    state.handle_response(
+        &test_response(blocks[0], proto::ErrorCode::NO_ERROR), 1 - failed_peer, "device",
+        fetch_id, 0);
+
+    assert!(state.fetches.is_empty());
+    assert_eq!(0, state.num_errors);
+    assert_eq!(contents, std::fs::read(dir.join("file")).unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_peer_disconnects_with_requests_open() {
+    let (mut state, dir) = test_state("disconnect");
+    let blocks: [&[u8]; 4] = [b"one", b"two", b"three", b"four"];
+    let (fetch_id, contents) = start_test_fetch(&mut state, &blocks);
+
+    let assignments = state.scheduler.assign(Instant::now());
+    assert!(assignments.iter().any(|a| a.peer == 1));
+    // Device 1 goes away before answering; the remote answers everything it was asked for.
+    state.forget_peer(1);
+    for assignment in assignments.iter().filter(|a| a.peer == 0) {
+        let response = test_response(blocks[assignment.block], proto::ErrorCode::NO_ERROR);
+        state.handle_response(&response, 0, "remote", fetch_id, assignment.block);
+    }
+
+    // What device 1 was asked for goes to the remote instead.
+    let reassigned = state.scheduler.assign(Instant::now());
+    assert!(reassigned.iter().all(|a| a.peer == 0));
+    assert_eq!(assignments.iter().filter(|a| a.peer == 1).map(|a| a.block).collect::<Vec<_>>(),
+               reassigned.iter().map(|a| a.block).collect::<Vec<_>>());
+    for assignment in reassigned {
+        let response = test_response(blocks[assignment.block], proto::ErrorCode::NO_ERROR);
+        state.handle_response(&response, 0, "remote", fetch_id, assignment.block);
+    }
+
+    assert!(state.fetches.is_empty());
+    assert_eq!(0, state.num_errors);
+    assert_eq!(contents, std::fs::read(dir.join("file")).unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_conflict_path() {
+    let now = std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    for (path, expected) in [
+        ("photo.jpg", "photo.sync-conflict-20231114-221320.jpg"),
+        ("dir/archive.tar.gz", "dir/archive.tar.sync-conflict-20231114-221320.gz"),
+        ("README", "README.sync-conflict-20231114-221320"),
+        ("home/.bashrc", "home/.sync-conflict-20231114-221320.bashrc"),
+    ] {
+        assert_eq!(Path::new(expected), conflict_path(Path::new(path), now), "{}", path);
+    }
+}
+
+#[test]
+fn test_decide() {
+    use ConflictPolicy::*;
+
+    let (mut state, dir) = test_state("decide");
+    std::fs::create_dir_all(dir.join("dir")).unwrap();
+    std::fs::write(dir.join("file"), b"local").unwrap();
+    stget::util::set_path_mtime(&dir.join("file"), 1000, 0).unwrap();
+
+    let remote_file = |modified_s, contents: &[u8]| {
+        let mut file = test_file("file", &[contents]);
+        file.modified_s = modified_s;
+        file
+    };
+    let mut remote_dir = proto::FileInfo::new();
+    remote_dir.type_ = proto::FileInfoType::DIRECTORY.into();
+
+    let renamed = |name: &str| Ok(Plan::RenameExisting(dir.join(name)));
+    let same = remote_file(1000, b"local");
+    for (policy, file, dest, expected) in [
+        // Nothing is in the way.
+        (Skip, &same, "missing", Ok(Plan::Create)),
+        (Overwrite, &same, "missing", Ok(Plan::Create)),
+        (Rename, &same, "missing", Ok(Plan::Create)),
+        (Newer, &same, "missing", Ok(Plan::Create)),
+        (Fail, &same, "missing", Ok(Plan::Create)),
+        // A file is in the way.
+        (Skip, &same, "file", Err(Refusal::Skip("it already exists"))),
+        (Overwrite, &same, "file", Ok(Plan::Overwrite)),
+        (Rename, &same, "file", renamed("file.sync-conflict-")),
+        (Fail, &same, "file", Err(Refusal::Conflict)),
+        (Newer, &same, "file", Err(Refusal::Skip("the local copy is up to date"))),
+        (Newer, &remote_file(1000, b"longer"), "file", Ok(Plan::Overwrite)),
+        (Newer, &remote_file(999, b"local"), "file",
+         Err(Refusal::Skip("the local copy is newer"))),
+        (Newer, &remote_file(1001, b"local"), "file", Ok(Plan::Overwrite)),
+        // A directory is in the way of a file.
+        (Skip, &same, "dir", Err(Refusal::Skip("it already exists"))),
+        (Overwrite, &same, "dir", Err(Refusal::Error("a directory is in the way"))),
+        (Rename, &same, "dir", renamed("dir.sync-conflict-")),
+        (Fail, &same, "dir", Err(Refusal::Conflict)),
+        (Newer, &same, "dir", Err(Refusal::Error("a directory is in the way"))),
+        // A directory is fetched into an existing one.
+        (Skip, &remote_dir, "dir", Ok(Plan::Create)),
+        (Overwrite, &remote_dir, "dir", Ok(Plan::Create)),
+        (Rename, &remote_dir, "dir", Ok(Plan::Create)),
+        (Newer, &remote_dir, "dir", Ok(Plan::Create)),
+        (Fail, &remote_dir, "dir", Ok(Plan::Create)),
+    ] {
+        state.on_conflict = policy;
+        let result = state.decide(file, &dir.join(dest));
+        let context = format!("{:?} with {:?} in the way", policy, dest);
+        match (result, expected) {
+            // The conflict name has the time in it; just check the start.
+            (Ok(Plan::RenameExisting(aside)), Ok(Plan::RenameExisting(prefix))) => {
+                assert!(aside.to_string_lossy().starts_with(&*prefix.to_string_lossy()),
+                        "{}: {:?}", context, aside);
             }
+            (result, expected) => assert_eq!(expected, result, "{}", context),
+        }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_plan() {
+    let (mut state, dir) = test_state("plan");
+    std::fs::create_dir_all(dir.join("dir")).unwrap();
+    std::fs::write(dir.join("file"), b"local").unwrap();
+    let file = test_file("file", &[b"remote"]);
+
+    // A dry run only says what would happen, whatever it is.
+    state.dry_run = true;
+    for policy in [ConflictPolicy::Fail, ConflictPolicy::Overwrite, ConflictPolicy::Skip] {
+        state.on_conflict = policy;
+        for dest in ["missing", "file", "dir"] {
+            assert_eq!(None, state.plan(&file, &dir.join(dest), dest), "{:?} {}", policy, dest);
         }
-        eprintln!("|");
     }
+    assert!(!state.aborted);
+    assert_eq!(0, state.num_errors);
+
+    state.dry_run = false;
+    state.on_conflict = ConflictPolicy::Skip;
+    assert_eq!(None, state.plan(&file, &dir.join("file"), "file"));
+    assert_eq!(0, state.num_errors);
+    state.on_conflict = ConflictPolicy::Overwrite;
+    assert_eq!(Some(Plan::Overwrite), state.plan(&file, &dir.join("file"), "file"));
+    assert_eq!(None, state.plan(&file, &dir.join("dir"), "dir"));
+    assert_eq!(1, state.num_errors);
+    assert!(!state.aborted);
+
+    // Failing on a conflict stops everything.
+    state.on_conflict = ConflictPolicy::Fail;
+    assert_eq!(None, state.plan(&file, &dir.join("file"), "file"));
+    assert_eq!(2, state.num_errors);
+    assert!(state.aborted);
+
+    // Nothing was touched.
+    assert_eq!(b"local", &std::fs::read(dir.join("file")).unwrap()[..]);
+    assert!(!dir.join("missing").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_progress_json() {
+    let start = Instant::now();
+    let mut progress = Progress::new(true);
+    progress.start_file(0, "F/one", 100, 0);
+    progress.start_file(1, "F/two", 50, 20);
+    progress.received(0, 40, start);
+    progress.tick(start + Duration::from_secs(2));
+    progress.received(0, 60, start + Duration::from_secs(2));
+    progress.finished(0, true);
+    progress.finished(1, false);
+    progress.finish(start + Duration::from_secs(3));
+
+    let events = progress.printed.iter()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    // Each event's name, and the fields it has.
+    let summary = |event: &serde_json::Value| {
+        let mut fields = event.as_object().unwrap().keys().cloned().collect::<Vec<_>>();
+        fields.sort();
+        format!("{}: {}", event["event"].as_str().unwrap(), fields.join(" "))
+    };
+    assert_eq!(
+        vec![
+            "file_started: bytes event path size",
+            "file_started: bytes event path size",
+            "progress: bytes bytes_per_sec eta_secs event files size",
+            "file_finished: event path size",
+            "file_failed: event path size",
+            "done: bytes_received event files_failed files_finished secs",
+        ],
+        events.iter().map(summary).collect::<Vec<_>>());
+
+    let progress_event = &events[2];
+    assert_eq!(150, progress_event["size"]);
+    assert_eq!(60, progress_event["bytes"]);
+    assert_eq!(20, progress_event["bytes_per_sec"]);
+    assert_eq!(5.0, progress_event["eta_secs"]);
+    assert_eq!(serde_json::json!([
+        {"path": "F/one", "size": 100, "bytes": 40},
+        {"path": "F/two", "size": 50, "bytes": 20},
+    ]), progress_event["files"]);
+    assert_eq!("F/one", events[3]["path"]);
+    assert_eq!(100, events[3]["size"]);
+    assert_eq!(serde_json::json!({
+        "event": "done",
+        "files_finished": 1,
+        "files_failed": 1,
+        "bytes_received": 100,
+        "secs": 3.0,
+    }), events[5]);
+}
+
+#[test]
+fn test_long_entry() {
+    let mut file = test_file("dir/file", &[b"first block", b"second block"]);
+    file.permissions = 0o644;
+    file.modified_s = 1_700_000_000;
+    file.modified_by = 0x48cb_dec7_b082_437a;
+    file.version.mut_or_insert_default().counters.push(proto::Counter {
+        id: 0x48cb_dec7_b082_437a,
+        value: 3,
+        ..Default::default()
+    });
+    assert_eq!(
+        "-rw-r--r--             23 2023-11-14 22:13:20      2 JDF55R5 {JDF55R5:3}              \
+         F/dir/file",
+        long_entry(&file, "F/dir/file"));
+
+    // Big files and times outside 1970-9999 widen their columns rather than being cut short.
+    let mut dir = proto::FileInfo::new();
+    dir.type_ = proto::FileInfoType::DIRECTORY.into();
+    dir.permissions = 0o755;
+    dir.size = 123_456_789_012_345_678;
+    dir.modified_s = -1;
+    assert_eq!(
+        "drwxr-xr-x 123456789012345678 1969-12-31 23:59:59      0 AAAAAAA {}                       \
+         F/dir",
+        long_entry(&dir, "F/dir"));
+    dir.modified_s = 253_402_300_800;
+    assert!(long_entry(&dir, "F/dir").contains(" 10000-01-01 00:00:00 "));
+}
+
+#[test]
+fn test_permissions_string() {
+    let mut file = proto::FileInfo::new();
+    for (permissions, expected) in [
+        (0o644, "rw-r--r--"),
+        (0o755, "rwxr-xr-x"),
+        (0o4750, "rwxr-x---"),
+        (0, "---------"),
+    ] {
+        file.permissions = permissions;
+        assert_eq!(expected, permissions_string(&file));
+    }
+    file.no_permissions = true;
+    assert_eq!("?????????", permissions_string(&file));
+}
+
+#[test]
+fn test_file_json() {
+    let mut file = test_file("dir/file", &[b"contents"]);
+    file.permissions = 0o644;
+    file.modified_s = 1_700_000_000;
+    file.modified_ns = 5;
+    let json = file_json("F", "folder-id", &file);
+    assert_eq!("2023-11-14 22:13:20", json["modified"]);
+    assert_eq!(1_700_000_000, json["modified_s"]);
+    assert_eq!(8, json["size"]);
+    assert_eq!("0644", json["permissions"]);
+    assert_eq!("file", json["type"]);
+    assert_eq!(serde_json::Value::Null, json["symlink_target"]);
+    file.no_permissions = true;
+    assert_eq!(serde_json::Value::Null, file_json("F", "folder-id", &file)["permissions"]);
 }
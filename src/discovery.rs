@@ -0,0 +1,238 @@
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, NetworkEndian};
+use crate::syncthing_proto;
+use protobuf::Message;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// The UDP port Syncthing devices announce themselves on.
+pub const LOCAL_DISCOVERY_PORT: u16 = 21027;
+
+const ANNOUNCE_MAGIC: u32 = 0x2ea7_d90b;
+
+/// Syncthing announces itself to this multicast group on IPv6, and with broadcasts on IPv4.
+const IPV6_MULTICAST_GROUP: Ipv6Addr = Ipv6Addr::new(0xff12, 0, 0, 0, 0, 0, 0, 0x8384);
+
+// How often the listening threads check whether they should give up.
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A local discovery packet: a device saying which addresses it can be reached at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    /// The SHA-256 hash of the device's certificate.
+    pub device_hash: Vec<u8>,
+    pub addresses: Vec<String>,
+    /// Random number picked when the device starts, so others can tell it restarted.
+    pub instance_id: i64,
+}
+
+impl Announcement {
+    /// Parse a packet received from `sender`. Addresses without a host (like
+    /// `tcp://0.0.0.0:22000`, which Syncthing sends when it listens on all interfaces) get the
+    /// sender's address filled in.
+    pub fn parse(packet: &[u8], sender: IpAddr) -> Result<Announcement> {
+        if packet.len() < 4 {
+            bail!("packet too short");
+        }
+        let magic = NetworkEndian::read_u32(&packet[..4]);
+        if magic != ANNOUNCE_MAGIC {
+            bail!("incorrect magic number: {:#x} (expected {:#x})", magic, ANNOUNCE_MAGIC);
+        }
+        let announce = syncthing_proto::Announce::parse_from_bytes(&packet[4..])
+            .context("error reading Announce")?;
+        Ok(Announcement {
+            device_hash: announce.id,
+            addresses: announce.addresses.iter()
+                .map(|address| fill_in_host(address, sender))
+                .collect(),
+            instance_id: announce.instance_id,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut announce = syncthing_proto::Announce::new();
+        announce.id = self.device_hash.clone();
+        announce.addresses = self.addresses.clone();
+        announce.instance_id = self.instance_id;
+
+        let mut packet = vec![0u8; 4];
+        NetworkEndian::write_u32(&mut packet, ANNOUNCE_MAGIC);
+        announce.write_to_vec(&mut packet).expect("failed to serialize Announce");
+        packet
+    }
+}
+
+// Replace an unspecified or missing host in an address URL with the address the announcement came
+// from.
+fn fill_in_host(address: &str, sender: IpAddr) -> String {
+    let (scheme, rest) = match address.split_once("://") {
+        Some(parts) => parts,
+        None => return address.to_owned(),
+    };
+    let (host, port_and_rest) = match rest.rsplit_once(':') {
+        Some(parts) => parts,
+        None => return address.to_owned(),
+    };
+    let unspecified = match host.trim_start_matches('[').trim_end_matches(']') {
+        "" => true,
+        host => host.parse::<IpAddr>().map(|ip| ip.is_unspecified()).unwrap_or(false),
+    };
+    if !unspecified {
+        return address.to_owned();
+    }
+    let sender = match sender {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.to_string(),
+            None => format!("[{}]", v6),
+        },
+        IpAddr::V4(v4) => v4.to_string(),
+    };
+    format!("{}://{}:{}", scheme, sender, port_and_rest)
+}
+
+#[test]
+fn test_announcement() {
+    let announcement = Announcement {
+        device_hash: vec![7; 32],
+        addresses: vec![
+            "tcp://0.0.0.0:22000".to_owned(),
+            "quic://:22000".to_owned(),
+            "tcp://[::]:22000".to_owned(),
+            "tcp://192.0.2.7:22001".to_owned(),
+            "relay://relay.example.com:22067/?id=ABC".to_owned(),
+        ],
+        instance_id: -42,
+    };
+    let packet = announcement.to_bytes();
+    assert_eq!(&[0x2e, 0xa7, 0xd9, 0x0b], &packet[..4]);
+
+    let parsed = Announcement::parse(&packet, "192.0.2.1".parse().unwrap()).unwrap();
+    assert_eq!(vec![
+        "tcp://192.0.2.1:22000",
+        "quic://192.0.2.1:22000",
+        "tcp://192.0.2.1:22000",
+        "tcp://192.0.2.7:22001",
+        "relay://relay.example.com:22067/?id=ABC",
+    ], parsed.addresses);
+    assert_eq!(-42, parsed.instance_id);
+
+    let parsed = Announcement::parse(&packet, "fe80::1".parse().unwrap()).unwrap();
+    assert_eq!("tcp://[fe80::1]:22000", parsed.addresses[0]);
+
+    assert!(Announcement::parse(b"\0\0\0\0", "192.0.2.1".parse().unwrap()).is_err());
+}
+
+/// Send an announcement to the local network, by IPv4 broadcast and IPv6 multicast. Succeeds if
+/// either one could be sent.
+pub fn announce(announcement: &Announcement) -> Result<()> {
+    let packet = announcement.to_bytes();
+    let v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.set_broadcast(true)?;
+            socket.send_to(&packet, (Ipv4Addr::BROADCAST, LOCAL_DISCOVERY_PORT))
+        });
+    let v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.send_to(&packet, (IPV6_MULTICAST_GROUP, LOCAL_DISCOVERY_PORT)));
+    match (v4, v6) {
+        (Err(e4), Err(e6)) => bail!("failed to send announcement: {} (IPv4), {} (IPv6)", e4, e6),
+        (Err(e), _) => debug!("failed to send IPv4 announcement: {}", e),
+        (_, Err(e)) => debug!("failed to send IPv6 announcement: {}", e),
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Listen for local discovery announcements from the device with the given certificate hash, and
+/// return the addresses it announces. If `announce_first` is given, it's sent once listening has
+/// started; Syncthing answers an announcement from a device it hasn't seen with one of its own,
+/// instead of waiting for its next regular one (every 30 seconds).
+pub fn lookup_local(
+    device_hash: &[u8],
+    announce_first: Option<&Announcement>,
+    timeout: Duration,
+) -> Result<Vec<String>> {
+    let deadline = Instant::now() + timeout;
+    let (sender, receiver) = mpsc::channel();
+
+    let mut errors = vec![];
+    let mut listening = false;
+    for socket in [listen_v4(), listen_v6()] {
+        match socket {
+            Ok(socket) => {
+                listening = true;
+                let sender = sender.clone();
+                std::thread::spawn(move || receive_announcements(socket, deadline, sender));
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    drop(sender);
+    if !listening {
+        bail!("failed to listen for announcements on port {}: {}", LOCAL_DISCOVERY_PORT,
+              errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; "));
+    }
+    for e in errors {
+        debug!("{}", e);
+    }
+
+    if let Some(announcement) = announce_first {
+        if let Err(e) = announce(announcement) {
+            warn!("{}", e);
+        }
+    }
+
+    for announcement in receiver {
+        if announcement.device_hash == device_hash {
+            if announcement.addresses.is_empty() {
+                bail!("the device announced itself without any addresses");
+            }
+            return Ok(announcement.addresses);
+        }
+    }
+    bail!("no announcement was received from the device within {} seconds", timeout.as_secs());
+}
+
+fn listen_v4() -> Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, LOCAL_DISCOVERY_PORT))
+        .context("IPv4")?;
+    socket.set_read_timeout(Some(READ_TIMEOUT))?;
+    Ok(socket)
+}
+
+fn listen_v6() -> Result<UdpSocket> {
+    // Bind to the group address rather than the unspecified one, which on dual-stack systems would
+    // clash with the IPv4 socket.
+    let socket = UdpSocket::bind((IPV6_MULTICAST_GROUP, LOCAL_DISCOVERY_PORT))
+        .or_else(|_| UdpSocket::bind((Ipv6Addr::UNSPECIFIED, LOCAL_DISCOVERY_PORT)))
+        .context("IPv6")?;
+    socket.join_multicast_v6(&IPV6_MULTICAST_GROUP, 0).context("IPv6 multicast")?;
+    socket.set_read_timeout(Some(READ_TIMEOUT))?;
+    Ok(socket)
+}
+
+fn receive_announcements(socket: UdpSocket, deadline: Instant, sender: mpsc::Sender<Announcement>) {
+    let mut buf = [0u8; 65536];
+    while Instant::now() < deadline {
+        let (len, from): (usize, SocketAddr) = match socket.recv_from(&mut buf) {
+            Ok(result) => result,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                continue;
+            }
+            Err(e) => {
+                warn!("error receiving announcements: {}", e);
+                return;
+            }
+        };
+        match Announcement::parse(&buf[..len], from.ip()) {
+            Ok(announcement) => {
+                debug!("announcement from {}: {:?}", from, announcement);
+                if sender.send(announcement).is_err() {
+                    return;
+                }
+            }
+            Err(e) => debug!("ignoring bad announcement from {}: {}", from, e),
+        }
+    }
+}
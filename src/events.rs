@@ -0,0 +1,175 @@
+use crate::SyncthingMessage;
+use crate::syncthing_proto;
+
+/// Something that happened on a session, for following its progress without scraping the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// The other end's Hello arrived.
+    Hello {
+        device_name: String,
+        client_name: String,
+        client_version: String,
+    },
+    /// The other end's cluster config arrived.
+    ClusterConfig {
+        folders: Vec<FolderSummary>,
+    },
+    /// A batch of index entries arrived: an Index, or an IndexUpdate if `update` is set.
+    Index {
+        folder: String,
+        update: bool,
+        files: usize,
+        /// The highest sequence number in the batch. Together with the folder's `max_sequence`,
+        /// this says how much of the index is still to come.
+        last_sequence: i64,
+    },
+    /// A block was requested.
+    BlockRequested {
+        id: i32,
+        folder: String,
+        name: String,
+        offset: i64,
+        size: i32,
+    },
+    /// The response to a block request arrived. `error` is set if the other end couldn't provide
+    /// the block.
+    BlockReceived {
+        id: i32,
+        size: usize,
+        error: Option<syncthing_proto::ErrorCode>,
+    },
+    /// A file has been fetched completely.
+    FileFinished {
+        folder: String,
+        name: String,
+        size: u64,
+    },
+    /// Fetching a file was given up on.
+    FileFailed {
+        folder: String,
+        name: String,
+        reason: String,
+    },
+    /// The other end closed the connection.
+    Close {
+        reason: String,
+    },
+    /// A message couldn't be read, or the connection failed.
+    Error(String),
+}
+
+/// A folder listed in a cluster config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderSummary {
+    pub id: String,
+    pub label: String,
+    /// The highest sequence number in the other end's own index of the folder, or zero if it
+    /// didn't say.
+    pub max_sequence: i64,
+}
+
+/// Receives the events of a session, set with `Session::set_observer`. It's called on the thread
+/// using the session, so it should return quickly.
+///
+/// Closures taking an `&Event` are observers too.
+pub trait Observer: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> Observer for F {
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// The event for a message received from the device whose certificate hash is `remote`, if it's
+/// one that has an event.
+pub(crate) fn message_event(message: &dyn SyncthingMessage, remote: Option<&[u8]>) -> Option<Event> {
+    let any = message.as_any();
+    if let Some(config) = any.downcast_ref::<syncthing_proto::ClusterConfig>() {
+        let folders = config.folders.iter()
+            .map(|folder| FolderSummary {
+                id: folder.id.clone(),
+                label: folder.label.clone(),
+                max_sequence: folder.devices.iter()
+                    .find(|device| Some(device.id.as_slice()) == remote)
+                    .map(|device| device.max_sequence)
+                    .unwrap_or(0),
+            })
+            .collect();
+        Some(Event::ClusterConfig { folders })
+    } else if let Some(index) = any.downcast_ref::<syncthing_proto::Index>() {
+        Some(index_event(&index.folder, false, &index.files))
+    } else if let Some(index) = any.downcast_ref::<syncthing_proto::IndexUpdate>() {
+        Some(index_event(&index.folder, true, &index.files))
+    } else if let Some(response) = any.downcast_ref::<syncthing_proto::Response>() {
+        let code = response.code.enum_value_or_default();
+        Some(Event::BlockReceived {
+            id: response.id,
+            size: response.data.len(),
+            error: (code != syncthing_proto::ErrorCode::NO_ERROR).then_some(code),
+        })
+    } else {
+        any.downcast_ref::<syncthing_proto::Close>()
+            .map(|close| Event::Close { reason: close.reason.clone() })
+    }
+}
+
+fn index_event(folder: &str, update: bool, files: &[syncthing_proto::FileInfo]) -> Event {
+    Event::Index {
+        folder: folder.to_owned(),
+        update,
+        files: files.len(),
+        last_sequence: files.iter().map(|file| file.sequence).max().unwrap_or(0),
+    }
+}
+
+#[test]
+fn test_message_event() {
+    let mut config = syncthing_proto::ClusterConfig::new();
+    let mut folder = syncthing_proto::Folder::new();
+    folder.id = "abcd-1234".to_owned();
+    folder.label = "Photos".to_owned();
+    for (id, max_sequence) in [(vec![1u8; 32], 10), (vec![2u8; 32], 20)] {
+        let mut device = syncthing_proto::Device::new();
+        device.id = id;
+        device.max_sequence = max_sequence;
+        folder.devices.push(device);
+    }
+    config.folders.push(folder);
+    assert_eq!(
+        Some(Event::ClusterConfig {
+            folders: vec![FolderSummary {
+                id: "abcd-1234".to_owned(),
+                label: "Photos".to_owned(),
+                max_sequence: 20,
+            }],
+        }),
+        message_event(&config, Some(&[2u8; 32])));
+
+    let mut index = syncthing_proto::IndexUpdate::new();
+    index.folder = "abcd-1234".to_owned();
+    for sequence in [12, 15, 13] {
+        let mut file = syncthing_proto::FileInfo::new();
+        file.sequence = sequence;
+        index.files.push(file);
+    }
+    assert_eq!(
+        Some(Event::Index {
+            folder: "abcd-1234".to_owned(),
+            update: true,
+            files: 3,
+            last_sequence: 15,
+        }),
+        message_event(&index, None));
+
+    let mut response = syncthing_proto::Response::new();
+    response.id = 7;
+    response.code = syncthing_proto::ErrorCode::NO_SUCH_FILE.into();
+    assert_eq!(
+        Some(Event::BlockReceived { id: 7, size: 0, error: Some(syncthing_proto::ErrorCode::NO_SUCH_FILE) }),
+        message_event(&response, None));
+
+    assert_eq!(None, message_event(&syncthing_proto::Ping::new(), None));
+}
@@ -0,0 +1,200 @@
+use anyhow::{bail, Result};
+use crate::syncthing_proto::{FileInfo, FileInfoType};
+use std::str::FromStr;
+
+/// The kind of entry a `FileInfo` describes, with the deprecated symlink types folded together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl FileKind {
+    pub fn of(file: &FileInfo) -> FileKind {
+        #[allow(deprecated)]
+        match file.type_.enum_value_or_default() {
+            FileInfoType::FILE => FileKind::File,
+            FileInfoType::DIRECTORY => FileKind::Directory,
+            FileInfoType::SYMLINK
+                | FileInfoType::SYMLINK_FILE
+                | FileInfoType::SYMLINK_DIRECTORY => FileKind::Symlink,
+        }
+    }
+}
+
+impl FromStr for FileKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<FileKind> {
+        Ok(match s {
+            "f" | "file" => FileKind::File,
+            "d" | "dir" | "directory" => FileKind::Directory,
+            "l" | "symlink" => FileKind::Symlink,
+            other => bail!("unknown file type {:?}; expected file, dir, or symlink", other),
+        })
+    }
+}
+
+/// Criteria for selecting entries from a remote index. All the criteria that are set must match.
+#[derive(Debug, Default)]
+pub struct FileFilter {
+    /// Glob patterns matched against the last path component. Any one of them may match.
+    pub names: Vec<String>,
+
+    /// Regular expression matched against the full path within the folder.
+    pub regex: Option<regex::Regex>,
+
+    pub larger_than: Option<u64>,
+    pub smaller_than: Option<u64>,
+
+    /// Unix timestamps compared against the modification time.
+    pub newer_than: Option<i64>,
+    pub older_than: Option<i64>,
+
+    pub kind: Option<FileKind>,
+}
+
+impl FileFilter {
+    pub fn matches(&self, file: &FileInfo) -> bool {
+        if let Some(kind) = self.kind {
+            if FileKind::of(file) != kind {
+                return false;
+            }
+        }
+
+        if !self.names.is_empty() {
+            let base_name = file.name.rsplit('/').next().unwrap();
+            if !self.names.iter().any(|pattern| glob_match(pattern, base_name, false)) {
+                return false;
+            }
+        }
+
+        if let Some(ref regex) = self.regex {
+            if !regex.is_match(&file.name) {
+                return false;
+            }
+        }
+
+        let size = file.size.max(0) as u64;
+        if self.larger_than.map(|n| size <= n).unwrap_or(false)
+            || self.smaller_than.map(|n| size >= n).unwrap_or(false)
+        {
+            return false;
+        }
+
+        if self.newer_than.map(|t| file.modified_s <= t).unwrap_or(false)
+            || self.older_than.map(|t| file.modified_s >= t).unwrap_or(false)
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Match a shell-style glob pattern against a slash-separated path.
+///
+/// `*` matches any run of characters except `/`, `**` matches anything including `/`, `?` matches
+/// any single character except `/`, and `[...]` matches a character class (with `!` or `^` for
+/// negation and `a-z` ranges). A backslash escapes the following character.
+pub fn glob_match(pattern: &str, text: &str, case_insensitive: bool) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) = if case_insensitive {
+        (pattern.to_lowercase().chars().collect(), text.to_lowercase().chars().collect())
+    } else {
+        (pattern.chars().collect(), text.chars().collect())
+    };
+    glob_match_chars(&pattern, &text)
+}
+
+fn glob_match_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            // "**/" also matches zero directories.
+            if rest.first() == Some(&'/') && glob_match_chars(&rest[1..], text) {
+                return true;
+            }
+            (0 ..= text.len()).any(|i| glob_match_chars(rest, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0 ..= text.len() {
+                if glob_match_chars(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => match text.first() {
+            Some(c) if *c != '/' => glob_match_chars(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some('[') => {
+            let c = match text.first() {
+                Some(c) if *c != '/' => *c,
+                _ => return false,
+            };
+            match match_class(&pattern[1..], c) {
+                Some((true, len)) => glob_match_chars(&pattern[1 + len ..], &text[1..]),
+                Some((false, _)) => false,
+                // Unterminated class; treat the bracket literally.
+                None => text.first() == Some(&'[') && glob_match_chars(&pattern[1..], &text[1..]),
+            }
+        }
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match_chars(&pattern[2..], &text[1..])
+        }
+        Some(p) => text.first() == Some(p) && glob_match_chars(&pattern[1..], &text[1..]),
+    }
+}
+
+// Match a character against a class body (the part after the opening bracket). Returns whether it
+// matched and how many pattern characters the class used up, including the closing bracket.
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negate = matches!(class.first(), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        let start = class[i];
+        if start == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if class.get(i + 1) == Some(&'-') && class.get(i + 2).map(|e| *e != ']').unwrap_or(false) {
+            if start <= c && c <= class[i + 2] {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if start == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    None
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*.mkv", "movie.mkv", false));
+    assert!(!glob_match("*.mkv", "movie.MKV", false));
+    assert!(glob_match("*.mkv", "movie.MKV", true));
+    assert!(!glob_match("*.mkv", "dir/movie.mkv", false));
+    assert!(glob_match("**/*.mkv", "dir/sub/movie.mkv", false));
+    assert!(glob_match("**/*.mkv", "movie.mkv", false));
+    assert!(glob_match("dir/**", "dir/sub/movie.mkv", false));
+    assert!(glob_match("file?.[ch]", "file1.c", false));
+    assert!(!glob_match("file?.[!ch]", "file1.c", false));
+    assert!(glob_match("[a-c]*", "banana", false));
+    assert!(glob_match("\\*", "*", false));
+}
@@ -0,0 +1,311 @@
+use anyhow::{anyhow, bail, Context, Result};
+use crate::session::{AnyServerCert, SyncthingCertVerifier};
+use crate::util;
+use crate::{Certificate, PrivateKey};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The global discovery servers Syncthing uses by default. The first one only answers lookups; the
+/// others only take announcements.
+pub const DEFAULT_SERVERS: &[&str] = &[
+    "https://discovery.syncthing.net/v2/?noannounce&id=LYXKCHX-VI3NYZR-ALCJBHF-WMZYSPK-QG6QJA3-MPFYMSO-U56GTUK-NA2MIAW",
+    "https://discovery-v4.syncthing.net/v2/?nolookup&id=LYXKCHX-VI3NYZR-ALCJBHF-WMZYSPK-QG6QJA3-MPFYMSO-U56GTUK-NA2MIAW",
+    "https://discovery-v6.syncthing.net/v2/?nolookup&id=LYXKCHX-VI3NYZR-ALCJBHF-WMZYSPK-QG6QJA3-MPFYMSO-U56GTUK-NA2MIAW",
+];
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before announcing again if the server doesn't say.
+const DEFAULT_REANNOUNCE: Duration = Duration::from_secs(30 * 60);
+
+/// A global discovery server, which maps device IDs to the addresses the devices announce.
+///
+/// Servers are given as URLs the way Syncthing's configuration has them, e.g.
+/// `https://discovery.example.com/v2/?id=<device ID>`. The query options are:
+///
+/// * `id=<device ID>` pins the server's certificate: it must hash to that device ID, the same
+///   way devices are authenticated.
+/// * `insecure` accepts any certificate. One of `id` or `insecure` is required, since we don't
+///   have a set of trusted certificate authorities to check the server against.
+/// * `noannounce` and `nolookup` restrict what the server is used for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveryServer {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+    device_id: Option<String>,
+    insecure: bool,
+    pub lookup: bool,
+    pub announce: bool,
+}
+
+impl DiscoveryServer {
+    pub fn parse(url: &str) -> Result<DiscoveryServer> {
+        let rest = url.strip_prefix("https://")
+            .ok_or_else(|| anyhow!("discovery server URL {:?} must start with https://", url))?;
+        let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (authority, path) = match location.find('/') {
+            Some(idx) => (&location[..idx], &location[idx..]),
+            None => (location, "/"),
+        };
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port.parse::<u16>()
+                    .with_context(|| format!("bad port in discovery server URL {:?}", url))?;
+                (host, port)
+            }
+            _ => (authority, 443),
+        };
+        if host.is_empty() {
+            bail!("discovery server URL {:?} has no host", url);
+        }
+
+        let mut server = DiscoveryServer {
+            url: url.to_owned(),
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+            device_id: None,
+            insecure: false,
+            lookup: true,
+            announce: true,
+        };
+        for option in query.split('&').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("id", id)) => {
+                    if !util::is_device_id(id) {
+                        bail!("discovery server URL {:?} has an invalid device ID", url);
+                    }
+                    server.device_id = Some(id.to_owned());
+                }
+                None if option == "insecure" => server.insecure = true,
+                None if option == "noannounce" => server.announce = false,
+                None if option == "nolookup" => server.lookup = false,
+                _ => debug!("ignoring unknown option {:?} in discovery server URL", option),
+            }
+        }
+        if server.device_id.is_none() && !server.insecure {
+            bail!("discovery server URL {:?} needs ?id=<device ID> to check the server's \
+                   certificate, or ?insecure to not check it", url);
+        }
+        Ok(server)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Ask the server which addresses a device announced.
+    pub fn lookup(&self, device_id: &str) -> Result<Vec<String>> {
+        let response = self.request(
+            "GET", &format!("{}?device={}", self.path, device_id), None, None)?;
+        match response.status {
+            200 => (),
+            404 => bail!("the discovery server doesn't know about the device"),
+            429 => bail!("the discovery server is rate limiting us; try again in {} seconds",
+                         response.header("retry-after").unwrap_or("a few")),
+            status => bail!("the discovery server answered with HTTP status {}", status),
+        }
+
+        let json: serde_json::Value = serde_json::from_slice(&response.body)
+            .context("bad response from the discovery server")?;
+        let addresses = json["addresses"].as_array()
+            .ok_or_else(|| anyhow!("the discovery server's response has no addresses"))?
+            .iter()
+            .filter_map(|a| a.as_str().map(str::to_owned))
+            .collect();
+        Ok(addresses)
+    }
+
+    /// Tell the server which addresses we can be reached at. The server identifies us by our
+    /// certificate. Returns how long until we should announce again.
+    pub fn announce(
+        &self,
+        cert: &Certificate,
+        key: &PrivateKey,
+        addresses: &[String],
+    ) -> Result<Duration> {
+        let body = serde_json::json!({ "addresses": addresses }).to_string();
+        let response = self.request("POST", &self.path, Some(body.as_bytes()), Some((cert, key)))?;
+        let seconds = |name| response.header(name)
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        match response.status {
+            200 | 204 => Ok(seconds("reannounce-after").unwrap_or(DEFAULT_REANNOUNCE)),
+            status => bail!("the discovery server answered with HTTP status {}{}", status,
+                            seconds("retry-after")
+                                .map(|d| format!("; try again in {} seconds", d.as_secs()))
+                                .unwrap_or_default()),
+        }
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        path_and_query: &str,
+        body: Option<&[u8]>,
+        client_auth: Option<(&Certificate, &PrivateKey)>,
+    ) -> Result<HttpResponse> {
+        let verifier: Arc<dyn rustls::client::ServerCertVerifier> = match self.device_id {
+            Some(ref id) if !self.insecure => Arc::new(SyncthingCertVerifier::new(id.clone())),
+            _ => Arc::new(AnyServerCert),
+        };
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier);
+        let config = match client_auth {
+            Some((cert, key)) => builder.with_single_cert(vec![cert.clone()], key.clone())?,
+            None => builder.with_no_client_auth(),
+        };
+        // The certificate is checked by device ID, so the name only matters for SNI.
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let server_name = rustls::ServerName::try_from(host)
+            .or_else(|_| rustls::ServerName::try_from("syncthing"))?;
+        let tls = rustls::ClientConnection::new(Arc::new(config), server_name)?;
+
+        let stream = connect((host, self.port))
+            .with_context(|| format!("failed to connect to {}:{}", self.host, self.port))?;
+        let mut stream = rustls::StreamOwned::new(tls, stream);
+
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}/{}\r\nConnection: close\r\n",
+            method, path_and_query, self.host, env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        if let Some(body) = body {
+            request += &format!("Content-Type: application/json\r\nContent-Length: {}\r\n",
+                                body.len());
+        }
+        request += "\r\n";
+        stream.write_all(request.as_bytes())?;
+        if let Some(body) = body {
+            stream.write_all(body)?;
+        }
+        stream.flush()?;
+
+        let mut data = vec![];
+        match stream.read_to_end(&mut data) {
+            Ok(_) => (),
+            // Some servers close the connection without a TLS close_notify.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !data.is_empty() => (),
+            Err(e) => return Err(e).context("error reading from the discovery server"),
+        }
+        HttpResponse::parse(&data)
+    }
+}
+
+fn connect(address: impl ToSocketAddrs) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                stream.set_write_timeout(Some(TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+#[derive(Debug)]
+struct HttpResponse {
+    status: u16,
+    /// Header names are lowercase.
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn parse(data: &[u8]) -> Result<HttpResponse> {
+        let header_end = data.windows(4).position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("incomplete HTTP response"))?;
+        let head = std::str::from_utf8(&data[..header_end])
+            .context("HTTP response headers aren't UTF-8")?;
+        let mut lines = head.split("\r\n");
+        let status = lines.next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("bad HTTP status line"))?;
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+            .collect();
+
+        let mut response = HttpResponse { status, headers, body: vec![] };
+        let body = &data[header_end + 4 ..];
+        response.body = if response.header("transfer-encoding")
+            .map(|te| te.eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false)
+        {
+            decode_chunked(body)?
+        } else if let Some(len) = response.header("content-length") {
+            let len = len.parse::<usize>().context("bad Content-Length")?;
+            body.get(..len).ok_or_else(|| anyhow!("truncated HTTP response"))?.to_vec()
+        } else {
+            body.to_vec()
+        };
+        Ok(response)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+fn decode_chunked(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n")
+            .ok_or_else(|| anyhow!("truncated chunked HTTP response"))?;
+        let size_field = std::str::from_utf8(&data[..line_end])?;
+        let size = usize::from_str_radix(size_field.split(';').next().unwrap().trim(), 16)
+            .context("bad chunk size in HTTP response")?;
+        data = &data[line_end + 2 ..];
+        if size == 0 {
+            return Ok(body);
+        }
+        let chunk = data.get(..size).ok_or_else(|| anyhow!("truncated chunked HTTP response"))?;
+        body.extend_from_slice(chunk);
+        data = data.get(size + 2 ..).unwrap_or_default();
+    }
+}
+
+#[test]
+fn test_discovery_server_url() {
+    for url in DEFAULT_SERVERS {
+        DiscoveryServer::parse(url).unwrap();
+    }
+    let server = DiscoveryServer::parse(DEFAULT_SERVERS[0]).unwrap();
+    assert_eq!(("discovery.syncthing.net", 443, "/v2/"),
+               (server.host.as_str(), server.port, server.path.as_str()));
+    assert!(server.lookup && !server.announce);
+
+    let server = DiscoveryServer::parse("https://127.0.0.1:8443/?insecure&nolookup").unwrap();
+    assert_eq!(("127.0.0.1", 8443, "/"), (server.host.as_str(), server.port, server.path.as_str()));
+    assert!(server.insecure && !server.lookup && server.announce);
+
+    assert!(DiscoveryServer::parse("https://[::1]:8443/v2/?insecure").is_ok());
+    assert!(DiscoveryServer::parse("https://discovery.example.com/v2/").is_err());
+    assert!(DiscoveryServer::parse("http://discovery.example.com/v2/?insecure").is_err());
+    assert!(DiscoveryServer::parse("https://discovery.example.com/?id=NOTANID").is_err());
+}
+
+#[test]
+fn test_http_response() {
+    let response = HttpResponse::parse(
+        b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n\
+          5\r\n{\"add\r\n9\r\nresses\":[\r\n2\r\n]}\r\n0\r\n\r\n").unwrap();
+    assert_eq!(200, response.status);
+    assert_eq!(b"{\"addresses\":[]}".as_slice(), response.body.as_slice());
+
+    let response = HttpResponse::parse(
+        b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 60\r\nContent-Length: 2\r\n\r\nokextra")
+        .unwrap();
+    assert_eq!(429, response.status);
+    assert_eq!(Some("60"), response.header("retry-after"));
+    assert_eq!(b"ok".as_slice(), response.body.as_slice());
+}
@@ -0,0 +1,192 @@
+use anyhow::{bail, Context, Result};
+use crate::filter::glob_match;
+use std::path::{Path, PathBuf};
+
+// Guards against #include cycles.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A list of Syncthing `.stignore`-style patterns.
+///
+/// Patterns are checked in order and the first one that matches a path decides whether it is
+/// ignored. Supported syntax:
+///
+/// * `//` starts a comment line. As in Syncthing, `#` doesn't, so `#foo` is an ordinary pattern.
+/// * `#include <file>` reads more patterns from a file, relative to the including file.
+/// * `!` at the start of a pattern negates it: matching paths are *not* ignored.
+/// * `(?i)` makes the pattern case-insensitive; `(?d)` is accepted and has no effect here.
+/// * `/` at the start anchors the pattern to the folder root; otherwise it matches at any depth.
+/// * `*`, `**`, `?` and `[...]` are globs as in [`glob_match`].
+///
+/// A pattern that matches a directory also matches everything inside it.
+#[derive(Debug, Default)]
+pub struct IgnorePatterns {
+    patterns: Vec<IgnorePattern>,
+}
+
+#[derive(Debug)]
+struct IgnorePattern {
+    original: String,
+    globs: Vec<String>,
+    include: bool,
+    case_insensitive: bool,
+}
+
+impl IgnorePatterns {
+    pub fn new() -> IgnorePatterns {
+        IgnorePatterns::default()
+    }
+
+    /// Read patterns from a `.stignore`-style file and add them after any existing patterns.
+    pub fn load_file(&mut self, path: &Path) -> Result<()> {
+        self.load_file_at_depth(path, 0)
+    }
+
+    fn load_file_at_depth(&mut self, path: &Path, depth: usize) -> Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            bail!("too many nested #include directives at {:?}", path);
+        }
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read ignore file {:?}", path))?;
+        let base_dir = path.parent().map(Path::to_owned).unwrap_or_default();
+        for line in contents.lines() {
+            let line = line.trim_end_matches('\r');
+            let include = line.strip_prefix("#include")
+                .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+            if let Some(include) = include {
+                let include = include.trim();
+                if include.is_empty() {
+                    bail!("#include without a file name in {:?}", path);
+                }
+                let include_path: PathBuf = base_dir.join(include);
+                self.load_file_at_depth(&include_path, depth + 1)
+                    .with_context(|| format!("included from {:?}", path))?;
+            } else {
+                self.add_line(line);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add one line of `.stignore` syntax. Blank lines and comments are skipped.
+    pub fn add_line(&mut self, line: &str) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with("//") {
+            return;
+        }
+
+        let mut rest = trimmed;
+        let mut include = false;
+        let mut case_insensitive = false;
+        loop {
+            if let Some(r) = rest.strip_prefix('!') {
+                include = true;
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("(?i)") {
+                case_insensitive = true;
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("(?d)") {
+                rest = r;
+            } else {
+                break;
+            }
+        }
+
+        let rest = rest.trim_end_matches('/');
+        let globs = if let Some(anchored) = rest.strip_prefix('/') {
+            vec![anchored.to_owned(), format!("{}/**", anchored)]
+        } else {
+            vec![
+                rest.to_owned(),
+                format!("{}/**", rest),
+                format!("**/{}", rest),
+                format!("**/{}/**", rest),
+            ]
+        };
+
+        self.patterns.push(IgnorePattern {
+            original: trimmed.to_owned(),
+            globs,
+            include,
+            case_insensitive,
+        });
+    }
+
+    /// Add a pattern that excludes matching paths.
+    pub fn add_exclude(&mut self, pattern: &str) {
+        self.add_line(pattern.trim_start_matches('!'));
+    }
+
+    /// Add a pattern that keeps matching paths, overriding any exclusions that come after it.
+    pub fn add_include(&mut self, pattern: &str) {
+        self.add_line(&format!("!{}", pattern.trim_start_matches('!')));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Returns the pattern that excludes the given folder-relative path, or None if the path is
+    /// not ignored.
+    pub fn ignored_by(&self, path: &str) -> Option<&str> {
+        for pattern in &self.patterns {
+            if pattern.globs.iter().any(|g| glob_match(g, path, pattern.case_insensitive)) {
+                return if pattern.include {
+                    None
+                } else {
+                    Some(&pattern.original)
+                };
+            }
+        }
+        None
+    }
+
+    pub fn is_ignored(&self, path: &str) -> bool {
+        self.ignored_by(path).is_some()
+    }
+}
+
+#[test]
+fn test_ignore_patterns() {
+    let mut ignores = IgnorePatterns::new();
+    for line in [
+        "// build output",
+        "!/target/keep.txt",
+        "/target",
+        "(?i)*.TMP",
+        "(?d).DS_Store",
+        "node_modules",
+        "cache/**/*.bin",
+        "#backup#",
+    ] {
+        ignores.add_line(line);
+    }
+
+    assert!(ignores.is_ignored("target"));
+    assert!(ignores.is_ignored("target/debug/stget"));
+    assert!(!ignores.is_ignored("target/keep.txt"));
+    assert!(!ignores.is_ignored("src/target"));
+    assert!(ignores.is_ignored("a/b/file.tmp"));
+    assert!(ignores.is_ignored("Photos/.DS_Store"));
+    assert!(ignores.is_ignored("web/node_modules/left-pad/index.js"));
+    assert!(ignores.is_ignored("cache/x/y/data.bin"));
+    assert!(!ignores.is_ignored("cache/data.txt"));
+    assert!(!ignores.is_ignored("src/main.rs"));
+    assert!(ignores.is_ignored("notes/#backup#"));
+}
+
+#[test]
+fn test_ignore_file_include() {
+    let dir = std::env::temp_dir().join(format!("stget-ignore-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join(".stignore"), "// comment\n#include more.txt\n#includes\n").unwrap();
+    std::fs::write(dir.join("more.txt"), "*.log\n").unwrap();
+
+    let mut ignores = IgnorePatterns::new();
+    let result = ignores.load_file(&dir.join(".stignore"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    result.unwrap();
+
+    assert!(ignores.is_ignored("a/debug.log"));
+    assert!(ignores.is_ignored("#includes"));
+    assert!(!ignores.is_ignored("comment"));
+}
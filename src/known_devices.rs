@@ -0,0 +1,144 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// A device we've connected to before, as recorded in the known devices file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownDevice {
+    pub address: String,
+    pub device_id: String,
+    pub name: String,
+    /// How many bytes per second to fetch from the device at most.
+    pub limit_rate: Option<u64>,
+}
+
+/// The devices we trusted the first time we connected to them, like SSH's `known_hosts`. Each line
+/// of the file has an address, the device ID it had, and the device's name:
+///
+/// ```text
+/// tcp://192.0.2.1:22000 SH5RUEY-JV6VLZE-K2PVLMH-QOASZP2-L5LMNHV-AAS5UWG-CT2IITD-CAQBTQ6 nas
+/// ```
+///
+/// A `limit-rate=SIZE` field can go before the name, to fetch from the device at most that many
+/// bytes per second (written as for `--limit-rate`):
+///
+/// ```text
+/// tcp://192.0.2.1:22000 SH5RUEY-JV6VLZE-K2PVLMH-QOASZP2-L5LMNHV-AAS5UWG-CT2IITD-CAQBTQ6 limit-rate=2M nas
+/// ```
+///
+/// Blank lines and lines starting with `#` are ignored.
+#[derive(Debug)]
+pub struct KnownDevices {
+    path: PathBuf,
+    devices: Vec<KnownDevice>,
+}
+
+impl KnownDevices {
+    /// Read the file at the given path. It's fine if it doesn't exist yet.
+    pub fn load(path: impl Into<PathBuf>) -> Result<KnownDevices> {
+        let path = path.into();
+        let devices = match File::open(&path) {
+            Ok(file) => parse(BufReader::new(file))
+                .with_context(|| format!("error reading {:?}", path))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).with_context(|| format!("failed to open {:?}", path)),
+        };
+        Ok(KnownDevices { path, devices })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The device recorded for an address, which should be written the way `DeviceAddress`
+    /// displays it, so that different ways of writing the same address match.
+    pub fn get(&self, address: &str) -> Option<&KnownDevice> {
+        self.devices.iter().find(|device| device.address == address)
+    }
+
+    /// The rate limits set for devices, by device ID.
+    pub fn rate_limits(&self) -> HashMap<String, u64> {
+        self.devices.iter()
+            .filter_map(|device| Some((device.device_id.clone(), device.limit_rate?)))
+            .collect()
+    }
+
+    /// Record a device, appending it to the file.
+    pub fn add(&mut self, device: KnownDevice) -> Result<()> {
+        if device.address.contains(char::is_whitespace) || device.device_id.contains(char::is_whitespace) {
+            bail!("can't record {:?}: the address and device ID can't contain spaces", device);
+        }
+        let mut name = device.name.replace(['\r', '\n'], " ");
+        // The name comes from the device, which shouldn't get to set its own limit.
+        if name.starts_with("limit-rate=") {
+            name = format!("\"{}\"", name);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to open {:?}", self.path))?;
+        let limit = match device.limit_rate {
+            Some(limit) => format!("limit-rate={} ", limit),
+            None => String::new(),
+        };
+        writeln!(file, "{} {} {}{}", device.address, device.device_id, limit, name)
+            .with_context(|| format!("failed to write to {:?}", self.path))?;
+        self.devices.push(KnownDevice { name, ..device });
+        Ok(())
+    }
+}
+
+fn parse(input: impl BufRead) -> Result<Vec<KnownDevice>> {
+    let mut devices = vec![];
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(3, char::is_whitespace);
+        let (address, device_id) = match (fields.next(), fields.next()) {
+            (Some(address), Some(device_id)) => (address, device_id),
+            _ => bail!("line {}: expected an address and a device ID", i + 1),
+        };
+        let mut name = fields.next().unwrap_or_default().trim();
+        let mut limit_rate = None;
+        if let Some(option) = name.strip_prefix("limit-rate=") {
+            let (value, rest) = option.split_once(char::is_whitespace).unwrap_or((option, ""));
+            limit_rate = Some(crate::util::parse_rate(value)
+                .with_context(|| format!("line {}", i + 1))?);
+            name = rest.trim();
+        }
+        devices.push(KnownDevice {
+            address: address.to_owned(),
+            device_id: device_id.to_owned(),
+            name: name.to_owned(),
+            limit_rate,
+        });
+    }
+    Ok(devices)
+}
+
+#[test]
+fn test_parse_known_devices() {
+    let input = "# comment\n\
+                 \n\
+                 tcp://192.0.2.1:22000 AAAAAAA-AAAAAAA-AAAAAAA-AAAAAAA-AAAAAAA-AAAAAAA-AAAAAAA-AAAAAAA my nas\n\
+                 tcp://[::1]:22001 BBBBBBB-BBBBBBB-BBBBBBB-BBBBBBB-BBBBBBB-BBBBBBB-BBBBBBB-BBBBBBB\n\
+                 tcp://[::1]:22002 CCCCCCC-CCCCCCC-CCCCCCC-CCCCCCC-CCCCCCC-CCCCCCC-CCCCCCC-CCCCCCC limit-rate=2M laptop\n";
+    let devices = parse(input.as_bytes()).unwrap();
+    assert_eq!(3, devices.len());
+    assert_eq!("tcp://192.0.2.1:22000", devices[0].address);
+    assert_eq!("my nas", devices[0].name);
+    assert_eq!(None, devices[0].limit_rate);
+    assert_eq!("tcp://[::1]:22001", devices[1].address);
+    assert_eq!("", devices[1].name);
+    assert_eq!("laptop", devices[2].name);
+    assert_eq!(Some(2 << 20), devices[2].limit_rate);
+
+    assert!(parse("tcp://192.0.2.1:22000\n".as_bytes()).is_err());
+    assert!(parse("tcp://192.0.2.1:22000 AAAAAAA limit-rate=0\n".as_bytes()).is_err());
+}
@@ -1,9 +1,24 @@
 #[macro_use] extern crate log;
 
 pub mod certificate;
+pub mod discovery;
+pub mod events;
+pub mod filter;
+pub mod global_discovery;
+pub mod ignore;
+pub mod known_devices;
+pub mod names;
+pub mod proxy;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod rate_limit;
+pub mod relay;
+pub mod scheduler;
 pub mod session;
 pub mod syncthing_proto;
+pub mod transport;
 pub mod util;
+pub mod version;
 
 pub use certificate::{Certificate, PrivateKey};
 
@@ -0,0 +1,162 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
+
+// Device names that Windows won't let us create files with, regardless of extension.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Check that a file name announced by a remote device is safe to use as a path relative to the
+/// destination directory: it must not be absolute, have `.` or `..` components, or contain NUL
+/// bytes. On Windows, names that are reserved for devices (`CON`, `NUL`, `COM1`, etc.) and
+/// characters that aren't allowed in file names are rejected too.
+///
+/// This only looks at the name. A name that passes can still lead outside the destination if one
+/// of its directories turns out to be a symlink, so that has to be checked against the filesystem
+/// before anything is written there (see `util::symlink_in_parents`).
+pub fn validate_name(name: &str) -> Result<()> {
+    validate_name_for(name, cfg!(windows))
+}
+
+fn validate_name_for(name: &str, windows: bool) -> Result<()> {
+    if name.is_empty() {
+        bail!("empty file name");
+    }
+    if name.contains('\0') {
+        bail!("file name contains a NUL byte");
+    }
+    if name.starts_with('/') || name.starts_with('\\') || has_drive_prefix(name) {
+        bail!("file name is an absolute path");
+    }
+
+    // Backslashes are separators on Windows, so check them everywhere in case the destination is
+    // shared with a Windows machine.
+    for component in name.split(['/', '\\']) {
+        match component {
+            "" => bail!("file name has an empty path component"),
+            "." | ".." => bail!("file name has a {:?} path component", component),
+            _ => (),
+        }
+
+        if windows {
+            if let Some(c) = component.chars().find(|c| "<>:\"|?*".contains(*c) || *c < ' ') {
+                bail!("file name contains {:?}, which is not allowed on Windows", c);
+            }
+            let stem = component.split('.').next().unwrap().trim_end();
+            if WINDOWS_RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+                bail!("file name has a reserved name {:?} as a path component", component);
+            }
+            if component.ends_with('.') || component.ends_with(' ') {
+                bail!("file name component {:?} ends with a dot or space", component);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn has_drive_prefix(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+#[test]
+fn test_validate_name() {
+    assert!(validate_name_for("Photos/2026/img.jpg", false).is_ok());
+    assert!(validate_name_for("..hidden/.config", false).is_ok());
+    assert!(validate_name_for("aux.c", false).is_ok());
+    assert!(validate_name_for("", false).is_err());
+    assert!(validate_name_for("../../.ssh/authorized_keys", false).is_err());
+    assert!(validate_name_for("a/../../b", false).is_err());
+    assert!(validate_name_for("a\\..\\b", false).is_err());
+    assert!(validate_name_for("/etc/passwd", false).is_err());
+    assert!(validate_name_for("C:/Windows", false).is_err());
+    assert!(validate_name_for("a//b", false).is_err());
+    assert!(validate_name_for("a\0b", false).is_err());
+    assert!(validate_name_for("dir/aux.c", true).is_err());
+    assert!(validate_name_for("dir/Com1", true).is_err());
+    assert!(validate_name_for("what?", true).is_err());
+    assert!(validate_name_for("trailing.", true).is_err());
+}
+
+// Names of temporary files are kept to this many bytes, as Syncthing does, well under the usual
+// limit of 255.
+const MAX_TEMP_NAME_LEN: usize = 160;
+
+/// The name Syncthing gives the temporary file a file is downloaded into, in the same directory as
+/// the final file. A name too long to fit along with the prefix and suffix is replaced by its
+/// SHA-256 hash.
+pub fn temp_name(file_name: &str) -> String {
+    let (prefix, suffix) = if cfg!(windows) {
+        ("~syncthing~", ".tmp")
+    } else {
+        (".syncthing.", ".tmp")
+    };
+    if prefix.len() + file_name.len() + suffix.len() <= MAX_TEMP_NAME_LEN {
+        return format!("{}{}{}", prefix, file_name, suffix);
+    }
+    let hash = ring::digest::digest(&ring::digest::SHA256, file_name.as_bytes());
+    let hex = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}{}{}", prefix, hex, suffix)
+}
+
+#[test]
+fn test_temp_name() {
+    let temp = temp_name("photo.jpg");
+    assert!(temp.contains("photo.jpg") && temp.ends_with(".tmp"));
+
+    let long = "x".repeat(250);
+    let temp = temp_name(&long);
+    assert!(temp.len() <= MAX_TEMP_NAME_LEN);
+    assert!(!temp.contains(&long[.. 100]));
+    assert_eq!(temp, temp_name(&long));
+    assert_ne!(temp, temp_name(&"y".repeat(250)));
+}
+
+/// Convert a name to Unicode Normalization Form C.
+pub fn nfc(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Keeps track of paths to find ones that would collide on a case-insensitive filesystem.
+#[derive(Debug, Default)]
+pub struct CollisionDetector {
+    seen: HashMap<String, String>,
+}
+
+impl CollisionDetector {
+    pub fn new() -> CollisionDetector {
+        CollisionDetector::default()
+    }
+
+    /// Record a path, and if a different path that differs only by case (or Unicode
+    /// normalization) was seen before, return it.
+    pub fn check(&mut self, path: &str) -> Option<&str> {
+        let key = nfc(path).to_lowercase();
+        match self.seen.entry(key) {
+            std::collections::hash_map::Entry::Occupied(entry) => {
+                if entry.get() == path {
+                    None
+                } else {
+                    Some(entry.into_mut())
+                }
+            }
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(path.to_owned());
+                None
+            }
+        }
+    }
+}
+
+#[test]
+fn test_collisions() {
+    let mut detector = CollisionDetector::new();
+    assert_eq!(None, detector.check("Readme.md"));
+    assert_eq!(None, detector.check("Readme.md"));
+    assert_eq!(Some("Readme.md"), detector.check("README.md"));
+    assert_eq!(None, detector.check("caf\u{e9}"));
+    assert_eq!(Some("caf\u{e9}"), detector.check("CAFE\u{301}"));
+}
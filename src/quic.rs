@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use crate::transport::Transport;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+/// A BEP connection over QUIC. TLS is part of QUIC itself, so BEP messages go over a single
+/// bidirectional stream as plaintext.
+///
+/// quinn is async, so this runs a small tokio runtime and blocks on it, to fit in with the rest of
+/// the program.
+pub struct QuicTransport {
+    runtime: Arc<tokio::runtime::Runtime>,
+    // Dropping the endpoint doesn't close the connection, but it has to be kept around for as long
+    // as the connection is in use.
+    _endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+    send: quinn::SendStream,
+    recv: Option<quinn::RecvStream>,
+    outgoing: Vec<u8>,
+}
+
+impl QuicTransport {
+    /// Connect to a host and port. The config needs to be set up for TLS 1.3, which is all QUIC
+    /// supports.
+    pub fn connect(host_and_port: &str, config: rustls::ClientConfig) -> Result<QuicTransport> {
+        let addr = host_and_port.to_socket_addrs()?
+            .next()
+            .with_context(|| format!("no addresses found for {}", host_and_port))?;
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let runtime = Arc::new(tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?);
+
+        let (endpoint, connection, send, recv) = runtime.block_on(async {
+            let endpoint = quinn::Endpoint::client(bind)?;
+            let client_config = quinn::ClientConfig::new(Arc::new(config));
+            let connection = endpoint.connect_with(client_config, addr, "syncthing")?.await?;
+            debug!("QUIC connection established to {}", addr);
+            let (send, recv) = connection.open_bi().await?;
+            anyhow::Ok((endpoint, connection, send, recv))
+        })?;
+
+        Ok(QuicTransport {
+            runtime,
+            _endpoint: endpoint,
+            connection,
+            send,
+            recv: Some(recv),
+            outgoing: vec![],
+        })
+    }
+}
+
+impl Transport for QuicTransport {
+    fn handshake(&mut self) -> Result<()> {
+        // Already done while connecting.
+        Ok(())
+    }
+
+    fn writer(&mut self) -> Box<dyn Write + '_> {
+        Box::new(&mut self.outgoing)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.outgoing.is_empty() {
+            self.runtime.block_on(self.send.write_all(&self.outgoing))?;
+            self.outgoing.clear();
+        }
+        Ok(())
+    }
+
+    fn raw_reader(&mut self) -> Result<Box<dyn Read + Send>> {
+        let recv = self.recv.take().context("the QUIC stream already has a reader")?;
+        Ok(Box::new(QuicReader {
+            runtime: Arc::clone(&self.runtime),
+            recv,
+        }))
+    }
+
+    fn receive(&mut self, raw: &[u8], data: &mut Vec<u8>) -> Result<usize> {
+        if raw.is_empty() {
+            // The remote closed the stream.
+            return Err(io::Error::from(io::ErrorKind::ConnectionAborted).into());
+        }
+        data.extend_from_slice(raw);
+        Ok(raw.len())
+    }
+
+    fn peer_certificate(&self) -> Option<rustls::Certificate> {
+        self.connection.peer_identity()?
+            .downcast::<Vec<rustls::Certificate>>().ok()?
+            .into_iter().next()
+    }
+}
+
+impl Drop for QuicTransport {
+    fn drop(&mut self) {
+        self.connection.close(0u32.into(), b"");
+    }
+}
+
+struct QuicReader {
+    runtime: Arc<tokio::runtime::Runtime>,
+    recv: quinn::RecvStream,
+}
+
+impl Read for QuicReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.runtime.block_on(self.recv.read(buf)) {
+            Ok(Some(n)) => Ok(n),
+            Ok(None) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
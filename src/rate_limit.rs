@@ -0,0 +1,76 @@
+use std::time::Instant;
+
+/// A token bucket limiting how many bytes are transferred per second.
+///
+/// Tokens are added at the given rate, up to a second's worth. A transfer can go ahead as long as
+/// there are any tokens left, and uses up its size in tokens even if that's more than there are;
+/// the bucket then has to refill past what it owes before the next one. That way, a single block
+/// can be bigger than a second's worth of data and still get through, while the average rate
+/// stays the same.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter that starts with a full bucket.
+    pub fn new(bytes_per_sec: u64, now: Instant) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            last: now,
+        }
+    }
+
+    /// Use up tokens for a transfer if it can go ahead now.
+    pub fn try_take(&mut self, bytes: u64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens <= 0. {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+
+    /// Whether a transfer could go ahead now, without using up any tokens.
+    pub fn ready(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens > 0.
+    }
+
+    /// Use up tokens for a transfer that has happened regardless, like data we didn't ask for.
+    pub fn take(&mut self, bytes: u64, now: Instant) {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.last = now;
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    use std::time::Duration;
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(1000, start);
+
+    // A second's worth can go straight away, and then some more has to build up.
+    assert!(limiter.try_take(600, start));
+    assert!(limiter.try_take(600, start));
+    assert!(!limiter.try_take(1, start));
+    assert!(!limiter.try_take(1, start + Duration::from_millis(200)));
+    assert!(limiter.try_take(1, start + Duration::from_millis(201)));
+    assert!(!limiter.ready(start + Duration::from_millis(201)));
+    assert!(limiter.ready(start + Duration::from_millis(203)));
+
+    // Unrequested data delays what comes after it.
+    let later = start + Duration::from_secs(10);
+    limiter.take(1500, later);
+    assert!(!limiter.try_take(1, later + Duration::from_millis(499)));
+    assert!(limiter.try_take(1, later + Duration::from_millis(501)));
+}
@@ -0,0 +1,352 @@
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{ByteOrder, NetworkEndian};
+use crate::proxy::Proxy;
+use crate::session::{AnyServerCert, SyncthingCertVerifier};
+use crate::util;
+use crate::{Certificate, PrivateKey};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+const RELAY_MAGIC: u32 = 0x9e79_bc40;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Relay protocol message types.
+const PING: i32 = 0;
+const PONG: i32 = 1;
+const JOIN_SESSION_REQUEST: i32 = 3;
+const RESPONSE: i32 = 4;
+const CONNECT_REQUEST: i32 = 5;
+const SESSION_INVITATION: i32 = 6;
+const RELAY_FULL: i32 = 7;
+
+// Keeps a relay from making us allocate a huge buffer.
+const MAX_MESSAGE_LEN: usize = 1024;
+
+/// A relay server, given as a URL the way Syncthing announces them:
+/// `relay://<host>:<port>/?id=<relay device ID>&...`. If the `id` is there, the relay's
+/// certificate must match it; other options are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayUrl {
+    pub host: String,
+    pub port: u16,
+    pub device_id: Option<String>,
+}
+
+impl RelayUrl {
+    pub fn parse(url: &str) -> Result<RelayUrl> {
+        let rest = url.strip_prefix("relay://")
+            .ok_or_else(|| anyhow!("relay URL {:?} must start with relay://", url))?;
+        let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let authority = location.split('/').next().unwrap();
+        let (host, port) = authority.rsplit_once(':')
+            .filter(|(_, port)| !port.contains(']'))
+            .ok_or_else(|| anyhow!("relay URL {:?} has no port", url))?;
+        let port = port.parse::<u16>()
+            .with_context(|| format!("bad port in relay URL {:?}", url))?;
+        let device_id = query.split('&')
+            .find_map(|option| option.strip_prefix("id="))
+            .map(str::to_owned);
+        if let Some(ref id) = device_id {
+            if !util::is_device_id(id) {
+                bail!("relay URL {:?} has an invalid device ID", url);
+            }
+        }
+        Ok(RelayUrl {
+            host: host.trim_start_matches('[').trim_end_matches(']').to_owned(),
+            port,
+            device_id,
+        })
+    }
+}
+
+/// What a relay sends back when asked to connect to a device: where to go to join the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInvitation {
+    pub from: Vec<u8>,
+    pub key: Vec<u8>,
+    /// None means the relay's own address.
+    pub address: Option<IpAddr>,
+    pub port: u16,
+    /// Whether we're expected to be the TLS server on the relayed connection.
+    pub server_socket: bool,
+}
+
+/// Connect to a device through a relay. The relay is asked for a session with the device with the
+/// given certificate hash, and once it's joined, the returned stream is connected to the device,
+/// ready for TLS. The device has to be connected to the same relay. If a proxy is given, both
+/// connections go through it.
+pub fn connect(
+    relay_url: &str,
+    device_hash: &[u8],
+    cert: &Certificate,
+    key: &PrivateKey,
+    proxy: Option<&Proxy>,
+) -> Result<TcpStream> {
+    let relay = RelayUrl::parse(relay_url)?;
+    let (invitation, relay_ip) = request_invitation(&relay, device_hash, cert, key, proxy)
+        .with_context(|| format!("failed to get a session invitation from relay {}:{}",
+                                 relay.host, relay.port))?;
+    if invitation.server_socket {
+        bail!("relay {}:{} wants us to be the TLS server, which we can't do",
+              relay.host, relay.port);
+    }
+
+    // Behind a proxy, the relay's IP address is the proxy's.
+    let address = match (invitation.address, proxy) {
+        (Some(ip), _) => ip.to_string(),
+        (None, Some(_)) => relay.host.clone(),
+        (None, None) => relay_ip.to_string(),
+    };
+    debug!("joining relay session at {}:{}", address, invitation.port);
+    let mut stream = match proxy {
+        Some(proxy) => proxy.connect(&address, invitation.port),
+        None => {
+            let ip = invitation.address.unwrap_or(relay_ip);
+            TcpStream::connect_timeout(&(ip, invitation.port).into(), TIMEOUT).map_err(Into::into)
+        }
+    }.with_context(|| format!("failed to connect to relay session at {}:{}",
+                              address, invitation.port))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    join_session(&mut stream, &invitation.key)?;
+    stream.set_read_timeout(None)?;
+    Ok(stream)
+}
+
+fn request_invitation(
+    relay: &RelayUrl,
+    device_hash: &[u8],
+    cert: &Certificate,
+    key: &PrivateKey,
+    proxy: Option<&Proxy>,
+) -> Result<(SessionInvitation, IpAddr)> {
+    let verifier: Arc<dyn rustls::client::ServerCertVerifier> = match relay.device_id {
+        Some(ref id) => Arc::new(SyncthingCertVerifier::new(id.clone())),
+        None => Arc::new(AnyServerCert),
+    };
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier)
+        .with_single_cert(vec![cert.clone()], key.clone())?;
+    config.alpn_protocols.push(b"bep-relay".to_vec());
+    let server_name = rustls::ServerName::try_from(relay.host.as_str())
+        .or_else(|_| rustls::ServerName::try_from("syncthing"))?;
+    let tls = rustls::ClientConnection::new(Arc::new(config), server_name)?;
+
+    let tcp = match proxy {
+        Some(proxy) => proxy.connect(&relay.host, relay.port),
+        None => connect_any((relay.host.as_str(), relay.port)).map_err(Into::into),
+    }.with_context(|| format!("failed to connect to relay {}:{}", relay.host, relay.port))?;
+    let relay_ip = tcp.peer_addr()?.ip();
+    let mut stream = rustls::StreamOwned::new(tls, tcp);
+    let invitation = ask_for_invitation(&mut stream, device_hash)?;
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+    Ok((invitation, relay_ip))
+}
+
+fn connect_any(address: impl ToSocketAddrs) -> std::io::Result<TcpStream> {
+    let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Send a connect request over a connection to the relay in protocol mode, and wait for the
+/// invitation.
+fn ask_for_invitation<S: Read + Write>(
+    stream: &mut S,
+    device_hash: &[u8],
+) -> Result<SessionInvitation> {
+    let mut body = vec![];
+    put_opaque(&mut body, device_hash);
+    write_message(stream, CONNECT_REQUEST, &body)?;
+    loop {
+        let (msgtype, body) = read_message(stream)?;
+        match msgtype {
+            SESSION_INVITATION => return parse_invitation(&body),
+            RESPONSE => {
+                let (code, message) = parse_response(&body)?;
+                bail!("relay refused the connection: {} (code {})", message, code);
+            }
+            RELAY_FULL => bail!("relay is full"),
+            PING => write_message(stream, PONG, &[])?,
+            other => debug!("ignoring relay message type {}", other),
+        }
+    }
+}
+
+/// Join a session on a connection to the relay's session port.
+fn join_session<S: Read + Write>(stream: &mut S, key: &[u8]) -> Result<()> {
+    let mut body = vec![];
+    put_opaque(&mut body, key);
+    write_message(stream, JOIN_SESSION_REQUEST, &body)?;
+    let (msgtype, body) = read_message(stream)?;
+    match msgtype {
+        RESPONSE => match parse_response(&body)? {
+            (0, _) => Ok(()),
+            (code, message) => bail!("relay refused to join the session: {} (code {})",
+                                     message, code),
+        },
+        RELAY_FULL => bail!("relay is full"),
+        other => bail!("unexpected relay message type {} when joining a session", other),
+    }
+}
+
+fn write_message<W: Write>(w: &mut W, msgtype: i32, body: &[u8]) -> Result<()> {
+    let mut header = [0u8; 12];
+    NetworkEndian::write_u32(&mut header[0..4], RELAY_MAGIC);
+    NetworkEndian::write_i32(&mut header[4..8], msgtype);
+    NetworkEndian::write_i32(&mut header[8..12], body.len() as i32);
+    w.write_all(&header)?;
+    w.write_all(body)?;
+    w.flush()?;
+    Ok(())
+}
+
+fn read_message<R: Read>(r: &mut R) -> Result<(i32, Vec<u8>)> {
+    let mut header = [0u8; 12];
+    r.read_exact(&mut header).context("error reading from relay")?;
+    let magic = NetworkEndian::read_u32(&header[0..4]);
+    if magic != RELAY_MAGIC {
+        bail!("incorrect relay magic number: {:#x} (expected {:#x})", magic, RELAY_MAGIC);
+    }
+    let msgtype = NetworkEndian::read_i32(&header[4..8]);
+    let len = NetworkEndian::read_i32(&header[8..12]);
+    if len < 0 || len as usize > MAX_MESSAGE_LEN {
+        bail!("bad relay message length {}", len);
+    }
+    let mut body = vec![0u8; len as usize];
+    r.read_exact(&mut body).context("error reading from relay")?;
+    Ok((msgtype, body))
+}
+
+fn parse_invitation(body: &[u8]) -> Result<SessionInvitation> {
+    let mut input = body;
+    let from = get_opaque(&mut input)?;
+    let key = get_opaque(&mut input)?;
+    let address = get_opaque(&mut input)?;
+    let port = get_u32(&mut input)?;
+    let server_socket = get_u32(&mut input)? != 0;
+    let address = match address.len() {
+        0 => None,
+        4 => Some(IpAddr::V4(Ipv4Addr::new(address[0], address[1], address[2], address[3]))),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&address);
+            let v6 = Ipv6Addr::from(octets);
+            Some(v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(v6)))
+        }
+        len => bail!("bad address length {} in session invitation", len),
+    };
+    // The port is a 16-bit value in a 32-bit field; accept it at either end.
+    let port = if port > 0xffff { (port >> 16) as u16 } else { port as u16 };
+    Ok(SessionInvitation { from, key, address, port, server_socket })
+}
+
+fn parse_response(body: &[u8]) -> Result<(i32, String)> {
+    let mut input = body;
+    let code = get_u32(&mut input)? as i32;
+    let message = String::from_utf8_lossy(&get_opaque(&mut input)?).into_owned();
+    Ok((code, message))
+}
+
+// XDR: opaque data is a 32-bit length, then the bytes padded to a multiple of 4.
+fn put_opaque(buf: &mut Vec<u8>, data: &[u8]) {
+    let mut len = [0u8; 4];
+    NetworkEndian::write_u32(&mut len, data.len() as u32);
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(data);
+    buf.resize(buf.len() + (4 - data.len() % 4) % 4, 0);
+}
+
+fn get_opaque(input: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = get_u32(input)? as usize;
+    let padded = len + (4 - len % 4) % 4;
+    if input.len() < padded {
+        bail!("truncated relay message");
+    }
+    let data = input[..len].to_vec();
+    *input = &input[padded..];
+    Ok(data)
+}
+
+fn get_u32(input: &mut &[u8]) -> Result<u32> {
+    if input.len() < 4 {
+        bail!("truncated relay message");
+    }
+    let value = NetworkEndian::read_u32(&input[..4]);
+    *input = &input[4..];
+    Ok(value)
+}
+
+#[test]
+fn test_relay_url() {
+    let relay = RelayUrl::parse(
+        "relay://192.0.2.1:22067/?id=JDF55R5-QQJBXUN-QQPSVFT-HFCAV6J-7NSVM7I-2KBA7PI-4MGOAIR-FA3I4AH\
+         &pingInterval=1m0s&networkTimeout=2m0s").unwrap();
+    assert_eq!(("192.0.2.1", 22067), (relay.host.as_str(), relay.port));
+    assert!(relay.device_id.is_some());
+    assert_eq!("::1", RelayUrl::parse("relay://[::1]:22067").unwrap().host);
+    assert!(RelayUrl::parse("relay://192.0.2.1").is_err());
+    assert!(RelayUrl::parse("tcp://192.0.2.1:22067").is_err());
+}
+
+#[test]
+fn test_relay_session() {
+    use std::net::TcpListener;
+
+    // A stand-in relay that hands out an invitation to its session port, then joins us up with a
+    // "device" that says hello.
+    let protocol = TcpListener::bind("127.0.0.1:0").unwrap();
+    let session = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (protocol_port, session_port) =
+        (protocol.local_addr().unwrap().port(), session.local_addr().unwrap().port());
+    let relay = std::thread::spawn(move || {
+        let (mut conn, _) = protocol.accept().unwrap();
+        let (msgtype, body) = read_message(&mut conn).unwrap();
+        assert_eq!(CONNECT_REQUEST, msgtype);
+        assert_eq!(vec![7u8; 32], get_opaque(&mut body.as_slice()).unwrap());
+        write_message(&mut conn, PING, &[]).unwrap();
+        assert_eq!(PONG, read_message(&mut conn).unwrap().0);
+        let mut invitation = vec![];
+        put_opaque(&mut invitation, &[7u8; 32]);
+        put_opaque(&mut invitation, b"session key");
+        put_opaque(&mut invitation, &[]);
+        invitation.extend_from_slice(&u32::from(session_port).to_be_bytes());
+        invitation.extend_from_slice(&0u32.to_be_bytes());
+        write_message(&mut conn, SESSION_INVITATION, &invitation).unwrap();
+
+        let (mut conn, _) = session.accept().unwrap();
+        let (msgtype, body) = read_message(&mut conn).unwrap();
+        assert_eq!(JOIN_SESSION_REQUEST, msgtype);
+        let mut response = 0u32.to_be_bytes().to_vec();
+        if get_opaque(&mut body.as_slice()).unwrap() == b"session key" {
+            put_opaque(&mut response, b"success");
+        }
+        write_message(&mut conn, RESPONSE, &response).unwrap();
+        conn.write_all(b"hello from the device").unwrap();
+    });
+
+    let mut conn = TcpStream::connect(("127.0.0.1", protocol_port)).unwrap();
+    let invitation = ask_for_invitation(&mut conn, &[7u8; 32]).unwrap();
+    assert_eq!((None, session_port, false),
+               (invitation.address, invitation.port, invitation.server_socket));
+
+    let mut conn = TcpStream::connect(("127.0.0.1", invitation.port)).unwrap();
+    join_session(&mut conn, &invitation.key).unwrap();
+    let mut greeting = String::new();
+    conn.read_to_string(&mut greeting).unwrap();
+    assert_eq!("hello from the device", greeting);
+    relay.join().unwrap();
+}
+
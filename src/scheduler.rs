@@ -0,0 +1,557 @@
+use crate::rate_limit::RateLimiter;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// How many block requests a device may have outstanding at once.
+const WINDOW: usize = 16;
+
+/// A device whose average response time is this many times the fastest device's only gets one
+/// request at a time.
+const SLOW_FACTOR: u32 = 4;
+
+/// Once every block has been handed out, a block that has been outstanding for this many times
+/// the fastest device's response time (and at least `MIN_STRAGGLER_TIME`) is requested again from
+/// an idle device.
+const STRAGGLER_FACTOR: u32 = 3;
+const MIN_STRAGGLER_TIME: Duration = Duration::from_secs(2);
+
+/// A block a device says it can't provide right now is requested from it again after this long,
+/// doubling each time, up to `MAX_RETRIES` times.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRIES: u32 = 4;
+
+/// Decides which device to request each block from when several devices have the same version of
+/// a file.
+///
+/// Devices ("peers") and files are identified by numbers the caller chooses. Blocks are handed out
+/// in order to whichever device has the most room for more requests, so faster devices end up
+/// doing more of the work. A device that is much slower than the others only gets one request at
+/// a time, and once nothing is left to hand out, blocks that are taking too long are requested
+/// again from idle devices. A block that a device fails to provide is retried on another one, or
+/// on the same one after a while if the failure might be temporary.
+///
+/// When several files are being fetched, blocks are handed out according to the `Priority`, and
+/// with a rate limit, only as fast as it allows. Each device can have a limit of its own as well.
+#[derive(Debug, Default)]
+pub struct BlockScheduler {
+    peers: BTreeMap<usize, PeerStats>,
+    files: BTreeMap<usize, FileBlocks>,
+    priority: Priority,
+    rate_limit: Option<RateLimiter>,
+    /// Limits for particular devices. They're kept when a device is removed, so that one that
+    /// comes back under the same number can't get around its limit by reconnecting.
+    peer_rate_limits: BTreeMap<usize, RateLimiter>,
+}
+
+/// Which file's blocks to request first when several files are being fetched.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Share the bandwidth between files: each block goes to the file with the least data
+    /// requested and not yet received.
+    #[default]
+    Fair,
+    /// Finish the smallest files first.
+    SmallestFirst,
+}
+
+#[derive(Debug, Default)]
+struct PeerStats {
+    outstanding: usize,
+    /// Moving average of how long responses take.
+    latency: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct FileBlocks {
+    peers: Vec<usize>,
+    blocks: Vec<Block>,
+    /// The total size of the blocks, in bytes.
+    size: u64,
+    /// The file is complete or was given up on; it's kept until its outstanding requests are in.
+    finished: bool,
+}
+
+#[derive(Debug, Default)]
+struct Block {
+    done: bool,
+    size: u64,
+    /// The devices the block has been requested from, and when.
+    requested: Vec<(usize, Instant)>,
+    /// Devices that failed to provide the block.
+    failed: Vec<usize>,
+    /// Devices that failed to provide the block this time, and when to ask them again.
+    retry_at: Vec<(usize, Instant)>,
+    retries: u32,
+}
+
+/// A block to request from a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assignment {
+    pub peer: usize,
+    pub file: usize,
+    pub block: usize,
+}
+
+impl BlockScheduler {
+    pub fn new() -> BlockScheduler {
+        BlockScheduler::default()
+    }
+
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Only request blocks as fast as the limiter allows.
+    pub fn set_rate_limit(&mut self, limiter: RateLimiter) {
+        self.rate_limit = Some(limiter);
+    }
+
+    /// Only request blocks from a device as fast as the limiter allows, besides the overall limit.
+    pub fn set_peer_rate_limit(&mut self, peer: usize, limiter: RateLimiter) {
+        self.peer_rate_limits.insert(peer, limiter);
+    }
+
+    /// Count data that arrived from a device without being requested, like index updates, against
+    /// the rate limits.
+    pub fn count_unrequested(&mut self, peer: usize, bytes: u64, now: Instant) {
+        if let Some(limiter) = &mut self.rate_limit {
+            limiter.take(bytes, now);
+        }
+        if let Some(limiter) = self.peer_rate_limits.get_mut(&peer) {
+            limiter.take(bytes, now);
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: usize) {
+        self.peers.entry(peer).or_default();
+    }
+
+    /// Forget about a device, e.g. because its connection was lost. Blocks that were requested
+    /// from it become available to other devices.
+    pub fn remove_peer(&mut self, peer: usize) {
+        self.peers.remove(&peer);
+        for file in self.files.values_mut() {
+            file.peers.retain(|p| *p != peer);
+            for block in &mut file.blocks {
+                block.requested.retain(|(p, _)| *p != peer);
+            }
+        }
+        self.files.retain(|_, file| !file.finished || file.has_outstanding());
+    }
+
+    pub fn has_peer(&self, peer: usize) -> bool {
+        self.peers.contains_key(&peer)
+    }
+
+    /// Start fetching a file. `have` says which blocks are already present, `sizes` how big each
+    /// block is, and `peers` which devices can provide the rest.
+    pub fn add_file(&mut self, file: usize, have: &[bool], sizes: &[u64], peers: Vec<usize>) {
+        let blocks = have.iter().zip(sizes)
+            .map(|(&done, &size)| Block { done, size, ..Block::default() })
+            .collect();
+        let size = sizes.iter().sum();
+        self.files.insert(file, FileBlocks { peers, blocks, size, finished: false });
+    }
+
+    /// Note that another device has the version of a file that's being fetched.
+    pub fn add_file_peer(&mut self, file: usize, peer: usize) {
+        if let Some(file) = self.files.get_mut(&file) {
+            if !file.peers.contains(&peer) {
+                file.peers.push(peer);
+            }
+        }
+    }
+
+    /// Note that a device no longer has the version of a file that's being fetched. Blocks already
+    /// requested from it might still arrive.
+    pub fn remove_file_peer(&mut self, file: usize, peer: usize) {
+        if let Some(file) = self.files.get_mut(&file) {
+            file.peers.retain(|p| *p != peer);
+        }
+    }
+
+    /// Stop requesting blocks for a file.
+    pub fn abandon_file(&mut self, file: usize) {
+        if let Some(blocks) = self.files.get_mut(&file) {
+            blocks.finished = true;
+            if !blocks.has_outstanding() {
+                self.files.remove(&file);
+            }
+        }
+    }
+
+    /// Decide what to request next.
+    pub fn assign(&mut self, now: Instant) -> Vec<Assignment> {
+        let best_latency = self.peers.values().filter_map(|p| p.latency).min();
+        let mut free: BTreeMap<usize, usize> = self.peers.iter()
+            .map(|(&id, stats)| {
+                let window = match (stats.latency, best_latency) {
+                    (Some(latency), Some(best)) if latency > best * SLOW_FACTOR => 1,
+                    _ => WINDOW,
+                };
+                (id, window.saturating_sub(stats.outstanding))
+            })
+            .collect();
+        // Devices over their own limit get nothing this time.
+        for (peer, limiter) in &mut self.peer_rate_limits {
+            if !limiter.ready(now) {
+                if let Some(n) = free.get_mut(peer) {
+                    *n = 0;
+                }
+            }
+        }
+
+        let mut order: Vec<usize> = self.files.iter()
+            .filter(|(_, f)| !f.finished)
+            .map(|(&id, _)| id)
+            .collect();
+        if self.priority == Priority::SmallestFirst {
+            order.sort_by_key(|id| self.files[id].size);
+        }
+        let all_handed_out = order.iter()
+            .flat_map(|id| &self.files[id].blocks)
+            .all(|block| block.done || !block.requested.is_empty());
+
+        // Hand out blocks one at a time, each to the file whose turn it is, until there's no room
+        // left or the rate limit is reached. For each file, the blocks before `next` have been
+        // dealt with.
+        let mut assignments = vec![];
+        let mut in_flight: Vec<u64> = order.iter()
+            .map(|id| self.files[id].blocks.iter()
+                .filter(|block| !block.requested.is_empty())
+                .map(|block| block.size)
+                .sum())
+            .collect();
+        let mut next = vec![0; order.len()];
+        let mut exhausted = vec![false; order.len()];
+        let mut limited = false;
+        loop {
+            // min_by_key picks the first of equals, so with SmallestFirst, it's in size order.
+            let turn = (0 .. order.len())
+                .filter(|&i| !exhausted[i])
+                .min_by_key(|&i| match self.priority {
+                    Priority::Fair => in_flight[i],
+                    Priority::SmallestFirst => 0,
+                });
+            let i = match turn {
+                Some(i) => i,
+                None => break,
+            };
+            let file = self.files.get_mut(&order[i]).unwrap();
+            let found = (next[i] .. file.blocks.len()).find_map(|idx| {
+                let block = &file.blocks[idx];
+                if block.done || !block.requested.is_empty() {
+                    return None;
+                }
+                pick_peer(&file.peers, block, &free, now).map(|peer| (idx, peer))
+            });
+            let (idx, peer) = match found {
+                Some(found) => found,
+                None => {
+                    exhausted[i] = true;
+                    continue;
+                }
+            };
+            let block = &mut file.blocks[idx];
+            if let Some(limiter) = &mut self.rate_limit {
+                if !limiter.try_take(block.size, now) {
+                    limited = true;
+                    break;
+                }
+            }
+            next[i] = idx + 1;
+            in_flight[i] += block.size;
+            *free.get_mut(&peer).unwrap() -= 1;
+            take_for_peer(&mut self.peer_rate_limits, &mut free, peer, block.size, now);
+            block.requested.push((peer, now));
+            assignments.push(Assignment { peer, file: order[i], block: idx });
+        }
+
+        if all_handed_out && !limited {
+            let threshold = best_latency
+                .map(|best| (best * STRAGGLER_FACTOR).max(MIN_STRAGGLER_TIME))
+                .unwrap_or(MIN_STRAGGLER_TIME);
+            for (&file_id, file) in self.files.iter_mut().filter(|(_, f)| !f.finished) {
+                for (idx, block) in file.blocks.iter_mut().enumerate() {
+                    let straggling = !block.done
+                        && block.requested.len() == 1
+                        && now.duration_since(block.requested[0].1) >= threshold;
+                    if !straggling {
+                        continue;
+                    }
+                    if let Some(peer) = pick_peer(&file.peers, block, &free, now) {
+                        if let Some(limiter) = &mut self.rate_limit {
+                            if !limiter.try_take(block.size, now) {
+                                break;
+                            }
+                        }
+                        debug!("requesting straggling block {} of file {} again from peer {}",
+                               idx, file_id, peer);
+                        *free.get_mut(&peer).unwrap() -= 1;
+                        take_for_peer(&mut self.peer_rate_limits, &mut free, peer, block.size, now);
+                        block.requested.push((peer, now));
+                        assignments.push(Assignment { peer, file: file_id, block: idx });
+                    }
+                }
+            }
+        }
+
+        for assignment in &assignments {
+            self.peers.get_mut(&assignment.peer).unwrap().outstanding += 1;
+        }
+        assignments
+    }
+
+    /// Record that a device answered a request. Returns whether the block is still wanted; it
+    /// isn't if another device provided it first or the file was abandoned.
+    pub fn response(&mut self, peer: usize, file: usize, block: usize, now: Instant) -> bool {
+        let file_blocks = match self.files.get_mut(&file) {
+            Some(f) => f,
+            None => return false,
+        };
+        let block_state = &mut file_blocks.blocks[block];
+        let requested_at = match block_state.requested.iter().position(|(p, _)| *p == peer) {
+            Some(pos) => block_state.requested.remove(pos).1,
+            None => return false,
+        };
+        let wanted = !file_blocks.finished && !block_state.done;
+        if file_blocks.finished && !file_blocks.has_outstanding() {
+            self.files.remove(&file);
+        }
+
+        if let Some(stats) = self.peers.get_mut(&peer) {
+            stats.outstanding -= 1;
+            let elapsed = now.duration_since(requested_at);
+            stats.latency = Some(match stats.latency {
+                Some(avg) => (avg * 3 + elapsed) / 4,
+                None => elapsed,
+            });
+        }
+        wanted
+    }
+
+    /// Record that a block was received and written. Returns whether the whole file is done.
+    pub fn completed(&mut self, file: usize, block: usize) -> bool {
+        let file_blocks = self.files.get_mut(&file).expect("completed block of an unknown file");
+        file_blocks.blocks[block].done = true;
+        if file_blocks.blocks.iter().all(|b| b.done) {
+            file_blocks.finished = true;
+            if !file_blocks.has_outstanding() {
+                self.files.remove(&file);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record that a device couldn't provide a block, so it can be requested elsewhere.
+    pub fn failed(&mut self, peer: usize, file: usize, block: usize) {
+        if let Some(file_blocks) = self.files.get_mut(&file) {
+            file_blocks.blocks[block].failed.push(peer);
+        }
+    }
+
+    /// Record that a device couldn't provide a block, but might be able to later. Other devices can
+    /// be asked for it straight away, and this one after a delay that doubles each time. Returns
+    /// false if the block has been retried too many times already, in which case it should be
+    /// marked as `failed` instead.
+    pub fn retry_later(&mut self, peer: usize, file: usize, block: usize, now: Instant) -> bool {
+        let block = match self.files.get_mut(&file) {
+            Some(file_blocks) => &mut file_blocks.blocks[block],
+            None => return false,
+        };
+        if block.retries >= MAX_RETRIES {
+            return false;
+        }
+        let delay = RETRY_DELAY * 2u32.pow(block.retries);
+        block.retries += 1;
+        block.retry_at.retain(|(p, _)| *p != peer);
+        block.retry_at.push((peer, now + delay));
+        true
+    }
+
+    /// Files that need a block no remaining device can provide, along with that block.
+    pub fn stalled(&self) -> Vec<(usize, usize)> {
+        self.files.iter()
+            .filter(|(_, file)| !file.finished)
+            .filter_map(|(&file_id, file)| {
+                file.blocks.iter()
+                    .position(|block| !block.done
+                        && block.requested.is_empty()
+                        && file.peers.iter().all(|p| block.failed.contains(p)))
+                    .map(|idx| (file_id, idx))
+            })
+            .collect()
+    }
+}
+
+impl FileBlocks {
+    fn has_outstanding(&self) -> bool {
+        self.blocks.iter().any(|b| !b.requested.is_empty())
+    }
+}
+
+// The device with the most room for requests that hasn't been asked for this block already and
+// hasn't failed to provide it, or isn't due to be asked again yet.
+fn pick_peer(
+    peers: &[usize],
+    block: &Block,
+    free: &BTreeMap<usize, usize>,
+    now: Instant,
+) -> Option<usize> {
+    peers.iter()
+        .filter(|p| !block.failed.contains(p) && !block.requested.iter().any(|(r, _)| r == *p))
+        .filter(|p| !block.retry_at.iter().any(|(r, at)| r == *p && now < *at))
+        .filter_map(|p| free.get(p).map(|n| (*p, *n)))
+        .filter(|(_, n)| *n > 0)
+        .max_by_key(|(p, n)| (*n, std::cmp::Reverse(*p)))
+        .map(|(p, _)| p)
+}
+
+// Use up a device's tokens for a request, and give it no more room if that's the last of them.
+fn take_for_peer(
+    limits: &mut BTreeMap<usize, RateLimiter>,
+    free: &mut BTreeMap<usize, usize>,
+    peer: usize,
+    bytes: u64,
+    now: Instant,
+) {
+    if let Some(limiter) = limits.get_mut(&peer) {
+        limiter.take(bytes, now);
+        if !limiter.ready(now) {
+            free.insert(peer, 0);
+        }
+    }
+}
+
+#[test]
+fn test_block_scheduler() {
+    let start = Instant::now();
+    let mut sched = BlockScheduler::new();
+    sched.add_peer(0);
+    sched.add_peer(1);
+    sched.add_file(7, &[true, false, false, false], &[10; 4], vec![0, 1]);
+
+    // Blocks are spread over both devices.
+    let assigned = sched.assign(start);
+    assert_eq!(3, assigned.len());
+    assert!(assigned.iter().any(|a| a.peer == 0) && assigned.iter().any(|a| a.peer == 1));
+    assert!(sched.assign(start).is_empty());
+
+    // A failed block goes to the other device.
+    let failing = assigned[0];
+    assert!(sched.response(failing.peer, 7, failing.block, start + Duration::from_millis(5)));
+    sched.failed(failing.peer, 7, failing.block);
+    let retry = sched.assign(start);
+    assert_eq!(vec![Assignment { peer: 1 - failing.peer, ..failing }], retry);
+
+    // A block that takes too long is also requested from an idle device, and the slower response
+    // isn't wanted once the faster one is in.
+    let later = start + Duration::from_secs(10);
+    for a in &assigned[1..] {
+        if a.peer != failing.peer {
+            assert!(sched.response(a.peer, 7, a.block, start + Duration::from_millis(5)));
+            assert!(!sched.completed(7, a.block));
+        }
+    }
+    assert!(sched.response(retry[0].peer, 7, retry[0].block, start + Duration::from_millis(5)));
+    assert!(!sched.completed(7, retry[0].block));
+    let slow = *assigned[1..].iter().find(|a| a.peer == failing.peer).unwrap();
+    let dup = sched.assign(later);
+    assert_eq!(vec![Assignment { peer: 1 - slow.peer, ..slow }], dup);
+    assert!(sched.response(dup[0].peer, 7, slow.block, later));
+    assert!(sched.completed(7, slow.block));
+    assert!(!sched.response(slow.peer, 7, slow.block, later));
+
+    // A block nobody can provide stalls the file.
+    sched.add_file(8, &[false], &[10], vec![0]);
+    let a = sched.assign(later);
+    assert!(sched.response(a[0].peer, 8, 0, later));
+    sched.failed(0, 8, 0);
+    assert_eq!(vec![(8, 0)], sched.stalled());
+}
+
+#[test]
+fn test_retry_later() {
+    let start = Instant::now();
+    let mut sched = BlockScheduler::new();
+    sched.add_peer(0);
+    sched.add_file(1, &[false], &[10], vec![0]);
+
+    // The block is asked for again once the delay is up, and the delay doubles each time.
+    let mut now = start;
+    for retry in 0 .. MAX_RETRIES {
+        let a = sched.assign(now);
+        assert_eq!(vec![Assignment { peer: 0, file: 1, block: 0 }], a);
+        assert!(sched.response(0, 1, 0, now));
+        assert!(sched.retry_later(0, 1, 0, now));
+        let delay = RETRY_DELAY * 2u32.pow(retry);
+        assert!(sched.assign(now + delay - Duration::from_millis(1)).is_empty());
+        assert!(sched.stalled().is_empty());
+        now += delay;
+    }
+
+    // Then it's given up on.
+    assert_eq!(1, sched.assign(now).len());
+    assert!(sched.response(0, 1, 0, now));
+    assert!(!sched.retry_later(0, 1, 0, now));
+    sched.failed(0, 1, 0);
+    assert_eq!(vec![(1, 0)], sched.stalled());
+}
+
+#[test]
+fn test_priority() {
+    let now = Instant::now();
+    let blocks = |assignments: Vec<Assignment>| {
+        assignments.iter().map(|a| (a.file, a.block)).collect::<Vec<_>>()
+    };
+
+    // Bandwidth is shared: the small file doesn't have to wait for the big one.
+    let mut sched = BlockScheduler::new();
+    sched.add_peer(0);
+    sched.add_file(1, &[false; 4], &[100; 4], vec![0]);
+    sched.add_file(2, &[false; 2], &[10; 2], vec![0]);
+    assert_eq!(vec![(1, 0), (2, 0), (2, 1), (1, 1), (1, 2), (1, 3)], blocks(sched.assign(now)));
+
+    // Or the small file goes first, and the rate limit holds back the rest.
+    let mut sched = BlockScheduler::new();
+    sched.set_priority(Priority::SmallestFirst);
+    sched.set_rate_limit(RateLimiter::new(15, now));
+    sched.add_peer(0);
+    sched.add_file(1, &[false; 4], &[100; 4], vec![0]);
+    sched.add_file(2, &[false; 2], &[10; 2], vec![0]);
+    assert_eq!(vec![(2, 0), (2, 1)], blocks(sched.assign(now)));
+    assert!(sched.assign(now).is_empty());
+    assert_eq!(vec![(1, 0)], blocks(sched.assign(now + Duration::from_secs(1))));
+}
+
+#[test]
+fn test_peer_rate_limit() {
+    let now = Instant::now();
+    let mut sched = BlockScheduler::new();
+    sched.set_peer_rate_limit(1, RateLimiter::new(15, now));
+    sched.add_peer(0);
+    sched.add_peer(1);
+    sched.add_file(1, &[false; 6], &[10; 6], vec![1]);
+
+    // The limited device gets as much as its limit allows, and then has to wait.
+    assert_eq!(2, sched.assign(now).len());
+    assert!(sched.assign(now).is_empty());
+    assert_eq!(1, sched.assign(now + Duration::from_secs(1)).len());
+
+    // Other devices aren't held back by it.
+    sched.add_file_peer(1, 0);
+    assert_eq!(3, sched.assign(now + Duration::from_secs(1)).len());
+
+    // Nor is it allowed more by being removed and added again.
+    let mut sched = BlockScheduler::new();
+    sched.set_peer_rate_limit(0, RateLimiter::new(15, now));
+    sched.add_peer(0);
+    sched.add_file(1, &[false; 4], &[10; 4], vec![0]);
+    assert_eq!(2, sched.assign(now).len());
+    sched.remove_peer(0);
+    sched.add_peer(0);
+    sched.add_file_peer(1, 0);
+    assert!(sched.assign(now).is_empty());
+}
@@ -1,10 +1,15 @@
 use anyhow::{bail, Context, Result};
 use crate::SyncthingMessage;
+use crate::events::{self, Event, Observer};
+use crate::proxy::Proxy;
+use crate::relay;
 use crate::syncthing_proto;
+use crate::transport::{TlsTransport, Transport, TransportKind};
 use crate::util;
-use std::io;
+use std::io::{self, Read, Write};
 use std::net::TcpStream;
-use std::sync::Arc;
+use std::fmt;
+use std::sync::{mpsc, Arc, Mutex};
 
 use byteorder::{ByteOrder, NetworkEndian};
 use lz4_compression;
@@ -16,16 +21,71 @@ use rustls;
 const HELLO_MAGIC: u32 = 0x2ea7_d90b;
 
 pub struct Session {
-    tls: rustls::ClientConnection,
-    stream: TcpStream,
+    transport: Box<dyn Transport>,
     device_name: String,
     next_request_id: i32,
+    /// Checks the remote's certificate when we connected out expecting a particular device.
+    verifier: Option<Arc<SyncthingCertVerifier>>,
+    observer: Option<Arc<dyn Observer>>,
+}
+
+/// The remote presented a certificate for a different device than the one we connected to it
+/// expecting.
+#[derive(Debug, Clone)]
+pub struct DeviceIdMismatch {
+    pub expected: String,
+    pub presented: String,
+}
+
+impl fmt::Display for DeviceIdMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected device {}, but the remote presented a certificate for device {}",
+               self.expected, self.presented)
+    }
+}
+
+impl std::error::Error for DeviceIdMismatch {}
+
+/// The remote hung up or sent an alert during the TLS handshake, most likely because it doesn't
+/// accept our certificate. It's added as context to the underlying error.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeRejected;
+
+impl fmt::Display for HandshakeRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the remote ended the TLS handshake")
+    }
+}
+
+/// Whether an error from a TLS connection means the remote ended it on purpose, rather than
+/// something like a network problem or it not speaking TLS.
+fn is_rejection(error: &anyhow::Error) -> bool {
+    if let Some(e) = error.downcast_ref::<io::Error>() {
+        return matches!(e.kind(),
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe);
+    }
+    matches!(error.downcast_ref::<rustls::Error>(), Some(rustls::Error::AlertReceived(_)))
 }
 
 impl Session {
+    /// A session over a transport that has been set up some other way. `device_name` is the name
+    /// our hello gives.
+    pub fn new(transport: Box<dyn Transport>, device_name: String) -> Session {
+        Session {
+            transport,
+            device_name,
+            next_request_id: 0,
+            verifier: None,
+            observer: None,
+        }
+    }
+
     pub fn write_hello(&mut self) -> Result<()> {
-        let mut tls_writer = self.tls.writer();
-        let mut output = protobuf::CodedOutputStream::new(&mut tls_writer);
+        let mut writer = self.transport.writer();
+        let mut output = protobuf::CodedOutputStream::new(&mut writer);
 
         let mut magic = [0u8;4];
         NetworkEndian::write_u32(&mut magic, HELLO_MAGIC);
@@ -46,7 +106,56 @@ impl Session {
         Ok(())
     }
 
-    pub fn read_hello(buf: &[u8]) -> Result<(usize, syncthing_proto::Hello)> {
+    /// Report what happens on the session to `observer`.
+    pub fn set_observer(&mut self, observer: Arc<dyn Observer>) {
+        self.observer = Some(observer);
+    }
+
+    /// Pass an event to the observer, if there is one. The session reports the messages it reads
+    /// and writes itself; this is for the code using it to report what the session can't see,
+    /// like a file being finished.
+    pub fn notify(&self, event: Event) {
+        if let Some(ref observer) = self.observer {
+            observer.event(&event);
+        }
+    }
+
+    pub fn read_hello(&self, buf: &[u8]) -> Result<(usize, syncthing_proto::Hello)> {
+        let result = Session::parse_hello(buf);
+        match result {
+            Ok((_, ref hello)) => self.notify(Event::Hello {
+                device_name: hello.device_name.clone(),
+                client_name: hello.client_name.clone(),
+                client_version: hello.client_version.clone(),
+            }),
+            Err(ref e) => self.notify(Event::Error(format!("{:#}", e))),
+        }
+        result
+    }
+
+    pub fn read_message(&self, buf: &[u8])
+            -> Result<(usize, syncthing_proto::MessageType, Box<dyn SyncthingMessage>)> {
+        let result = Session::parse_message(buf);
+        if self.observer.is_some() {
+            match result {
+                Ok((_, msgtype, ref message)) => {
+                    // Only the cluster config needs to know which device it came from.
+                    let remote = if msgtype == syncthing_proto::MessageType::CLUSTER_CONFIG {
+                        self.remote_device_id().map(|id| util::hash_from_device_id(&id))
+                    } else {
+                        None
+                    };
+                    if let Some(event) = events::message_event(message.as_ref(), remote.as_deref()) {
+                        self.notify(event);
+                    }
+                }
+                Err(ref e) => self.notify(Event::Error(format!("{:#}", e))),
+            }
+        }
+        result
+    }
+
+    fn parse_hello(buf: &[u8]) -> Result<(usize, syncthing_proto::Hello)> {
         let mut input = protobuf::CodedInputStream::from_bytes(buf);
 
         let magic = NetworkEndian::read_u32(&input.read_raw_bytes(4)?);
@@ -65,7 +174,7 @@ impl Session {
         Ok((input.pos() as usize, hello))
     }
 
-    pub fn read_message(buf: &[u8])
+    fn parse_message(buf: &[u8])
             -> Result<(usize, syncthing_proto::MessageType, Box<dyn SyncthingMessage>)> {
         let mut input = protobuf::CodedInputStream::from_bytes(buf);
 
@@ -125,8 +234,8 @@ impl Session {
         message_type: syncthing_proto::MessageType,
         ) -> Result<()>
     {
-        let mut tls_writer = self.tls.writer();
-        let mut output = protobuf::CodedOutputStream::new(&mut tls_writer);
+        let mut writer = self.transport.writer();
+        let mut output = protobuf::CodedOutputStream::new(&mut writer);
 
         let mut header = syncthing_proto::Header::new();
         header.compression = syncthing_proto::MessageCompression::NONE.into();
@@ -174,111 +283,74 @@ impl Session {
         req.from_temporary = false;
 
         self.write_message(&req, syncthing_proto::MessageType::REQUEST)?;
+        self.notify(Event::BlockRequested {
+            id: request_id,
+            folder: req.folder,
+            name: req.name,
+            offset,
+            size,
+        });
         Ok(request_id)
     }
 
     // FIXME(wfraser) only for testing
-    pub fn read_to_end(&mut self, data: &mut Vec<u8>) -> Result<usize> {
-        use std::io::Read;
-        use std::mem::transmute;
-
-        let mut nread = 0;
-        loop {
-            if data.len() == data.capacity() {
-                data.reserve(32);
-            }
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.transport.writer().write(data).map_err(|e| e.into())
+    }
 
-            match self.tls.reader().read(unsafe { transmute(data.spare_capacity_mut()) }) {
-                Ok(n) => {
-                    unsafe { data.set_len(data.len() + n); }
-                    nread += n;
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // No more plaintext available.
-                    break;
-                }
-                Err(e) => {
-                    return Err(e.into());
-                }
-            }
-        }
-        Ok(nread)
+    /// Do the TLS handshake. If the remote isn't the device we expected, the error is a
+    /// `DeviceIdMismatch`, and if the remote ended the handshake, it has `HandshakeRejected` as
+    /// context.
+    pub fn handshake(&mut self) -> Result<()> {
+        let result = self.transport.handshake();
+        result.map_err(|e| match self.verifier.as_ref().and_then(|v| v.mismatch()) {
+            Some(mismatch) => mismatch.into(),
+            None if is_rejection(&e) => e.context(HandshakeRejected),
+            None => e,
+        })
     }
 
-    // FIXME(wfraser) only for testing
-    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
-        use std::io::Write;
-        self.tls.writer().write(data).map_err(|e| e.into())
-    }
-
-    // This is basically rustls::ClientSession::complete_io() with extra logging.
-    // FIXME(wfraser) only public for testing
-    pub fn complete_io(&mut self) -> Result<(usize, usize)> {
-        let handshaking = self.tls.is_handshaking();
-        let mut eof = false;
-        let mut wrlen = 0;
-        let mut rdlen = 0;
-
-        loop {
-            while self.tls.wants_write() {
-                debug!("writing");
-                match self.tls.write_tls(&mut self.stream) {
-                    Ok(n) => {
-                        debug!("wrote {} bytes of TLS", n);
-                        wrlen += n;
-                    },
-                    Err(e) => {
-                        error!("write error: {}", e);
-                        return Err(e.into());
-                    }
+    /// Start a thread that reads raw data from the connection and sends it over a channel, tagged
+    /// with `tag`, so that several sessions can be waited on at once. Pass what it sends to
+    /// `receive`. An empty buffer means the connection was closed. The thread exits when the
+    /// connection is closed or the receiving end of the channel is dropped.
+    pub fn spawn_reader<T: Copy + Send + 'static>(
+        &mut self,
+        tag: T,
+        sender: mpsc::Sender<(T, io::Result<Vec<u8>>)>,
+    ) -> Result<()> {
+        let mut reader = self.transport.raw_reader().context("failed to set up the reader")?;
+        std::thread::spawn(move || {
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let result = reader.read(&mut buf).map(|n| buf[..n].to_vec());
+                let last = !matches!(result, Ok(ref data) if !data.is_empty());
+                if sender.send((tag, result)).is_err() || last {
+                    break;
                 }
             }
+        });
+        Ok(())
+    }
 
-            if !handshaking && wrlen > 0 {
-                debug!("write completed");
-                return Ok((rdlen, wrlen));
-            }
-
-            if !eof && self.tls.wants_read() {
-                debug!("reading");
-                match self.tls.read_tls(&mut self.stream) {
-                    Ok(n) => {
-                        debug!("read {} bytes of TLS", n);
-                        if n == 0 {
-                            eof = true;
-                        }
-                        rdlen += n;
-                    },
-                    Err(e) => {
-                        error!("read error: {}", e);
-                        return Err(e.into());
-                    }
-                }
-            }
+    /// Process raw data read by the thread started by `spawn_reader`, appending any plaintext it
+    /// contains to `data`. Returns the number of plaintext bytes.
+    pub fn receive(&mut self, raw: &[u8], data: &mut Vec<u8>) -> Result<usize> {
+        let result = self.transport.receive(raw, data);
+        if let Err(ref e) = result {
+            self.notify(Event::Error(format!("{:#}", e)));
+        }
+        result
+    }
 
-            if let Err(e) = self.tls.process_new_packets() {
-                error!("error processing TLS packets: {}", e);
-                return Err(e.into());
-            }
+    /// The device ID of the other end, once the handshake is done.
+    pub fn remote_device_id(&self) -> Option<String> {
+        self.transport.peer_certificate().map(|cert| cert_device_id(&cert))
+    }
 
-            match (eof, handshaking, self.tls.is_handshaking()) {
-                (_, true, false) => {
-                    debug!("done handshaking");
-                    return Ok((rdlen, wrlen));
-                },
-                (_, false, _) => {
-                    debug!("read completed");
-                    return Ok((rdlen, wrlen));
-                },
-                (true, true, true) => {
-                    debug!("unexpected EOF");
-                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
-                },
-                (..) => {
-                    debug!("looping again");
-                }
-            }
-        }
+    /// Send anything that has been written to the session.
+    pub fn flush(&mut self) -> Result<()> {
+        self.transport.flush()
     }
 }
 
@@ -291,8 +363,15 @@ impl ::std::fmt::Debug for Session {
 }
 
 pub struct SessionBuilder {
+    /// Where to connect: a host and port, or a `relay://` URL when connecting through a relay.
+    /// `DeviceAddress::parse` turns Syncthing's address URLs into this and `transport`.
     pub remote_host_and_port: String,
-    pub remote_device_id: String,
+    pub transport: TransportKind,
+    /// Tunnel the connection through this proxy. QUIC connections can't use one.
+    pub proxy: Option<Proxy>,
+    /// The device we expect to find. If it's None, any device is accepted, and
+    /// `Session::remote_device_id` says which one it was. Relays need to know it.
+    pub remote_device_id: Option<String>,
     pub local_device_name: Option<String>,
     pub client_cert: super::Certificate,
     pub private_key: super::PrivateKey,
@@ -300,55 +379,172 @@ pub struct SessionBuilder {
 
 impl SessionBuilder {
     pub fn connect(self) -> Result<Session> {
-        let device_name = match self.local_device_name {
-            Some(name) => name,
-            None => match util::get_hostname() {
-                Ok(name) => {
-                    debug!("no device name specified; using hostname {}", name);
-                    name
-                },
-                Err(e) => {
-                    error!("failed to get the system hostname: {}", e);
-                    return Err(e).context("failed to get the system hostname");
-                }
+        let device_name = local_device_name(self.local_device_name)?;
+
+        let host_and_port = &self.remote_host_and_port;
+        let verifier = self.remote_device_id.clone()
+            .map(|device_id| Arc::new(SyncthingCertVerifier::new(device_id)));
+        let server_cert_verifier: Arc<dyn rustls::client::ServerCertVerifier> = match verifier {
+            Some(ref verifier) => verifier.clone(),
+            None => Arc::new(AnyServerCert),
+        };
+        let transport: Box<dyn Transport> = match self.transport {
+            TransportKind::Tcp | TransportKind::Relay => {
+                let stream = if self.transport == TransportKind::Relay {
+                    let device_id = self.remote_device_id.as_ref()
+                        .context("a device ID is needed to connect through a relay")?;
+                    let device_hash = util::hash_from_device_id(device_id);
+                    relay::connect(host_and_port, &device_hash, &self.client_cert, &self.private_key,
+                                   self.proxy.as_ref())
+                        .map_err(|e| {
+                            error!("failed to connect through {}: {:#}", host_and_port, e);
+                            e
+                        })?
+                } else if let Some(ref proxy) = self.proxy {
+                    proxy.connect_to(host_and_port).map_err(|e| {
+                        error!("failed to connect to {}: {:#}", host_and_port, e);
+                        e
+                    })?
+                } else {
+                    TcpStream::connect(host_and_port).map_err(|e| {
+                        error!("failed to connect to {}: {}", host_and_port, e);
+                        e
+                    })?
+                };
+
+                let mut config = rustls::ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_custom_certificate_verifier(server_cert_verifier)
+                    .with_single_cert(vec![self.client_cert], self.private_key)?;
+                config.alpn_protocols.push(b"bep/1.0".to_vec());
+
+                let dnsname = rustls::ServerName::try_from("syncthing")?;
+                let tls = rustls::ClientConnection::new(Arc::new(config), dnsname)?;
+                Box::new(TlsTransport::new(rustls::Connection::Client(tls), stream))
+            }
+            TransportKind::Quic if self.proxy.as_ref().is_some_and(|proxy| {
+                let host = host_and_port.rsplit_once(':')
+                    .map_or(host_and_port.as_str(), |(host, _)| host);
+                !proxy.bypasses(host)
+            }) => {
+                bail!("QUIC connections can't go through a proxy");
+            }
+            TransportKind::Quic => {
+                // QUIC only does TLS 1.3.
+                let mut config = rustls::ClientConfig::builder()
+                    .with_safe_default_cipher_suites()
+                    .with_safe_default_kx_groups()
+                    .with_protocol_versions(&[&rustls::version::TLS13])?
+                    .with_custom_certificate_verifier(server_cert_verifier)
+                    .with_single_cert(vec![self.client_cert], self.private_key)?;
+                config.alpn_protocols.push(b"bep/1.0".to_vec());
+                // The handshake happens while connecting, so a mismatch shows up here.
+                connect_quic(host_and_port, config).map_err(|e| {
+                    error!("failed to connect to {} over QUIC: {:#}", host_and_port, e);
+                    match verifier.as_ref().and_then(|v| v.mismatch()) {
+                        Some(mismatch) => mismatch.into(),
+                        None => e,
+                    }
+                })?
             }
         };
-        info!("our device name is {:?}", device_name);
 
-        let mut config = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_custom_certificate_verifier(
-                Arc::new(SyncthingCertVerifier::new(self.remote_device_id)))
-            .with_single_cert(vec![self.client_cert], self.private_key)?;
-        config.alpn_protocols.push(b"bep/1.0".to_vec());
+        Ok(Session {
+            transport,
+            device_name,
+            next_request_id: 0,
+            verifier,
+            observer: None,
+        })
+    }
+}
 
-        let host_and_port = &self.remote_host_and_port;
-        let stream = TcpStream::connect(host_and_port).map_err(|e| {
-            error!("failed to connect to {}: {}", host_and_port, e);
-            e
-        })?;
+/// Sets up sessions on connections that other devices make to us. Only the devices listed in
+/// `allowed_device_ids` are accepted.
+pub struct SessionAcceptor {
+    pub allowed_device_ids: Vec<String>,
+    pub local_device_name: Option<String>,
+    pub server_cert: super::Certificate,
+    pub private_key: super::PrivateKey,
+}
 
-        let dnsname = rustls::ServerName::try_from("syncthing")?;
+impl SessionAcceptor {
+    /// Start a session on an incoming connection. The TLS handshake happens in
+    /// `Session::handshake`, which fails if the device isn't allowed; after that,
+    /// `Session::remote_device_id` says which device it is.
+    pub fn accept(&self, stream: TcpStream) -> Result<Session> {
+        let device_name = local_device_name(self.local_device_name.clone())?;
+
+        let verifier = Arc::new(AllowedDevicesVerifier {
+            device_ids: self.allowed_device_ids.clone(),
+        });
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(vec![self.server_cert.clone()], self.private_key.clone())?;
+        config.alpn_protocols.push(b"bep/1.0".to_vec());
 
+        let tls = rustls::ServerConnection::new(Arc::new(config))?;
         Ok(Session {
-            tls: rustls::ClientConnection::new(Arc::new(config), dnsname)?,
-            stream,
+            transport: Box::new(TlsTransport::new(rustls::Connection::Server(tls), stream)),
             device_name,
             next_request_id: 0,
+            verifier: None,
+            observer: None,
         })
     }
 }
 
-struct SyncthingCertVerifier {
+fn local_device_name(name: Option<String>) -> Result<String> {
+    let device_name = match name {
+        Some(name) => name,
+        None => match util::get_hostname() {
+            Ok(name) => {
+                debug!("no device name specified; using hostname {}", name);
+                name
+            },
+            Err(e) => {
+                error!("failed to get the system hostname: {}", e);
+                return Err(e).context("failed to get the system hostname");
+            }
+        }
+    };
+    info!("our device name is {:?}", device_name);
+    Ok(device_name)
+}
+
+#[cfg(feature = "quic")]
+fn connect_quic(host_and_port: &str, config: rustls::ClientConfig) -> Result<Box<dyn Transport>> {
+    Ok(Box::new(crate::quic::QuicTransport::connect(host_and_port, config)?))
+}
+
+#[cfg(not(feature = "quic"))]
+fn connect_quic(_host_and_port: &str, _config: rustls::ClientConfig) -> Result<Box<dyn Transport>> {
+    bail!("stget was built without QUIC support");
+}
+
+/// Accepts a server certificate only if its hash matches the expected device ID.
+pub(crate) struct SyncthingCertVerifier {
     device_id: String,
+    /// The device ID of a certificate that was refused, so the error can say what it was.
+    presented: Mutex<Option<String>>,
 }
 
 impl SyncthingCertVerifier {
-    pub fn new(device_id: String) -> SyncthingCertVerifier {
+    pub(crate) fn new(device_id: String) -> SyncthingCertVerifier {
         SyncthingCertVerifier {
             device_id,
+            presented: Mutex::new(None),
         }
     }
+
+    fn mismatch(&self) -> Option<DeviceIdMismatch> {
+        let presented = self.presented.lock().unwrap().clone()?;
+        Some(DeviceIdMismatch {
+            expected: self.device_id.clone(),
+            presented,
+        })
+    }
 }
 
 impl rustls::client::ServerCertVerifier for SyncthingCertVerifier {
@@ -362,20 +558,151 @@ impl rustls::client::ServerCertVerifier for SyncthingCertVerifier {
         _now: std::time::SystemTime,
     ) -> ::std::result::Result<rustls::client::ServerCertVerified, rustls::Error>
     {
-        use rustls::internal::msgs::codec::Codec;
         debug!("Checking device ID");
-        let cert_bytes = end_entity.get_encoding();
-        let mut hash_ctx = ring::digest::Context::new(&ring::digest::SHA256);
-        hash_ctx.update(&cert_bytes[3..]);
-        let digest = hash_ctx.finish();
-        debug!("cert hash is {:?}", digest);
-        let device_id = util::device_id_from_hash(digest.as_ref());
-        debug!("device ID {}", device_id);
+        let device_id = cert_device_id(end_entity);
         if device_id == self.device_id {
             debug!("device ID matches");
             return Ok(rustls::client::ServerCertVerified::assertion());
         }
         error!("none of the presented server certificates have the expected Device ID");
+        *self.presented.lock().unwrap() = Some(device_id);
         Err(rustls::Error::General("Syncthing device ID mismatch".to_owned()))
     }
 }
+
+/// Accepts a client certificate only if its hash matches one of the allowed device IDs.
+struct AllowedDevicesVerifier {
+    device_ids: Vec<String>,
+}
+
+impl rustls::server::ClientCertVerifier for AllowedDevicesVerifier {
+    fn client_auth_root_subjects(&self) -> Option<rustls::DistinguishedNames> {
+        // Syncthing certificates are self-signed, so there are no CAs to suggest.
+        Some(vec![])
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _now: std::time::SystemTime,
+    ) -> ::std::result::Result<rustls::server::ClientCertVerified, rustls::Error>
+    {
+        debug!("Checking client device ID");
+        let device_id = cert_device_id(end_entity);
+        if self.device_ids.contains(&device_id) {
+            debug!("device ID is allowed");
+            return Ok(rustls::server::ClientCertVerified::assertion());
+        }
+        error!("device {} isn't allowed to connect", device_id);
+        Err(rustls::Error::General(format!("device {} isn't allowed to connect", device_id)))
+    }
+}
+
+/// The device ID for a certificate: its SHA-256 hash, encoded the way Syncthing does.
+fn cert_device_id(cert: &rustls::Certificate) -> String {
+    use rustls::internal::msgs::codec::Codec;
+    let cert_bytes = cert.get_encoding();
+    let mut hash_ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    hash_ctx.update(&cert_bytes[3..]);
+    let digest = hash_ctx.finish();
+    debug!("cert hash is {:?}", digest);
+    let device_id = util::device_id_from_hash(digest.as_ref());
+    debug!("device ID {}", device_id);
+    device_id
+}
+
+/// Accepts any server certificate, for servers that aren't identified by a device ID.
+pub(crate) struct AnyServerCert;
+
+impl rustls::client::ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> ::std::result::Result<rustls::client::ServerCertVerified, rustls::Error>
+    {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Keeps what's written to it, for tests.
+#[cfg(test)]
+struct BufferTransport {
+    written: Arc<Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl Transport for BufferTransport {
+    fn handshake(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn writer(&mut self) -> Box<dyn Write + '_> {
+        struct SharedWriter<'a>(&'a Mutex<Vec<u8>>);
+        impl Write for SharedWriter<'_> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        Box::new(SharedWriter(&self.written))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn raw_reader(&mut self) -> Result<Box<dyn Read + Send>> {
+        bail!("not supported");
+    }
+
+    fn receive(&mut self, _raw: &[u8], _data: &mut Vec<u8>) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn peer_certificate(&self) -> Option<rustls::Certificate> {
+        None
+    }
+}
+
+#[test]
+fn test_observer() {
+    let written = Arc::new(Mutex::new(vec![]));
+    let mut session = Session::new(
+        Box::new(BufferTransport { written: written.clone() }), "laptop".to_owned());
+    let events = Arc::new(Mutex::new(vec![]));
+    let seen = events.clone();
+    session.set_observer(Arc::new(move |event: &Event| seen.lock().unwrap().push(event.clone())));
+
+    // Read back our own hello.
+    session.write_hello().unwrap();
+    let hello = written.lock().unwrap().clone();
+    session.read_hello(&hello).unwrap();
+    let id = session.write_block_request(
+        "abcd-1234".to_owned(), "photo.jpg".to_owned(), 131072, 1024, vec![0; 32]).unwrap();
+
+    assert_eq!(
+        vec![
+            Event::Hello {
+                device_name: "laptop".to_owned(),
+                client_name: env!("CARGO_PKG_NAME").to_owned(),
+                client_version: env!("CARGO_PKG_VERSION").to_owned(),
+            },
+            Event::BlockRequested {
+                id,
+                folder: "abcd-1234".to_owned(),
+                name: "photo.jpg".to_owned(),
+                offset: 131072,
+                size: 1024,
+            },
+        ],
+        *events.lock().unwrap());
+}
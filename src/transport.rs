@@ -0,0 +1,383 @@
+use anyhow::{bail, Context, Result};
+use crate::relay::RelayUrl;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+
+/// How a `Session` reaches the remote device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// TLS over a TCP connection.
+    Tcp,
+    /// TLS over a TCP connection relayed by a Syncthing relay server.
+    Relay,
+    /// A QUIC connection, which has TLS built in. Only available with the `quic` feature.
+    Quic,
+}
+
+/// The port Syncthing listens on unless configured otherwise.
+pub const DEFAULT_PORT: u16 = 22000;
+
+/// Which IP version a `tcp4://`/`tcp6://` (or `quic4://`/`quic6://`) address is restricted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+/// An address to connect to a device at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceAddress {
+    pub transport: TransportKind,
+    /// The host and port, with brackets around IPv6 addresses, which is what `SessionBuilder`
+    /// takes. For relays it's the whole `relay://` URL.
+    pub host_and_port: String,
+    pub ip_version: Option<IpVersion>,
+}
+
+impl DeviceAddress {
+    /// Parse an address the way Syncthing writes them (`tcp://192.0.2.1:22000`,
+    /// `tcp6://[2001:db8::1]:22000`, `quic://example.com`, `relay://...`), or a host name or IP
+    /// address on its own, optionally with a port (`example.com`, `192.0.2.1:22001`, `::1`,
+    /// `[::1]:22001`), which is taken to be TCP. The port defaults to 22000.
+    pub fn parse(address: &str) -> Result<DeviceAddress> {
+        let (scheme, rest) = address.split_once("://").unwrap_or(("tcp", address));
+        let (transport, ip_version) = match scheme {
+            "tcp" => (TransportKind::Tcp, None),
+            "tcp4" => (TransportKind::Tcp, Some(IpVersion::V4)),
+            "tcp6" => (TransportKind::Tcp, Some(IpVersion::V6)),
+            "quic" => (TransportKind::Quic, None),
+            "quic4" => (TransportKind::Quic, Some(IpVersion::V4)),
+            "quic6" => (TransportKind::Quic, Some(IpVersion::V6)),
+            "relay" => {
+                RelayUrl::parse(address)?;
+                return Ok(DeviceAddress {
+                    transport: TransportKind::Relay,
+                    host_and_port: address.to_owned(),
+                    ip_version: None,
+                });
+            }
+            other => bail!("unsupported address type {:?} in {:?}", other, address),
+        };
+
+        // Syncthing doesn't put anything after the port, but allow a trailing slash.
+        let authority = rest.split(['/', '?']).next().unwrap();
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let (host, after) = bracketed.split_once(']')
+                .with_context(|| format!("missing ']' in {:?}", address))?;
+            match after {
+                "" => (host, None),
+                _ => match after.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => bail!("unexpected {:?} after the host in {:?}", after, address),
+                },
+            }
+        } else if authority.matches(':').count() > 1 {
+            // An IPv6 address without brackets, which can't have a port.
+            (authority, None)
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+
+        if host.is_empty() {
+            bail!("no host in {:?}", address);
+        }
+        let host_and_port = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+            Ok(IpAddr::V4(ip)) => ip.to_string(),
+            Err(_) if host.contains(':') => bail!("bad IPv6 address {:?} in {:?}", host, address),
+            Err(_) => host.to_owned(),
+        };
+        let port = match port {
+            Some(port) => port.parse::<u16>()
+                .with_context(|| format!("bad port {:?} in {:?}", port, address))?,
+            None => DEFAULT_PORT,
+        };
+        Ok(DeviceAddress {
+            transport,
+            host_and_port: format!("{}:{}", host_and_port, port),
+            ip_version,
+        })
+    }
+
+    /// Look up the IP addresses the host resolves to, returning an address for each one, ordered
+    /// for connection attempts the way RFC 8305 (happy eyeballs) suggests: alternating between
+    /// IPv6 and IPv4, starting with IPv6. Relay addresses are returned as they are; they're looked
+    /// up when connecting.
+    pub fn resolve(&self) -> Result<Vec<DeviceAddress>> {
+        if self.transport == TransportKind::Relay {
+            return Ok(vec![self.clone()]);
+        }
+        let (mut v6, mut v4): (Vec<SocketAddr>, Vec<SocketAddr>) = self.host_and_port
+            .to_socket_addrs()
+            .with_context(|| format!("failed to look up {}", self.host_and_port))?
+            .filter(|addr| match self.ip_version {
+                Some(IpVersion::V4) => addr.is_ipv4(),
+                Some(IpVersion::V6) => addr.is_ipv6(),
+                None => true,
+            })
+            .partition(SocketAddr::is_ipv6);
+        if v6.is_empty() && v4.is_empty() {
+            bail!("no {}addresses found for {}",
+                  match self.ip_version {
+                      Some(IpVersion::V4) => "IPv4 ",
+                      Some(IpVersion::V6) => "IPv6 ",
+                      None => "",
+                  },
+                  self.host_and_port);
+        }
+        v6.dedup();
+        v4.dedup();
+        let mut addrs = vec![];
+        let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+        loop {
+            match (v6.next(), v4.next()) {
+                (None, None) => break,
+                (a, b) => addrs.extend(a.into_iter().chain(b)),
+            }
+        }
+        Ok(addrs.into_iter()
+            .map(|addr| DeviceAddress {
+                transport: self.transport,
+                host_and_port: addr.to_string(),
+                ip_version: self.ip_version,
+            })
+            .collect())
+    }
+}
+
+impl fmt::Display for DeviceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = match (self.transport, self.ip_version) {
+            (TransportKind::Relay, _) => return f.write_str(&self.host_and_port),
+            (TransportKind::Tcp, None) => "tcp",
+            (TransportKind::Tcp, Some(IpVersion::V4)) => "tcp4",
+            (TransportKind::Tcp, Some(IpVersion::V6)) => "tcp6",
+            (TransportKind::Quic, None) => "quic",
+            (TransportKind::Quic, Some(IpVersion::V4)) => "quic4",
+            (TransportKind::Quic, Some(IpVersion::V6)) => "quic6",
+        };
+        write!(f, "{}://{}", scheme, self.host_and_port)
+    }
+}
+
+#[test]
+fn test_device_address() {
+    let parse = |s| DeviceAddress::parse(s).map(|address| address.to_string());
+    assert_eq!("tcp://192.0.2.1:22000", parse("192.0.2.1").unwrap());
+    assert_eq!("tcp://192.0.2.1:22001", parse("192.0.2.1:22001").unwrap());
+    assert_eq!("tcp://example.com:22000", parse("example.com").unwrap());
+    assert_eq!("tcp://[::1]:22000", parse("::1").unwrap());
+    assert_eq!("tcp://[::1]:22000", parse("[::1]").unwrap());
+    assert_eq!("tcp://[2001:db8::1]:22001", parse("[2001:db8::1]:22001").unwrap());
+    assert_eq!("tcp://[2001:db8::1]:22000", parse("tcp://[2001:db8::1]:22000/").unwrap());
+    assert_eq!("tcp4://192.0.2.1:22000", parse("tcp4://192.0.2.1:22000").unwrap());
+    assert_eq!("tcp6://[2001:db8::1]:22000", parse("tcp6://[2001:db8::1]").unwrap());
+    assert_eq!("quic://example.com:22000", parse("quic://example.com").unwrap());
+    assert_eq!("quic6://[::1]:22000", parse("quic6://[::1]:22000").unwrap());
+
+    let relay = DeviceAddress::parse("relay://192.0.2.1:22067/?id=\
+        SH5RUEY-JV6VLZE-K2PVLMH-QOASZP2-L5LMNHV-AAS5UWG-CT2IITD-CAQBTQ6").unwrap();
+    assert_eq!(TransportKind::Relay, relay.transport);
+    assert!(relay.host_and_port.starts_with("relay://"));
+
+    assert!(parse("dynamic://").is_err());
+    assert!(parse("tcp://:22000").is_err());
+    assert!(parse("192.0.2.1:port").is_err());
+    assert!(parse("192.0.2.1:65536").is_err());
+    assert!(parse("[::1]x").is_err());
+    assert!(parse("[::1").is_err());
+    assert!(parse("1::2::3").is_err());
+}
+
+#[test]
+fn test_resolve_order() {
+    let address = DeviceAddress::parse("[::1]:22001").unwrap();
+    assert_eq!(vec![address.clone()], address.resolve().unwrap());
+    let address = DeviceAddress::parse("tcp4://[::1]:22001").unwrap();
+    assert!(address.resolve().is_err());
+}
+
+/// The connection underneath a `Session`. BEP messages are written to it and read from it as
+/// plaintext; whatever encryption the transport uses happens in here.
+pub trait Transport: Send {
+    /// Finish setting up the connection, e.g. the TLS handshake.
+    fn handshake(&mut self) -> Result<()>;
+
+    /// Where to write plaintext to send. Nothing is sent until `flush` is called.
+    fn writer(&mut self) -> Box<dyn Write + '_>;
+
+    /// Send anything that has been written.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Something to read raw data from the connection with, on another thread. What it reads is
+    /// passed to `receive`. Can only be called once.
+    fn raw_reader(&mut self) -> Result<Box<dyn Read + Send>>;
+
+    /// Process raw data read by the raw reader, appending any plaintext it contains to `data`.
+    /// Returns the number of plaintext bytes. Empty `raw` data means the connection was closed.
+    fn receive(&mut self, raw: &[u8], data: &mut Vec<u8>) -> Result<usize>;
+
+    /// The certificate the other end presented, once the handshake is done.
+    fn peer_certificate(&self) -> Option<rustls::Certificate>;
+}
+
+/// TLS over a TCP stream, which may be relayed. We can be either end of the TLS connection.
+pub struct TlsTransport {
+    tls: rustls::Connection,
+    stream: TcpStream,
+}
+
+impl TlsTransport {
+    pub fn new(tls: rustls::Connection, stream: TcpStream) -> TlsTransport {
+        TlsTransport { tls, stream }
+    }
+
+    fn read_to_end(&mut self, data: &mut Vec<u8>) -> Result<usize> {
+        use std::io::Read;
+        use std::mem::transmute;
+
+        let mut nread = 0;
+        loop {
+            if data.len() == data.capacity() {
+                data.reserve(32);
+            }
+
+            match self.tls.reader().read(unsafe { transmute::<&mut [std::mem::MaybeUninit<u8>], &mut [u8]>(data.spare_capacity_mut()) }) {
+                Ok(0) if nread == 0 => {
+                    // The remote closed the connection cleanly.
+                    return Err(io::Error::from(io::ErrorKind::ConnectionAborted).into());
+                }
+                Ok(0) => break,
+                Ok(n) => {
+                    unsafe { data.set_len(data.len() + n); }
+                    nread += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // No more plaintext available.
+                    break;
+                }
+                Err(e) => {
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(nread)
+    }
+
+    // This is basically rustls::ClientSession::complete_io() with extra logging.
+    fn complete_io(&mut self) -> Result<(usize, usize)> {
+        let handshaking = self.tls.is_handshaking();
+        let mut eof = false;
+        let mut wrlen = 0;
+        let mut rdlen = 0;
+
+        loop {
+            while self.tls.wants_write() {
+                debug!("writing");
+                match self.tls.write_tls(&mut self.stream) {
+                    Ok(n) => {
+                        debug!("wrote {} bytes of TLS", n);
+                        wrlen += n;
+                    },
+                    Err(e) => {
+                        error!("write error: {}", e);
+                        return Err(e.into());
+                    }
+                }
+            }
+
+            if !handshaking && wrlen > 0 {
+                debug!("write completed");
+                return Ok((rdlen, wrlen));
+            }
+
+            if !eof && self.tls.wants_read() {
+                debug!("reading");
+                match self.tls.read_tls(&mut self.stream) {
+                    Ok(n) => {
+                        debug!("read {} bytes of TLS", n);
+                        if n == 0 {
+                            eof = true;
+                        }
+                        rdlen += n;
+                    },
+                    Err(e) => {
+                        error!("read error: {}", e);
+                        return Err(e.into());
+                    }
+                }
+            }
+
+            if let Err(e) = self.tls.process_new_packets() {
+                error!("error processing TLS packets: {}", e);
+                return Err(e.into());
+            }
+
+            match (eof, handshaking, self.tls.is_handshaking()) {
+                (_, true, false) => {
+                    debug!("done handshaking");
+                    return Ok((rdlen, wrlen));
+                },
+                (_, false, _) => {
+                    debug!("read completed");
+                    return Ok((rdlen, wrlen));
+                },
+                (true, true, true) => {
+                    debug!("unexpected EOF");
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                },
+                (..) => {
+                    debug!("looping again");
+                }
+            }
+        }
+    }
+}
+
+impl Transport for TlsTransport {
+    fn handshake(&mut self) -> Result<()> {
+        while self.tls.is_handshaking() {
+            self.complete_io()?;
+        }
+        Ok(())
+    }
+
+    fn writer(&mut self) -> Box<dyn Write + '_> {
+        Box::new(self.tls.writer())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        while self.tls.wants_write() {
+            self.tls.write_tls(&mut self.stream)?;
+        }
+        Ok(())
+    }
+
+    fn raw_reader(&mut self) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.stream.try_clone()?))
+    }
+
+    fn receive(&mut self, mut tls_data: &[u8], data: &mut Vec<u8>) -> Result<usize> {
+        if tls_data.is_empty() {
+            // Let rustls know about the EOF.
+            self.tls.read_tls(&mut tls_data)?;
+            self.tls.process_new_packets()?;
+            return self.read_to_end(data);
+        }
+        let mut nread = 0;
+        while !tls_data.is_empty() {
+            self.tls.read_tls(&mut tls_data)?;
+            self.tls.process_new_packets()?;
+            nread += self.read_to_end(data)?;
+        }
+        Ok(nread)
+    }
+
+    fn peer_certificate(&self) -> Option<rustls::Certificate> {
+        self.tls.peer_certificates()?.first().cloned()
+    }
+}
@@ -1,4 +1,7 @@
+use std::fs::File;
 use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use base32;
 
 #[cfg(unix)]
@@ -63,6 +66,186 @@ fn test_reverse_device_id() {
     assert_eq!(&hash, &hash_from_device_id("JDF55R5-QQJBXUN-QQPSVFT-HFCAV6J-7NSVM7I-2KBA7PI-4MGOAIR-FA3I4AH"));
 }
 
+/// Check whether a string is a well-formed device ID, including its check characters.
+pub fn is_device_id(s: &str) -> bool {
+    let groups = s.split('-').collect::<Vec<_>>();
+    if groups.len() != 8
+        || groups.iter().any(|g| g.len() != 7 || !g.bytes().all(|c| matches!(c, b'A' ..= b'Z' | b'2' ..= b'7')))
+    {
+        return false;
+    }
+    device_id_from_hash(&hash_from_device_id(s)) == s
+}
+
+#[test]
+fn test_is_device_id() {
+    assert!(is_device_id("JDF55R5-QQJBXUN-QQPSVFT-HFCAV6J-7NSVM7I-2KBA7PI-4MGOAIR-FA3I4AH"));
+    assert!(!is_device_id("JDF55R5-QQJBXUN-QQPSVFT-HFCAV6J-7NSVM7I-2KBA7PI-4MGOAIR-FA3I4AG"));
+    assert!(!is_device_id("192.0.2.1:22000"));
+}
+
+/// Syncthing identifies devices in version vectors and `modified_by` fields by a "short ID": the
+/// first 64 bits of the certificate hash, big-endian. It displays these as the first 7 characters
+/// of the base32 encoding.
+pub fn short_device_id(short_id: u64) -> String {
+    let s = base32::encode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &short_id.to_be_bytes());
+    s[0..7].to_owned()
+}
+
+#[test]
+fn test_short_device_id() {
+    // First 8 bytes of the hash used in test_device_id above.
+    assert_eq!("JDF55R5", short_device_id(0x48cb_dec7_b082_437a));
+}
+
+/// Convert a count of days since 1970-01-01 into a (year, month, day) civil date.
+/// This is Howard Hinnant's `civil_from_days` algorithm.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let y = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/// Format a Unix timestamp as `YYYY-MM-DD HH:MM:SS` in UTC.
+pub fn format_timestamp(secs: i64) -> String {
+    let days = secs.div_euclid(86_400);
+    let rem = secs.rem_euclid(86_400);
+    let (y, m, d) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            y, m, d, rem / 3600, (rem / 60) % 60, rem % 60)
+}
+
+#[test]
+fn test_format_timestamp() {
+    assert_eq!("1970-01-01 00:00:00", format_timestamp(0));
+    assert_eq!("2026-01-01 12:34:56", format_timestamp(1_767_270_896));
+    assert_eq!("1969-12-31 23:59:59", format_timestamp(-1));
+    assert_eq!("2024-02-29 23:59:59", format_timestamp(1_709_251_199));
+    assert_eq!("2000-03-01 00:00:00", format_timestamp(951_868_800));
+    assert_eq!("10000-01-01 00:00:00", format_timestamp(253_402_300_800));
+}
+
+/// The inverse of `civil_from_days`: the number of days since 1970-01-01 of the given date.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let m = i64::from(month);
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The number of days in a month of the given year.
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parse a UTC date of the form `YYYY-MM-DD`, optionally followed by a space or `T` and `HH:MM` or
+/// `HH:MM:SS`, into a Unix timestamp.
+pub fn parse_timestamp(s: &str) -> anyhow::Result<i64> {
+    let bad = || anyhow::anyhow!("invalid date {:?}; expected YYYY-MM-DD[ HH:MM[:SS]]", s);
+    let s = s.trim_end_matches('Z');
+    let (date, time) = match s.find([' ', 'T']) {
+        Some(i) => (&s[..i], Some(&s[i + 1 ..])),
+        None => (s, None),
+    };
+
+    let date_parts = date.split('-').map(str::parse::<i64>).collect::<Result<Vec<_>, _>>()
+        .map_err(|_| bad())?;
+    let (year, month, day) = match date_parts[..] {
+        [y, m @ 1 ..= 12, d @ 1 ..= 31] if d as u32 <= days_in_month(y, m as u32) =>
+            (y, m as u32, d as u32),
+        _ => return Err(bad()),
+    };
+
+    let mut secs = days_from_civil(year, month, day) * 86_400;
+    if let Some(time) = time {
+        let time_parts = time.split(':').map(str::parse::<i64>).collect::<Result<Vec<_>, _>>()
+            .map_err(|_| bad())?;
+        secs += match time_parts[..] {
+            [h @ 0 ..= 23, m @ 0 ..= 59] => h * 3600 + m * 60,
+            [h @ 0 ..= 23, m @ 0 ..= 59, s @ 0 ..= 60] => h * 3600 + m * 60 + s,
+            _ => return Err(bad()),
+        };
+    }
+    Ok(secs)
+}
+
+#[test]
+fn test_parse_timestamp() {
+    assert_eq!(0, parse_timestamp("1970-01-01").unwrap());
+    assert_eq!(1_767_225_600, parse_timestamp("2026-01-01").unwrap());
+    assert_eq!(1_767_270_896, parse_timestamp("2026-01-01T12:34:56Z").unwrap());
+    assert!(parse_timestamp("2026-13-01").is_err());
+    assert!(parse_timestamp("2026-02-31").is_err());
+    assert!(parse_timestamp("2026-04-31").is_err());
+    assert!(parse_timestamp("2026-02-29").is_err());
+    assert_eq!(parse_timestamp("2024-03-01").unwrap() - 86_400, parse_timestamp("2024-02-29").unwrap());
+    assert!(parse_timestamp("1900-02-29").is_err());
+    assert!(parse_timestamp("2000-02-29").is_ok());
+    assert!(parse_timestamp("yesterday").is_err());
+}
+
+/// Parse a byte count with an optional binary suffix: `K`, `M`, `G`, or `T`, optionally followed by
+/// `B` or `iB` (e.g. `1G`, `500KiB`, `4096`).
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let bad = || anyhow::anyhow!("invalid size {:?}; expected a number with an optional K/M/G/T suffix", s);
+    let trimmed = s.trim();
+    let without_b = trimmed.strip_suffix("iB")
+        .or_else(|| trimmed.strip_suffix('B'))
+        .unwrap_or(trimmed);
+    let (digits, multiplier) = match without_b.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&without_b[.. without_b.len() - 1], 1u64 << 10),
+        Some('M') => (&without_b[.. without_b.len() - 1], 1 << 20),
+        Some('G') => (&without_b[.. without_b.len() - 1], 1 << 30),
+        Some('T') => (&without_b[.. without_b.len() - 1], 1 << 40),
+        _ => (without_b, 1),
+    };
+    let n: f64 = digits.parse().map_err(|_| bad())?;
+    if !n.is_finite() || n < 0. {
+        return Err(bad());
+    }
+    Ok((n * multiplier as f64) as u64)
+}
+
+/// Parse a rate limit in bytes per second, written like a size. Zero is refused, since nothing
+/// would ever be transferred.
+pub fn parse_rate(s: &str) -> anyhow::Result<u64> {
+    match parse_size(s)? {
+        0 => anyhow::bail!("invalid rate {:?}; it has to be at least one byte per second", s),
+        rate => Ok(rate),
+    }
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(4096, parse_size("4096").unwrap());
+    assert_eq!(1 << 30, parse_size("1G").unwrap());
+    assert_eq!(5 << 20, parse_size("5M").unwrap());
+    assert_eq!(500 << 10, parse_size("500KiB").unwrap());
+    assert_eq!(1536, parse_size("1.5k").unwrap());
+    assert!(parse_size("lots").is_err());
+
+    assert_eq!(5 << 20, parse_rate("5M").unwrap());
+    assert!(parse_rate("0").is_err());
+    assert!(parse_rate("0.1").is_err());
+}
+
 /// This is similar to Luhn mod 32, except with some bugs that are in the Syncthing implementation:
 /// the initial factor is 1 instead of 2, and it reads the input forwards instead of in reverse.
 fn syncthing_luhn(group: &str) -> char {
@@ -84,6 +267,324 @@ fn syncthing_luhn(group: &str) -> char {
     ALPHABET[check_codepoint] as char
 }
 
+/// Set a file's modification time from a Unix timestamp.
+pub fn set_file_mtime(file: &File, secs: i64, nanos: i32) -> io::Result<()> {
+    let time = system_time(secs, nanos).ok_or_else(|| timestamp_out_of_range(secs))?;
+    file.set_times(std::fs::FileTimes::new().set_modified(time))
+}
+
+/// Set the modification time of whatever is at a path. Unlike `set_file_mtime`, this works for
+/// directories on Windows too, where they can't be opened as a `File`.
+pub fn set_path_mtime(path: &Path, secs: i64, nanos: i32) -> io::Result<()> {
+    let time = system_time(secs, nanos).ok_or_else(|| timestamp_out_of_range(secs))?;
+    filetime::set_file_mtime(path, filetime::FileTime::from_system_time(time))
+}
+
+/// Set a file's Unix permission bits.
+#[cfg(unix)]
+pub fn set_file_mode(file: &File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))
+}
+
+/// Windows only has a read-only flag, so that's set if none of the write bits are.
+#[cfg(windows)]
+pub fn set_file_mode(file: &File, mode: u32) -> io::Result<()> {
+    let mut perms = file.metadata()?.permissions();
+    perms.set_readonly(mode & 0o222 == 0);
+    file.set_permissions(perms)
+}
+
+/// Set the permission bits of whatever is at a path, like `set_file_mode`.
+#[cfg(unix)]
+pub fn set_path_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))
+}
+
+#[cfg(windows)]
+pub fn set_path_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut perms = std::fs::metadata(path)?.permissions();
+    perms.set_readonly(mode & 0o222 == 0);
+    std::fs::set_permissions(path, perms)
+}
+
+/// Resolve a relative symlink target against the directory containing the link, without touching
+/// the filesystem. Both are relative to some root directory. Returns None if the target is an
+/// absolute path or if it climbs out of the root.
+pub fn resolve_relative_path(link_dir: &Path, target: &str) -> Option<PathBuf> {
+    let target = Path::new(target);
+    if target.has_root() {
+        return None;
+    }
+    let mut resolved = vec![];
+    for component in link_dir.components().chain(target.components()) {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                resolved.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved.iter().collect())
+}
+
+#[test]
+fn test_resolve_relative_path() {
+    let resolve = |dir: &str, target: &str| {
+        resolve_relative_path(Path::new(dir), target).map(|p| p.to_string_lossy().into_owned())
+    };
+    assert_eq!(Some("a/b/c".to_owned()), resolve("a/b", "c"));
+    assert_eq!(Some("a/c".to_owned()), resolve("a/b", "../c"));
+    assert_eq!(Some("c".to_owned()), resolve("a/b", "./../../c"));
+    assert_eq!(None, resolve("a/b", "../../../c"));
+    assert_eq!(None, resolve("", "/etc/passwd"));
+}
+
+/// How many symlinks `symlink_stays_within` follows before giving up, like the kernel's limit.
+const MAX_SYMLINKS: usize = 40;
+
+/// Check, against what's on the filesystem, that a symlink at `link` (somewhere under `root`) with
+/// the given target would point inside `root`. Unlike `resolve_relative_path`, this follows links
+/// that already exist, so a target that only looks harmless, like `../y/..` where `y` is a link to
+/// `.`, is caught. Parts of the path that don't exist yet are resolved by their text. The link's
+/// own directories will be created as real directories, but a `..` after a missing part of the
+/// target is refused, since whatever gets created there later decides where it leads.
+pub fn symlink_stays_within(root: &Path, link: &Path, target: &str) -> io::Result<bool> {
+    // Both are compared as absolute paths; `join` leaves them alone if they already are.
+    let current_dir = std::env::current_dir()?;
+    let link = current_dir.join(link);
+
+    // The root might not have been created yet, so canonicalize as much of it as exists.
+    let mut root = current_dir.join(root);
+    let mut rest = vec![];
+    let mut root = loop {
+        match root.canonicalize() {
+            Ok(canonical) => break canonical,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match root.file_name() {
+                Some(name) => {
+                    rest.push(name.to_owned());
+                    root.pop();
+                }
+                None => return Err(e),
+            },
+            Err(e) => return Err(e),
+        }
+    };
+    root.extend(rest.iter().rev());
+
+    let link_dir = link.parent().unwrap_or_else(|| Path::new(""));
+    // Each part, and whether it's one of the link's directories.
+    let mut pending = link_dir.components()
+        .map(|component| (component.as_os_str().to_owned(), true))
+        .chain(Path::new(target).components()
+            .map(|component| (component.as_os_str().to_owned(), false)))
+        .collect::<std::collections::VecDeque<_>>();
+
+    let mut resolved = PathBuf::new();
+    let mut missing = false;
+    // How many parts at the end of `resolved` are missing parts of the target.
+    let mut unknown = 0;
+    let mut links_followed = 0;
+    while let Some((part, is_link_dir)) = pending.pop_front() {
+        match Path::new(&part).components().next() {
+            Some(Component::Normal(name)) => {
+                resolved.push(name);
+                if missing {
+                    if !is_link_dir {
+                        unknown += 1;
+                    }
+                    continue;
+                }
+                match std::fs::symlink_metadata(&resolved) {
+                    Ok(metadata) if metadata.file_type().is_symlink() => {
+                        links_followed += 1;
+                        if links_followed > MAX_SYMLINKS {
+                            return Ok(false);
+                        }
+                        let link_target = std::fs::read_link(&resolved)?;
+                        resolved.pop();
+                        for component in link_target.components().rev() {
+                            pending.push_front((component.as_os_str().to_owned(), false));
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        missing = true;
+                        if !is_link_dir {
+                            unknown += 1;
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Some(Component::ParentDir) => {
+                if unknown > 0 {
+                    return Ok(false);
+                }
+                resolved.pop();
+            }
+            // `push` replaces the whole path with an absolute one.
+            Some(Component::RootDir) | Some(Component::Prefix(_)) => {
+                resolved.push(&part);
+            }
+            Some(Component::CurDir) | None => (),
+        }
+    }
+    Ok(resolved.starts_with(&root))
+}
+
+/// The first directory between `root` and `path` (not counting either) that is a symlink, if any.
+/// Writing to `path` would go wherever that link leads rather than staying under `root`.
+pub fn symlink_in_parents(root: &Path, path: &Path) -> io::Result<Option<PathBuf>> {
+    let relative = match path.strip_prefix(root) {
+        Ok(relative) => relative,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                            format!("{:?} is not under {:?}", path, root))),
+    };
+    let mut current = root.to_owned();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        current.push(component);
+        match std::fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Ok(Some(current)),
+            Ok(_) => (),
+            // Nothing further down exists either, so it'll all be created as directories.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// A directory under the system's temporary directory for a test to use, empty to begin with.
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("stget-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_stays_within() {
+    let root = test_dir("symlink-within");
+    std::fs::create_dir(root.join("d")).unwrap();
+    let within = |link: &str, target: &str| {
+        symlink_stays_within(&root, &root.join(link), target).unwrap()
+    };
+
+    assert!(within("a", "d/file"));
+    assert!(within("d/a", "../file"));
+    assert!(!within("a", "../file"));
+    assert!(!within("a", "/etc/passwd"));
+
+    // Each of these looks fine as text, but goes through `y`, which points at the root itself.
+    std::os::unix::fs::symlink(".", root.join("y")).unwrap();
+    assert!(within("z", "y"));
+    assert!(!within("d/z", "../y/.."));
+    assert!(!within("z", "y/../outside"));
+
+    // What `new` leads to isn't known until it's created.
+    assert!(within("z", "new/file"));
+    assert!(!within("z", "new/../.."));
+    assert!(!within("z", "new/.."));
+    assert!(within("new/z", "../d/file"));
+    assert!(!within("new/z", "../../file"));
+
+    std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+    assert!(!within("z", "loop/x"));
+
+    let new_root = root.join("not/created/yet");
+    assert!(symlink_stays_within(&new_root, &new_root.join("d/link"), "../file").unwrap());
+    assert!(!symlink_stays_within(&new_root, &new_root.join("link"), "../file").unwrap());
+
+    // Relative to the current directory, as with the default `--dest .`.
+    let relative = Path::new(".");
+    assert!(symlink_stays_within(relative, &relative.join("link"), "new/file").unwrap());
+    assert!(!symlink_stays_within(relative, &relative.join("link"), "../file").unwrap());
+    let relative = PathBuf::from(format!("stget-not-created-{}/dest", std::process::id()));
+    assert!(symlink_stays_within(&relative, &relative.join("d/link"), "../file").unwrap());
+    assert!(!symlink_stays_within(&relative, &relative.join("link"), "../file").unwrap());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_in_parents() {
+    let root = test_dir("symlink-parents");
+    std::fs::create_dir_all(root.join("d/e")).unwrap();
+    std::os::unix::fs::symlink("d", root.join("link")).unwrap();
+
+    assert_eq!(None, symlink_in_parents(&root, &root.join("d/e/file")).unwrap());
+    assert_eq!(None, symlink_in_parents(&root, &root.join("d/new/deeper/file")).unwrap());
+    // The file itself being a link is a conflict, not something this looks for.
+    assert_eq!(None, symlink_in_parents(&root, &root.join("link")).unwrap());
+    assert_eq!(Some(root.join("link")),
+               symlink_in_parents(&root, &root.join("link/e/file")).unwrap());
+    assert!(symlink_in_parents(&root, Path::new("/elsewhere/file")).is_err());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+pub fn create_symlink(target: &str, path: &Path, _is_dir: bool) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+pub fn create_symlink(target: &str, path: &Path, is_dir: bool) -> io::Result<()> {
+    if is_dir {
+        std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path)
+    }
+}
+
+/// Convert a Unix timestamp into a `SystemTime`, or None if it's out of the range the system can
+/// represent.
+pub fn system_time(secs: i64, nanos: i32) -> Option<SystemTime> {
+    let nanos = Duration::from_nanos(nanos.max(0) as u64);
+    let time = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+    };
+    time?.checked_add(nanos)
+}
+
+fn timestamp_out_of_range(secs: i64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("timestamp {} is out of range", secs))
+}
+
+#[test]
+fn test_system_time() {
+    assert_eq!(Some(UNIX_EPOCH + Duration::new(1_700_000_000, 500)),
+               system_time(1_700_000_000, 500));
+    assert_eq!(Some(UNIX_EPOCH - Duration::from_secs(10) + Duration::from_nanos(1)),
+               system_time(-10, 1));
+    // Where the range ends depends on the system, but nothing goes past the largest timestamp.
+    assert_eq!(None, system_time(i64::MAX, i32::MAX));
+
+    // Out of range isn't something to panic over, just an error.
+    let dir = test_dir("system-time");
+    let path = dir.join("file");
+    let file = File::create(&path).unwrap();
+    assert!(set_file_mtime(&file, i64::MAX, i32::MAX).is_err());
+    assert!(set_path_mtime(&path, i64::MAX, i32::MAX).is_err());
+    set_path_mtime(&path, 1_700_000_000, 0).unwrap();
+    assert_eq!(UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+               std::fs::metadata(&path).unwrap().modified().unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
 #[cfg(unix)]
 pub fn get_hostname() -> io::Result<String> {
     extern "C" {
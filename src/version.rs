@@ -0,0 +1,53 @@
+use crate::syncthing_proto::Vector;
+use std::cmp::Ordering;
+
+/// Compare two version vectors, the way Syncthing decides which copy of a file is newer.
+///
+/// A version vector has a counter for each device that has changed the file, which that device
+/// increases each time it does. One version is newer than another if none of its counters are
+/// lower and at least one is higher; devices that aren't listed count as zero. If each version
+/// has a counter higher than the other's, the changes were made independently (a conflict), and
+/// this returns None.
+pub fn compare(a: &Vector, b: &Vector) -> Option<Ordering> {
+    let value = |vector: &Vector, id: u64| {
+        vector.counters.iter()
+            .find(|counter| counter.id == id)
+            .map(|counter| counter.value)
+            .unwrap_or(0)
+    };
+    let mut result = Ordering::Equal;
+    for id in a.counters.iter().chain(&b.counters).map(|counter| counter.id) {
+        match (value(a, id).cmp(&value(b, id)), result) {
+            (Ordering::Equal, _) => (),
+            (ordering, Ordering::Equal) => result = ordering,
+            (ordering, _) if ordering == result => (),
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+#[test]
+fn test_compare() {
+    let vector = |counters: &[(u64, u64)]| {
+        let mut vector = Vector::new();
+        for &(id, value) in counters {
+            let mut counter = crate::syncthing_proto::Counter::new();
+            counter.id = id;
+            counter.value = value;
+            vector.counters.push(counter);
+        }
+        vector
+    };
+    let v1 = vector(&[(1, 1)]);
+    let v2 = vector(&[(1, 2)]);
+    let v2_other = vector(&[(1, 2), (2, 1)]);
+    let conflict = vector(&[(1, 1), (2, 1)]);
+
+    assert_eq!(Some(Ordering::Equal), compare(&v1, &v1));
+    assert_eq!(Some(Ordering::Equal), compare(&Vector::new(), &vector(&[(1, 0)])));
+    assert_eq!(Some(Ordering::Less), compare(&v1, &v2));
+    assert_eq!(Some(Ordering::Greater), compare(&v2_other, &v2));
+    assert_eq!(Some(Ordering::Greater), compare(&conflict, &v1));
+    assert_eq!(None, compare(&v2, &conflict));
+}